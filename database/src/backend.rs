@@ -0,0 +1,361 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use sync::{FlockMutexGuard, InProcessMutexGuard};
+use tempfile::NamedTempFile;
+use types::{Email, Fingerprint, KeyID};
+use write_log::{WriteLogCursor, WriteLogDetails, WriteLogEntry};
+use Result;
+use {CertPolicy, CertVersion, Database, QuarantineReason, QuarantinedCert, Query};
+
+use openpgp::Cert;
+
+use fs::Filesystem;
+use memory::MemoryDatabase;
+use sqlite::Sqlite;
+
+/// A database with its backend chosen at runtime.
+pub enum KeyDatabase {
+    Filesystem(Filesystem),
+    Sqlite(Sqlite),
+    Memory(MemoryDatabase),
+}
+
+/// The settings needed to open a database, whichever backend it uses.
+pub struct DatabaseConfig {
+    /// One of "filesystem", "sqlite" or "memory".
+    pub backend: String,
+    pub keys_internal_dir: PathBuf,
+    pub keys_external_dir: PathBuf,
+    pub tmp_dir: PathBuf,
+    pub history_retention: Duration,
+    pub policy: CertPolicy,
+    /// Only supported by the filesystem backend.
+    pub dry_run: bool,
+}
+
+impl KeyDatabase {
+    /// Opens the backend chosen in the configuration.
+    pub fn open(config: DatabaseConfig) -> Result<Self> {
+        match config.backend.as_str() {
+            "filesystem" => Ok(Filesystem::new_internal(
+                config.keys_internal_dir,
+                config.keys_external_dir,
+                config.tmp_dir,
+                config.history_retention,
+                config.policy,
+                config.dry_run,
+            )?
+            .into()),
+            _ if config.dry_run => Err(anyhow!(
+                "The {} backend does not support dry runs",
+                config.backend
+            )),
+            "sqlite" => Ok(Sqlite::new_from_base(config.keys_internal_dir)?
+                .with_policy(config.policy)
+                .into()),
+            "memory" => Ok(MemoryDatabase::new().with_policy(config.policy).into()),
+            _ => Err(anyhow!("Unknown database backend: {}", config.backend)),
+        }
+    }
+}
+
+/// The lock guard of any of the backends.
+///
+/// The guards are only ever held for their `Drop` implementation.
+#[allow(dead_code)]
+pub enum KeyDatabaseGuard {
+    Flock(Vec<FlockMutexGuard>),
+    InProcess(Vec<InProcessMutexGuard>),
+}
+
+/// A temporary cert of any of the backends.
+pub enum KeyDatabaseTempCert {
+    File(NamedTempFile),
+    Bytes(Vec<u8>),
+}
+
+impl KeyDatabaseTempCert {
+    fn into_file(self) -> Result<NamedTempFile> {
+        match self {
+            KeyDatabaseTempCert::File(file) => Ok(file),
+            _ => Err(anyhow!("temporary cert belongs to a different backend")),
+        }
+    }
+
+    fn into_bytes(self) -> Result<Vec<u8>> {
+        match self {
+            KeyDatabaseTempCert::Bytes(bytes) => Ok(bytes),
+            _ => Err(anyhow!("temporary cert belongs to a different backend")),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $db:ident => $e:expr) => {
+        match $self {
+            KeyDatabase::Filesystem($db) => $e,
+            KeyDatabase::Sqlite($db) => $e,
+            KeyDatabase::Memory($db) => $e,
+        }
+    };
+}
+
+impl From<Filesystem> for KeyDatabase {
+    fn from(db: Filesystem) -> Self {
+        KeyDatabase::Filesystem(db)
+    }
+}
+
+impl From<Sqlite> for KeyDatabase {
+    fn from(db: Sqlite) -> Self {
+        KeyDatabase::Sqlite(db)
+    }
+}
+
+impl From<MemoryDatabase> for KeyDatabase {
+    fn from(db: MemoryDatabase) -> Self {
+        KeyDatabase::Memory(db)
+    }
+}
+
+impl Database for KeyDatabase {
+    type MutexGuard = KeyDatabaseGuard;
+    type TempCert = KeyDatabaseTempCert;
+
+    fn lock_fprs(&self, fprs: &[Fingerprint]) -> Result<Self::MutexGuard> {
+        Ok(match self {
+            KeyDatabase::Filesystem(db) => KeyDatabaseGuard::Flock(db.lock_fprs(fprs)?),
+            KeyDatabase::Sqlite(db) => KeyDatabaseGuard::Flock(db.lock_fprs(fprs)?),
+            KeyDatabase::Memory(db) => KeyDatabaseGuard::InProcess(db.lock_fprs(fprs)?),
+        })
+    }
+
+    fn lock_emails(&self, emails: &[Email]) -> Result<Self::MutexGuard> {
+        Ok(match self {
+            KeyDatabase::Filesystem(db) => KeyDatabaseGuard::Flock(db.lock_emails(emails)?),
+            KeyDatabase::Sqlite(db) => KeyDatabaseGuard::Flock(db.lock_emails(emails)?),
+            KeyDatabase::Memory(db) => KeyDatabaseGuard::InProcess(db.lock_emails(emails)?),
+        })
+    }
+
+    fn lookup_primary_fingerprint(&self, term: &Query) -> Option<Fingerprint> {
+        dispatch!(self, db => db.lookup_primary_fingerprint(term))
+    }
+
+    fn link_email(&self, email: &Email, fpr: &Fingerprint) -> Result<()> {
+        dispatch!(self, db => db.link_email(email, fpr))
+    }
+
+    fn unlink_email(&self, email: &Email, fpr: &Fingerprint) -> Result<()> {
+        dispatch!(self, db => db.unlink_email(email, fpr))
+    }
+
+    fn link_fpr(&self, from: &Fingerprint, to: &Fingerprint) -> Result<()> {
+        dispatch!(self, db => db.link_fpr(from, to))
+    }
+
+    fn unlink_fpr(&self, from: &Fingerprint, to: &Fingerprint) -> Result<()> {
+        dispatch!(self, db => db.unlink_fpr(from, to))
+    }
+
+    fn by_fpr(&self, fpr: &Fingerprint) -> Option<String> {
+        dispatch!(self, db => db.by_fpr(fpr))
+    }
+
+    fn by_kid(&self, kid: &KeyID) -> Option<String> {
+        dispatch!(self, db => db.by_kid(kid))
+    }
+
+    fn by_email(&self, email: &Email) -> Option<String> {
+        dispatch!(self, db => db.by_email(email))
+    }
+
+    fn by_email_wkd(&self, email: &Email) -> Option<Vec<u8>> {
+        dispatch!(self, db => db.by_email_wkd(email))
+    }
+
+    fn by_domain_and_hash_wkd(&self, domain: &str, hash: &str) -> Option<Vec<u8>> {
+        dispatch!(self, db => db.by_domain_and_hash_wkd(domain, hash))
+    }
+
+    fn check_link_fpr(
+        &self,
+        fpr: &Fingerprint,
+        fpr_target: &Fingerprint,
+    ) -> Result<Option<Fingerprint>> {
+        dispatch!(self, db => db.check_link_fpr(fpr, fpr_target))
+    }
+
+    fn by_fpr_full(&self, fpr: &Fingerprint) -> Option<String> {
+        dispatch!(self, db => db.by_fpr_full(fpr))
+    }
+
+    fn by_primary_fpr(&self, fpr: &Fingerprint) -> Option<String> {
+        dispatch!(self, db => db.by_primary_fpr(fpr))
+    }
+
+    fn write_to_temp(&self, content: &[u8]) -> Result<Self::TempCert> {
+        Ok(match self {
+            KeyDatabase::Filesystem(db) => KeyDatabaseTempCert::File(db.write_to_temp(content)?),
+            KeyDatabase::Sqlite(db) => KeyDatabaseTempCert::Bytes(db.write_to_temp(content)?),
+            KeyDatabase::Memory(db) => KeyDatabaseTempCert::Bytes(db.write_to_temp(content)?),
+        })
+    }
+
+    fn move_tmp_to_full(&self, content: Self::TempCert, fpr: &Fingerprint) -> Result<()> {
+        match self {
+            KeyDatabase::Filesystem(db) => db.move_tmp_to_full(content.into_file()?, fpr),
+            KeyDatabase::Sqlite(db) => db.move_tmp_to_full(content.into_bytes()?, fpr),
+            KeyDatabase::Memory(db) => db.move_tmp_to_full(content.into_bytes()?, fpr),
+        }
+    }
+
+    fn move_tmp_to_published(&self, content: Self::TempCert, fpr: &Fingerprint) -> Result<()> {
+        match self {
+            KeyDatabase::Filesystem(db) => db.move_tmp_to_published(content.into_file()?, fpr),
+            KeyDatabase::Sqlite(db) => db.move_tmp_to_published(content.into_bytes()?, fpr),
+            KeyDatabase::Memory(db) => db.move_tmp_to_published(content.into_bytes()?, fpr),
+        }
+    }
+
+    fn move_tmp_to_published_wkd(
+        &self,
+        content: Option<Self::TempCert>,
+        fpr: &Fingerprint,
+    ) -> Result<()> {
+        match self {
+            KeyDatabase::Filesystem(db) => {
+                let content = content.map(KeyDatabaseTempCert::into_file).transpose()?;
+                db.move_tmp_to_published_wkd(content, fpr)
+            }
+            KeyDatabase::Sqlite(db) => {
+                let content = content.map(KeyDatabaseTempCert::into_bytes).transpose()?;
+                db.move_tmp_to_published_wkd(content, fpr)
+            }
+            KeyDatabase::Memory(db) => {
+                let content = content.map(KeyDatabaseTempCert::into_bytes).transpose()?;
+                db.move_tmp_to_published_wkd(content, fpr)
+            }
+        }
+    }
+
+    fn write_to_quarantine(
+        &self,
+        fpr: &Fingerprint,
+        content: &[u8],
+        reason: &QuarantineReason,
+    ) -> Result<()> {
+        dispatch!(self, db => db.write_to_quarantine(fpr, content, reason))
+    }
+
+    fn list_quarantined(&self) -> Result<Vec<QuarantinedCert>> {
+        dispatch!(self, db => db.list_quarantined())
+    }
+
+    fn remove_quarantined(&self, fpr: &Fingerprint) -> Result<()> {
+        dispatch!(self, db => db.remove_quarantined(fpr))
+    }
+
+    fn remove_tpk(&self, fpr: &Fingerprint) -> Result<()> {
+        dispatch!(self, db => db.remove_tpk(fpr))
+    }
+
+    fn write_log_append(
+        &self,
+        filename: &str,
+        fpr_primary: &Fingerprint,
+        details: &WriteLogDetails,
+    ) -> Result<()> {
+        dispatch!(self, db => db.write_log_append(filename, fpr_primary, details))
+    }
+
+    fn journal_begin(&self, fpr_primary: &Fingerprint, tpk: &Cert) -> Result<()> {
+        dispatch!(self, db => db.journal_begin(fpr_primary, tpk))
+    }
+
+    fn journal_commit(&self, fpr_primary: &Fingerprint) -> Result<()> {
+        dispatch!(self, db => db.journal_commit(fpr_primary))
+    }
+
+    fn read_write_log(
+        &self,
+        since: u64,
+        cursor: &WriteLogCursor,
+        limit: usize,
+    ) -> Result<(Vec<WriteLogEntry>, WriteLogCursor)> {
+        dispatch!(self, db => db.read_write_log(since, cursor, limit))
+    }
+
+    fn cert_history(&self, fpr_primary: &Fingerprint) -> Result<Vec<CertVersion>> {
+        dispatch!(self, db => db.cert_history(fpr_primary))
+    }
+
+    fn check_consistency(&self) -> Result<()> {
+        dispatch!(self, db => db.check_consistency())
+    }
+
+    fn policy(&self) -> &CertPolicy {
+        dispatch!(self, db => db.policy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use test;
+    use DEFAULT_HISTORY_RETENTION;
+
+    #[test]
+    fn memory_uid_verification() {
+        let tmpdir = TempDir::new().unwrap();
+        let mut db: KeyDatabase = MemoryDatabase::new_with_log_dir(tmpdir.path())
+            .unwrap()
+            .into();
+        let log_path = tmpdir.path().join(db.get_current_log_filename());
+
+        test::test_uid_verification(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn open_configured_backend() {
+        let tmpdir = TempDir::new().unwrap();
+        let config = |backend: &str, dry_run| DatabaseConfig {
+            backend: backend.to_owned(),
+            keys_internal_dir: tmpdir.path().join("internal"),
+            keys_external_dir: tmpdir.path().join("external"),
+            tmp_dir: tmpdir.path().join("tmp"),
+            history_retention: DEFAULT_HISTORY_RETENTION,
+            policy: CertPolicy::default(),
+            dry_run,
+        };
+
+        match KeyDatabase::open(config("filesystem", true)).unwrap() {
+            KeyDatabase::Filesystem(_) => (),
+            _ => panic!("expected the filesystem backend"),
+        }
+        match KeyDatabase::open(config("sqlite", false)).unwrap() {
+            KeyDatabase::Sqlite(_) => (),
+            _ => panic!("expected the sqlite backend"),
+        }
+        assert!(tmpdir.path().join("internal").join("keys.sqlite").exists());
+        assert!(KeyDatabase::open(config("sqlite", true)).is_err());
+        assert!(KeyDatabase::open(config("postgres", false)).is_err());
+    }
+
+    #[test]
+    fn filesystem_uid_verification() {
+        let tmpdir = TempDir::new().unwrap();
+        let mut db: KeyDatabase = Filesystem::new_from_base(tmpdir.path()).unwrap().into();
+        let log_path = tmpdir
+            .path()
+            .join("keys")
+            .join("log")
+            .join(db.get_current_log_filename());
+
+        test::test_uid_verification(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+}
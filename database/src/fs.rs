@@ -1,28 +1,33 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
 use std::fs::{
-    create_dir_all, read_link, remove_file, rename, set_permissions, File, OpenOptions, Permissions,
+    create_dir_all, read, read_dir, read_link, remove_file, rename, set_permissions, Permissions,
 };
-use std::io::Write;
+use std::io::{ErrorKind, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use pathdiff::diff_paths;
-use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use serde_json;
 use tempfile;
 use url::form_urlencoded;
 
-use sync::FlockMutexGuard;
+use history::{self, CertVersion, CertVersionKind};
+use sync::{FlockMutexGuard, FlockStripes};
 use types::{Email, Fingerprint, KeyID};
+use write_log::{self, WriteLogCursor, WriteLogDetails, WriteLogEntry, WriteLogOp};
 use Result;
-use {Database, Query};
+use {tpk_get_emails, tpk_get_fprs, tpk_get_linkable_fprs, CertPolicy, Database, Query};
+use {QuarantineReason, QuarantinedCert};
 
 use wkd;
 
 use tempfile::NamedTempFile;
 
-use openpgp::Cert;
-use openpgp_utils::POLICY;
+use openpgp::{parse::Parse, Cert};
 
 pub struct Filesystem {
     tmp_dir: PathBuf,
@@ -34,6 +39,12 @@ pub struct Filesystem {
     keys_dir_published: PathBuf,
     keys_dir_published_wkd: PathBuf,
     keys_dir_log: PathBuf,
+    keys_dir_history: PathBuf,
+    history_retention: Duration,
+    policy: CertPolicy,
+    journal_dir: PathBuf,
+    fpr_locks: FlockStripes,
+    email_locks: FlockStripes,
 
     links_dir_by_fingerprint: PathBuf,
     links_dir_by_keyid: PathBuf,
@@ -43,6 +54,101 @@ pub struct Filesystem {
     dry_run: bool,
 }
 
+/// The links a complex operation on a Cert may touch.
+///
+/// Written to the journal before the operation starts, and removed
+/// when it is done.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct JournalIntent {
+    fingerprints: Vec<Fingerprint>,
+    emails: Vec<Email>,
+}
+
+/// A problem found by `Filesystem::fsck`.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "problem", rename_all = "kebab-case")]
+pub enum FsckProblem {
+    /// A published Cert is stored under the wrong fingerprint.
+    WrongPublishedPath {
+        path: PathBuf,
+        primary: Fingerprint,
+    },
+    MissingWkd {
+        primary: Fingerprint,
+    },
+    SuperfluousWkd {
+        primary: Fingerprint,
+    },
+    MissingFprLink {
+        primary: Fingerprint,
+        fingerprint: Fingerprint,
+    },
+    /// A key of the Cert is linked to a different Cert.
+    FprCollision {
+        primary: Fingerprint,
+        fingerprint: Fingerprint,
+    },
+    MissingEmailLink {
+        primary: Fingerprint,
+        email: Email,
+    },
+    MissingWkdEmailLink {
+        primary: Fingerprint,
+        email: Email,
+    },
+    /// A link points to a Cert that doesn't contain the key or email.
+    WrongLinkTarget {
+        path: PathBuf,
+        primary: Fingerprint,
+    },
+    /// A link points to a Cert that doesn't exist.
+    DanglingLink {
+        path: PathBuf,
+    },
+    MalformedPath {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsckProblem::WrongPublishedPath { path, primary } => {
+                write!(f, "{:?} points to the wrong Cert, found {}", path, primary)
+            }
+            FsckProblem::MissingWkd { primary } => write!(f, "Missing wkd for fp {}", primary),
+            FsckProblem::SuperfluousWkd { primary } => {
+                write!(f, "Incorrectly present wkd for fp {}", primary)
+            }
+            FsckProblem::MissingFprLink {
+                primary,
+                fingerprint,
+            } => write!(f, "Missing link to key {} for sub {}", primary, fingerprint),
+            FsckProblem::FprCollision {
+                primary,
+                fingerprint,
+            } => write!(
+                f,
+                "Key {} of {} is linked to a different Cert",
+                fingerprint, primary
+            ),
+            FsckProblem::MissingEmailLink { primary, email } => {
+                write!(f, "Missing link to key {} for email {}", primary, email)
+            }
+            FsckProblem::MissingWkdEmailLink { primary, email } => {
+                write!(f, "Missing wkd link to key {} for email {}", primary, email)
+            }
+            FsckProblem::WrongLinkTarget { path, primary } => write!(
+                f,
+                "{:?} points to the wrong Cert, {} does not contain the key or email",
+                path, primary
+            ),
+            FsckProblem::DanglingLink { path } => write!(f, "Broken symlink {:?}", path),
+            FsckProblem::MalformedPath { path } => write!(f, "Malformed path: {:?}", path),
+        }
+    }
+}
+
 /// Returns the given path, ensuring that the parent directory exists.
 ///
 /// Use this on paths returned by .path_to_* before creating the
@@ -68,13 +174,28 @@ impl Filesystem {
         keys_external_dir: impl Into<PathBuf>,
         tmp_dir: impl Into<PathBuf>,
     ) -> Result<Self> {
-        Self::new_internal(keys_internal_dir, keys_external_dir, tmp_dir, false)
+        Self::new_internal(
+            keys_internal_dir,
+            keys_external_dir,
+            tmp_dir,
+            history::DEFAULT_HISTORY_RETENTION,
+            CertPolicy::default(),
+            false,
+        )
     }
 
+    /// Opens the database, and replays interrupted operations.
+    ///
+    /// `history_retention` is how long past versions of Certs are
+    /// kept, the most recent version is always kept.  `policy` decides
+    /// which Certs are accepted.  Both are already in effect for the
+    /// replay.
     pub fn new_internal(
         keys_internal_dir: impl Into<PathBuf>,
         keys_external_dir: impl Into<PathBuf>,
         tmp_dir: impl Into<PathBuf>,
+        history_retention: Duration,
+        policy: CertPolicy,
         dry_run: bool,
     ) -> Result<Self> {
         let tmp_dir = tmp_dir.into();
@@ -85,6 +206,8 @@ impl Filesystem {
         let keys_dir_full = keys_internal_dir.join("full");
         let keys_dir_quarantined = keys_internal_dir.join("quarantined");
         let keys_dir_log = keys_internal_dir.join("log");
+        let keys_dir_history = keys_internal_dir.join("history");
+        let journal_dir = keys_internal_dir.join("journal");
         let keys_dir_published = keys_external_dir.join("pub");
         let keys_dir_published_wkd = keys_external_dir.join("wkd");
         create_dir_all(&keys_dir_full)?;
@@ -92,6 +215,10 @@ impl Filesystem {
         create_dir_all(&keys_dir_published)?;
         create_dir_all(&keys_dir_published_wkd)?;
         create_dir_all(&keys_dir_log)?;
+        create_dir_all(&keys_dir_history)?;
+        create_dir_all(&journal_dir)?;
+        let fpr_locks = FlockStripes::new(keys_internal_dir.join("locks").join("fpr"))?;
+        let email_locks = FlockStripes::new(keys_internal_dir.join("locks").join("email"))?;
 
         let links_dir = keys_external_dir.join("links");
         let links_dir_by_keyid = links_dir.join("by-keyid");
@@ -107,7 +234,7 @@ impl Filesystem {
         info!("keys_internal_dir: '{}'", keys_internal_dir.display());
         info!("keys_external_dir: '{}'", keys_external_dir.display());
         info!("tmp_dir: '{}'", tmp_dir.display());
-        Ok(Filesystem {
+        let db = Filesystem {
             keys_internal_dir,
             keys_external_dir,
             tmp_dir,
@@ -117,6 +244,12 @@ impl Filesystem {
             keys_dir_published_wkd,
             keys_dir_quarantined,
             keys_dir_log,
+            keys_dir_history,
+            history_retention,
+            policy,
+            journal_dir,
+            fpr_locks,
+            email_locks,
 
             links_dir_by_keyid,
             links_dir_by_fingerprint,
@@ -124,7 +257,122 @@ impl Filesystem {
             links_dir_wkd_by_email,
 
             dry_run,
-        })
+        };
+
+        if !dry_run {
+            db.replay_journal()?;
+        }
+
+        Ok(db)
+    }
+
+    /// Restores consistency for all complex operations that have
+    /// been interrupted.
+    ///
+    /// The published Cert is the source of truth: all links recorded
+    /// in the journal are recreated or removed to match it.
+    fn replay_journal(&self) -> Result<()> {
+        use std::str::FromStr;
+
+        for entry in read_dir(&self.journal_dir)? {
+            let path = entry?.path();
+            let fpr_primary = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| Fingerprint::from_str(name).ok())
+            {
+                Some(fpr_primary) => fpr_primary,
+                None => {
+                    warn!("Ignoring malformed journal entry {}", path.display());
+                    continue;
+                }
+            };
+            self.replay_journal_entry(&path, &fpr_primary)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replays one journal entry, unless another process finishes the
+    /// operation first.
+    fn replay_journal_entry(&self, path: &Path, fpr_primary: &Fingerprint) -> Result<()> {
+        let read_intent = || -> Result<Option<JournalIntent>> {
+            match read(path) {
+                Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        };
+
+        let mut intent = match read_intent()? {
+            Some(intent) => intent,
+            None => return Ok(()),
+        };
+        loop {
+            let mut fprs = intent.fingerprints.clone();
+            fprs.push(fpr_primary.clone());
+            let _lock = self.lock_fprs(&fprs)?;
+            let _lock_emails = self.lock_emails(&intent.emails)?;
+
+            // The entry may have been completed or replaced while we
+            // were waiting for the locks.
+            let current = match read_intent()? {
+                Some(current) => current,
+                None => return Ok(()),
+            };
+            if current != intent {
+                intent = current;
+                continue;
+            }
+
+            warn!("Replaying interrupted operation for {}", fpr_primary);
+            self.replay_intent(fpr_primary, &intent)?;
+            return match remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+    }
+
+    fn replay_intent(&self, fpr_primary: &Fingerprint, intent: &JournalIntent) -> Result<()> {
+        let published_tpk = self
+            .by_primary_fpr(fpr_primary)
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok());
+
+        let (linkable_fprs, published_emails) = if let Some(ref tpk) = published_tpk {
+            self.regenerate_wkd(fpr_primary, tpk)?;
+            (
+                tpk_get_linkable_fprs(tpk, &self.policy),
+                tpk_get_emails(tpk),
+            )
+        } else {
+            self.move_tmp_to_published_wkd(None, fpr_primary)?;
+            (vec![], vec![])
+        };
+
+        for fpr in &intent.fingerprints {
+            if !linkable_fprs.contains(fpr) {
+                self.unlink_fpr(fpr, fpr_primary)?;
+                continue;
+            }
+            match self.check_link_fpr(fpr, fpr_primary) {
+                Ok(Some(fpr)) => self.link_fpr(&fpr, fpr_primary)?,
+                Ok(None) => (),
+                Err(e) => info!("Not linking {} to {}: {:?}", fpr, fpr_primary, e),
+            }
+        }
+
+        for email in &intent.emails {
+            if published_emails.contains(email) {
+                self.link_email(email, fpr_primary)?;
+            } else {
+                self.unlink_email(email, fpr_primary)?;
+            }
+        }
+
+        self.update_write_log(fpr_primary, WriteLogOp::Replay, intent.emails.clone(), None);
+
+        Ok(())
     }
 
     /// Returns the path to the given Fingerprint.
@@ -133,6 +381,12 @@ impl Filesystem {
         self.keys_dir_full.join(path_split(&hex))
     }
 
+    /// Returns the path to the history of the given Fingerprint.
+    fn fingerprint_to_path_history(&self, fingerprint: &Fingerprint) -> PathBuf {
+        let hex = fingerprint.to_string();
+        self.keys_dir_history.join(path_split(&hex))
+    }
+
     /// Returns the path to the given Fingerprint.
     fn fingerprint_to_path_quarantined(&self, fingerprint: &Fingerprint) -> PathBuf {
         let hex = fingerprint.to_string();
@@ -311,19 +565,12 @@ impl Filesystem {
         symlink_unlink_with_check(&link, &expected)
     }
 
-    fn open_logfile(&self, file_name: &str) -> Result<File> {
-        let file_path = self.keys_dir_log.join(file_name);
-        Ok(OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(file_path)?)
-    }
-
     fn perform_checks(
         &self,
         checks_dir: &Path,
-        tpks: &mut HashMap<Fingerprint, Cert>,
-        check: impl Fn(&Path, &Cert, &Fingerprint) -> Result<()>,
+        tpks: &mut HashMap<Fingerprint, Option<Cert>>,
+        problems: &mut Vec<FsckProblem>,
+        check: impl Fn(&Path, &Cert, &Fingerprint) -> Vec<FsckProblem>,
     ) -> Result<()> {
         use std::fs;
         use walkdir::WalkDir;
@@ -338,25 +585,241 @@ impl Filesystem {
 
             // Compute the corresponding primary fingerprint just
             // by looking at the paths.
-            let primary_fp = Filesystem::path_to_primary(path)
-                .ok_or_else(|| format_err!("Malformed path: {:?}", path.read_link().unwrap()))?;
+            let primary_fp = match Filesystem::path_to_primary(path) {
+                Some(primary_fp) => primary_fp,
+                None => {
+                    problems.push(FsckProblem::MalformedPath {
+                        path: path.to_owned(),
+                    });
+                    continue;
+                }
+            };
             // Load into cache.
-            if !tpks.contains_key(&primary_fp) {
-                tpks.insert(
-                    primary_fp.clone(),
-                    self.lookup(&Query::ByFingerprint(primary_fp.clone()))?
-                        .ok_or_else(|| format_err!("No Cert with fingerprint {:?}", primary_fp))?,
-                );
+            let tpk = tpks.entry(primary_fp.clone()).or_insert_with(|| {
+                self.by_primary_fpr(&primary_fp)
+                    .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok())
+            });
+
+            match tpk {
+                Some(tpk) => problems.extend(check(path, tpk, &primary_fp)),
+                None => problems.push(FsckProblem::DanglingLink {
+                    path: path.to_owned(),
+                }),
             }
+        }
 
-            let tpk = tpks.get(&primary_fp).ok_or_else(|| {
-                format_err!("Broken symlink {:?}: No such Key {}", path, primary_fp)
-            })?;
+        Ok(())
+    }
 
-            check(path, tpk, &primary_fp)?;
+    /// Checks the whole store, and returns all problems found.
+    ///
+    /// Unlike `check_consistency`, this does not stop at the first
+    /// problem.
+    pub fn fsck(&self) -> Result<Vec<FsckProblem>> {
+        // A cache of all Certs, for quick lookups.
+        let mut tpks = HashMap::new();
+        let mut problems = Vec::new();
+
+        self.perform_checks(
+            &self.keys_dir_published,
+            &mut tpks,
+            &mut problems,
+            |path, _, primary_fp| {
+                // The Fingerprint corresponding with this path.
+                match Filesystem::path_to_fingerprint(path) {
+                    Some(fp) if fp == *primary_fp => vec![],
+                    _ => vec![FsckProblem::WrongPublishedPath {
+                        path: path.to_owned(),
+                        primary: primary_fp.clone(),
+                    }],
+                }
+            },
+        )?;
+
+        self.perform_checks(
+            &self.keys_dir_published,
+            &mut tpks,
+            &mut problems,
+            |_, tpk, primary_fp| {
+                // check that certificate exists in published wkd path
+                let path_wkd = self.fingerprint_to_path_published_wkd(primary_fp);
+                let should_wkd_exist = tpk.userids().next().is_some();
+
+                if should_wkd_exist && !path_wkd.exists() {
+                    vec![FsckProblem::MissingWkd {
+                        primary: primary_fp.clone(),
+                    }]
+                } else if !should_wkd_exist && path_wkd.exists() {
+                    vec![FsckProblem::SuperfluousWkd {
+                        primary: primary_fp.clone(),
+                    }]
+                } else {
+                    vec![]
+                }
+            },
+        )?;
+
+        // check that all subkeys are linked
+        self.perform_checks(
+            &self.keys_dir_published,
+            &mut tpks,
+            &mut problems,
+            |_, tpk, primary_fp| {
+                tpk_get_linkable_fprs(tpk, &self.policy)
+                    .into_iter()
+                    .filter_map(|fpr| match self.check_link_fpr(&fpr, primary_fp) {
+                        Ok(None) => None,
+                        Ok(Some(_)) => Some(FsckProblem::MissingFprLink {
+                            primary: primary_fp.clone(),
+                            fingerprint: fpr,
+                        }),
+                        Err(_) => Some(FsckProblem::FprCollision {
+                            primary: primary_fp.clone(),
+                            fingerprint: fpr,
+                        }),
+                    })
+                    .collect()
+            },
+        )?;
+
+        // check that all published uids are linked
+        self.perform_checks(
+            &self.keys_dir_published,
+            &mut tpks,
+            &mut problems,
+            |_, tpk, primary_fp| {
+                let mut problems = Vec::new();
+                for email in tpk_get_emails(tpk) {
+                    if !self.link_by_email(&email).exists() {
+                        problems.push(FsckProblem::MissingEmailLink {
+                            primary: primary_fp.clone(),
+                            email: email.clone(),
+                        });
+                    }
+                    if !self.link_wkd_by_email(&email).exists() {
+                        problems.push(FsckProblem::MissingWkdEmailLink {
+                            primary: primary_fp.clone(),
+                            email,
+                        });
+                    }
+                }
+                problems
+            },
+        )?;
+
+        for links_dir in &[&self.links_dir_by_fingerprint, &self.links_dir_by_keyid] {
+            self.perform_checks(
+                links_dir,
+                &mut tpks,
+                &mut problems,
+                |path, tpk, primary_fp| {
+                    // The KeyID corresponding with this path.
+                    let id = match Filesystem::path_to_keyid(path) {
+                        Some(id) => id,
+                        None => {
+                            return vec![FsckProblem::MalformedPath {
+                                path: path.to_owned(),
+                            }]
+                        }
+                    };
+
+                    let found = tpk
+                        .keys()
+                        .map(|amalgamation| {
+                            KeyID::try_from(amalgamation.key().fingerprint()).unwrap()
+                        })
+                        .any(|key_fp| key_fp == id);
+                    if found {
+                        vec![]
+                    } else {
+                        vec![FsckProblem::WrongLinkTarget {
+                            path: path.to_owned(),
+                            primary: primary_fp.clone(),
+                        }]
+                    }
+                },
+            )?;
         }
 
-        Ok(())
+        self.perform_checks(
+            &self.links_dir_by_email,
+            &mut tpks,
+            &mut problems,
+            |path, tpk, primary_fp| {
+                // The Email corresponding with this path.
+                let email = match Filesystem::path_to_email(path) {
+                    Some(email) => email,
+                    None => {
+                        return vec![FsckProblem::MalformedPath {
+                            path: path.to_owned(),
+                        }]
+                    }
+                };
+                if tpk_get_emails(tpk).contains(&email) {
+                    vec![]
+                } else {
+                    vec![FsckProblem::WrongLinkTarget {
+                        path: path.to_owned(),
+                        primary: primary_fp.clone(),
+                    }]
+                }
+            },
+        )?;
+
+        Ok(problems)
+    }
+
+    /// Fixes a problem found by `fsck`.
+    ///
+    /// Missing links and WKD copies are restored like in
+    /// `regenerate_links`, links to the wrong Cert are removed.
+    /// Returns whether the problem can be repaired: published Certs
+    /// stored in the wrong place, collisions and malformed paths need
+    /// manual intervention.
+    pub fn fsck_repair(&self, problem: &FsckProblem) -> Result<bool> {
+        match problem {
+            FsckProblem::MissingWkd { primary }
+            | FsckProblem::SuperfluousWkd { primary }
+            | FsckProblem::MissingFprLink { primary, .. }
+            | FsckProblem::MissingEmailLink { primary, .. }
+            | FsckProblem::MissingWkdEmailLink { primary, .. } => {
+                let tpk = self
+                    .by_primary_fpr(primary)
+                    .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok())
+                    .ok_or_else(|| anyhow!("Key not in database!"))?;
+                let _lock = self.lock_fprs(&tpk_get_fprs(&tpk))?;
+                let _lock_emails = self.lock_emails(&tpk_get_emails(&tpk))?;
+
+                self.regenerate_links(primary)?;
+                Ok(true)
+            }
+            FsckProblem::WrongLinkTarget { path, primary } => {
+                let tpk = self
+                    .by_primary_fpr(primary)
+                    .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok())
+                    .ok_or_else(|| anyhow!("Key not in database!"))?;
+                let _lock = self.lock_fprs(&tpk_get_fprs(&tpk))?;
+                let _lock_emails = self.lock_emails(&tpk_get_emails(&tpk))?;
+
+                // The link may have been fixed in the meantime.
+                if Filesystem::path_to_primary(path).as_ref() == Some(primary) {
+                    remove_file(path)?;
+                }
+                Ok(true)
+            }
+            FsckProblem::DanglingLink { path } => {
+                if Filesystem::path_to_primary(path)
+                    .and_then(|primary| self.by_primary_fpr(&primary))
+                    .is_none()
+                {
+                    remove_file(path)?;
+                }
+                Ok(true)
+            }
+            FsckProblem::WrongPublishedPath { .. }
+            | FsckProblem::FprCollision { .. }
+            | FsckProblem::MalformedPath { .. } => Ok(false),
+        }
     }
 }
 
@@ -388,11 +851,16 @@ fn symlink_unlink_with_check(link: &Path, expected: &Path) -> Result<()> {
 }
 
 impl Database for Filesystem {
-    type MutexGuard = FlockMutexGuard;
+    type MutexGuard = Vec<FlockMutexGuard>;
     type TempCert = NamedTempFile;
 
-    fn lock(&self) -> Result<Self::MutexGuard> {
-        FlockMutexGuard::lock(&self.keys_internal_dir)
+    fn lock_fprs(&self, fprs: &[Fingerprint]) -> Result<Self::MutexGuard> {
+        let names: Vec<String> = fprs.iter().map(|fpr| fpr.to_string()).collect();
+        self.fpr_locks.lock(names.iter().map(String::as_str))
+    }
+
+    fn lock_emails(&self, emails: &[Email]) -> Result<Self::MutexGuard> {
+        self.email_locks.lock(emails.iter().map(Email::as_str))
     }
 
     fn write_to_temp(&self, content: &[u8]) -> Result<Self::TempCert> {
@@ -404,17 +872,22 @@ impl Database for Filesystem {
         Ok(tempfile)
     }
 
-    fn write_log_append(&self, filename: &str, fpr_primary: &Fingerprint) -> Result<()> {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let fingerprint_line = format!("{:010} {}\n", timestamp, fpr_primary);
-
-        self.open_logfile(filename)?
-            .write_all(fingerprint_line.as_bytes())?;
+    fn write_log_append(
+        &self,
+        filename: &str,
+        fpr_primary: &Fingerprint,
+        details: &WriteLogDetails,
+    ) -> Result<()> {
+        write_log::append(&self.keys_dir_log, filename, fpr_primary, details)
+    }
 
-        Ok(())
+    fn read_write_log(
+        &self,
+        since: u64,
+        cursor: &WriteLogCursor,
+        limit: usize,
+    ) -> Result<(Vec<WriteLogEntry>, WriteLogCursor)> {
+        write_log::read_write_log(&self.keys_dir_log, since, cursor, limit)
     }
 
     fn move_tmp_to_full(&self, file: Self::TempCert, fpr: &Fingerprint) -> Result<()> {
@@ -424,7 +897,12 @@ impl Database for Filesystem {
         set_permissions(file.path(), Permissions::from_mode(0o640))?;
         let target = self.fingerprint_to_path_full(fpr);
         file.persist(ensure_parent(&target)?)?;
-        Ok(())
+        history::record_version(
+            &self.fingerprint_to_path_history(fpr),
+            CertVersionKind::Full,
+            &target,
+            self.history_retention,
+        )
     }
 
     fn move_tmp_to_published(&self, file: Self::TempCert, fpr: &Fingerprint) -> Result<()> {
@@ -434,7 +912,12 @@ impl Database for Filesystem {
         set_permissions(file.path(), Permissions::from_mode(0o644))?;
         let target = self.fingerprint_to_path_published(fpr);
         file.persist(ensure_parent(&target)?)?;
-        Ok(())
+        history::record_version(
+            &self.fingerprint_to_path_history(fpr),
+            CertVersionKind::Published,
+            &target,
+            self.history_retention,
+        )
     }
 
     fn move_tmp_to_published_wkd(
@@ -456,19 +939,86 @@ impl Database for Filesystem {
         Ok(())
     }
 
-    fn write_to_quarantine(&self, fpr: &Fingerprint, content: &[u8]) -> Result<()> {
-        let mut tempfile = tempfile::Builder::new()
-            .prefix("key")
-            .rand_bytes(16)
-            .tempfile_in(&self.tmp_dir)?;
-        tempfile.write_all(content).unwrap();
-
+    fn write_to_quarantine(
+        &self,
+        fpr: &Fingerprint,
+        content: &[u8],
+        reason: &QuarantineReason,
+    ) -> Result<()> {
         let target = self.fingerprint_to_path_quarantined(fpr);
-        tempfile.persist(ensure_parent(&target)?)?;
+        let target_reason = target.with_extension("reason");
+
+        self.write_to_temp(content)?
+            .persist(ensure_parent(&target)?)?;
+        self.write_to_temp(&serde_json::to_vec(reason)?)?
+            .persist(ensure_parent(&target_reason)?)?;
+
+        Ok(())
+    }
+
+    fn list_quarantined(&self) -> Result<Vec<QuarantinedCert>> {
+        use std::str::FromStr;
+
+        let mut quarantined = Vec::new();
+        for entry in read_dir(&self.keys_dir_quarantined)? {
+            let path = entry?.path();
+            let fingerprint = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| Fingerprint::from_str(name).ok())
+            {
+                Some(fingerprint) => fingerprint,
+                None => continue,
+            };
+            let reason = match read(path.with_extension("reason")) {
+                Ok(reason) => Some(serde_json::from_slice(&reason)?),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+
+            quarantined.push(QuarantinedCert {
+                fingerprint,
+                reason,
+                content: String::from_utf8(read(&path)?)?,
+            });
+        }
+
+        Ok(quarantined)
+    }
+
+    fn remove_quarantined(&self, fpr: &Fingerprint) -> Result<()> {
+        let path = self.fingerprint_to_path_quarantined(fpr);
+        if !path.exists() {
+            return Err(anyhow!("Key not in quarantine!"));
+        }
+
+        let path_reason = path.with_extension("reason");
+        if path_reason.exists() {
+            remove_file(path_reason)?;
+        }
+        remove_file(path)?;
 
         Ok(())
     }
 
+    fn remove_tpk(&self, fpr: &Fingerprint) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+
+        for path in &[
+            self.fingerprint_to_path_full(fpr),
+            self.fingerprint_to_path_published(fpr),
+            self.fingerprint_to_path_published_wkd(fpr),
+        ] {
+            if path.exists() {
+                remove_file(path)?;
+            }
+        }
+
+        history::remove_versions(&self.fingerprint_to_path_history(fpr))
+    }
+
     fn check_link_fpr(
         &self,
         fpr: &Fingerprint,
@@ -616,165 +1166,60 @@ impl Database for Filesystem {
         self.read_from_path(&path, false)
     }
 
-    /// Checks the database for consistency.
-    ///
-    /// Note that this operation may take a long time, and is
-    /// generally only useful for testing.
-    fn check_consistency(&self) -> Result<()> {
-        // A cache of all Certs, for quick lookups.
-        let mut tpks = HashMap::new();
+    /// Writes the journal entry for an operation on `fpr_primary`,
+    /// replacing any previous entry.
+    fn journal_begin(&self, fpr_primary: &Fingerprint, tpk: &Cert) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
 
-        self.perform_checks(
-            &self.keys_dir_published,
-            &mut tpks,
-            |path, _, primary_fp| {
-                // The KeyID corresponding with this path.
-                let fp = Filesystem::path_to_fingerprint(path)
-                    .ok_or_else(|| format_err!("Malformed path: {:?}", path))?;
-
-                if fp != *primary_fp {
-                    return Err(format_err!(
-                        "{:?} points to the wrong Cert, expected {} \
-                            but found {}",
-                        path,
-                        fp,
-                        primary_fp
-                    ));
-                }
-                Ok(())
-            },
-        )?;
+        let intent = JournalIntent {
+            fingerprints: tpk_get_fprs(tpk),
+            emails: tpk_get_emails(tpk),
+        };
 
-        self.perform_checks(&self.keys_dir_published, &mut tpks, |_, tpk, primary_fp| {
-            // check that certificate exists in published wkd path
-            let path_wkd = self.fingerprint_to_path_published_wkd(primary_fp);
-            let should_wkd_exist = tpk.userids().next().is_some();
+        let mut tempfile = tempfile::Builder::new()
+            .prefix("journal")
+            .rand_bytes(16)
+            .tempfile_in(&self.tmp_dir)?;
+        tempfile.write_all(&serde_json::to_vec(&intent)?)?;
+        tempfile.as_file().sync_all()?;
+        tempfile.persist(self.journal_dir.join(fpr_primary.to_string()))?;
 
-            if should_wkd_exist && !path_wkd.exists() {
-                return Err(format_err!("Missing wkd for fp {}", primary_fp));
-            };
-            if !should_wkd_exist && path_wkd.exists() {
-                return Err(format_err!("Incorrectly present wkd for fp {}", primary_fp));
-            };
-            Ok(())
-        })?;
+        Ok(())
+    }
 
-        // check that all subkeys are linked
-        self.perform_checks(&self.keys_dir_published, &mut tpks, |_, tpk, primary_fp| {
-            let policy = &POLICY;
-            let fingerprints = tpk
-                .keys()
-                .with_policy(policy, None)
-                .for_certification()
-                .for_signing()
-                .map(|amalgamation| amalgamation.key().fingerprint())
-                .map(Fingerprint::try_from)
-                .flatten();
-
-            for fpr in fingerprints {
-                if let Some(missing_fpr) = self.check_link_fpr(&fpr, primary_fp)? {
-                    return Err(format_err!(
-                        "Missing link to key {} for sub {}",
-                        primary_fp,
-                        missing_fpr
-                    ));
-                }
-            }
-            Ok(())
-        })?;
+    fn journal_commit(&self, fpr_primary: &Fingerprint) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
 
-        // check that all published uids are linked
-        self.perform_checks(&self.keys_dir_published, &mut tpks, |_, tpk, primary_fp| {
-            let emails = tpk
-                .userids()
-                .map(|binding| binding.userid().clone())
-                .map(|userid| Email::try_from(&userid).unwrap());
-
-            for email in emails {
-                let email_path = self.link_by_email(&email);
-                if !email_path.exists() {
-                    return Err(format_err!(
-                        "Missing link to key {} for email {}",
-                        primary_fp,
-                        email
-                    ));
-                }
-                let email_wkd_path = self.link_wkd_by_email(&email);
-                if !email_wkd_path.exists() {
-                    return Err(format_err!(
-                        "Missing wkd link to key {} for email {}",
-                        primary_fp,
-                        email
-                    ));
-                }
-            }
-            Ok(())
-        })?;
-
-        self.perform_checks(&self.links_dir_by_fingerprint, &mut tpks, |path, tpk, _| {
-            // The KeyID corresponding with this path.
-            let id = Filesystem::path_to_keyid(path)
-                .ok_or_else(|| format_err!("Malformed path: {:?}", path))?;
-
-            let found = tpk
-                .keys()
-                .map(|amalgamation| KeyID::try_from(amalgamation.key().fingerprint()).unwrap())
-                .any(|key_fp| key_fp == id);
-            if !found {
-                return Err(format_err!(
-                    "{:?} points to the wrong Cert, the Cert does not \
-                            contain the (sub)key {}",
-                    path,
-                    id
-                ));
-            }
-            Ok(())
-        })?;
-
-        self.perform_checks(&self.links_dir_by_keyid, &mut tpks, |path, tpk, _| {
-            // The KeyID corresponding with this path.
-            let id = Filesystem::path_to_keyid(path)
-                .ok_or_else(|| format_err!("Malformed path: {:?}", path))?;
-
-            let found = tpk
-                .keys()
-                .map(|amalgamation| KeyID::try_from(amalgamation.key().fingerprint()).unwrap())
-                .any(|key_fp| key_fp == id);
-            if !found {
-                return Err(format_err!(
-                    "{:?} points to the wrong Cert, the Cert does not \
-                            contain the (sub)key {}",
-                    path,
-                    id
-                ));
-            }
-            Ok(())
-        })?;
-
-        self.perform_checks(&self.links_dir_by_email, &mut tpks, |path, tpk, _| {
-            // The Email corresponding with this path.
-            let email = Filesystem::path_to_email(path)
-                .ok_or_else(|| format_err!("Malformed path: {:?}", path))?;
-            let mut found = false;
-            for uidb in tpk.userids() {
-                if Email::try_from(uidb.userid()).unwrap() == email {
-                    found = true;
-                    break;
-                }
-            }
-            if !found {
-                return Err(format_err!(
-                    "{:?} points to the wrong Cert, the Cert does not \
-                            contain the email {}",
-                    path,
-                    email
-                ));
-            }
-            Ok(())
-        })?;
+        let path = self.journal_dir.join(fpr_primary.to_string());
+        if path.exists() {
+            remove_file(path)?;
+        }
 
         Ok(())
     }
+
+    fn cert_history(&self, fpr_primary: &Fingerprint) -> Result<Vec<CertVersion>> {
+        history::read_versions(&self.fingerprint_to_path_history(fpr_primary))
+    }
+
+    fn policy(&self) -> &CertPolicy {
+        &self.policy
+    }
+
+    /// Checks the database for consistency.
+    ///
+    /// Note that this operation may take a long time, and is
+    /// generally only useful for testing.
+    fn check_consistency(&self) -> Result<()> {
+        match self.fsck()?.into_iter().next() {
+            Some(problem) => Err(anyhow!("{}", problem)),
+            None => Ok(()),
+        }
+    }
 }
 
 fn path_split(path: &str) -> PathBuf {
@@ -801,8 +1246,11 @@ fn path_merge(path: &Path) -> String {
 mod tests {
     use super::*;
     use openpgp::cert::CertBuilder;
+    use openpgp_utils::tpk_to_string;
+    use std::str::FromStr;
     use tempfile::TempDir;
     use test;
+    use PolicyConfig;
 
     #[test]
     fn init() {
@@ -867,6 +1315,221 @@ mod tests {
         assert!(!db.merge(k3).unwrap().into_tpk_status().email_status.len() > 0);
     }
 
+    #[test]
+    fn journal_replay_interrupted_unpublish() {
+        let (tmp_dir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let email = Email::from_str("a@invalid.example.org").unwrap();
+        db.merge(tpk.clone()).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+
+        // Crash after the published Cert was replaced, but before the
+        // email links were removed.
+        let unpublished = tpk.clone().retain_userids(|_| false);
+        db.journal_begin(&fpr, &tpk).unwrap();
+        let tmp = db
+            .write_to_temp(&tpk_to_string(&unpublished).unwrap())
+            .unwrap();
+        db.move_tmp_to_published(tmp, &fpr).unwrap();
+        assert!(db.check_consistency().is_err());
+        drop(db);
+
+        let db = Filesystem::new_from_base(tmp_dir.path()).unwrap();
+        db.check_consistency().expect("inconsistent database");
+        assert!(db.by_email(&email).is_none());
+        assert!(db.by_email_wkd(&email).is_none());
+        assert!(db.by_fpr(&fpr).is_some());
+        assert_eq!(read_dir(&db.journal_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn journal_replay_interrupted_merge() {
+        let (tmp_dir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .add_signing_subkey()
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let fpr_sign =
+            Fingerprint::try_from(tpk.keys().subkeys().next().unwrap().fingerprint()).unwrap();
+
+        // Crash after the Certs were written, but before the subkey
+        // was linked.
+        db.merge(tpk.clone()).unwrap();
+        db.journal_begin(&fpr, &tpk).unwrap();
+        remove_file(db.link_by_fingerprint(&fpr_sign)).unwrap();
+        remove_file(db.link_by_keyid(&(&fpr_sign).into())).unwrap();
+        assert!(db.by_fpr(&fpr_sign).is_none());
+        drop(db);
+
+        let db = Filesystem::new_from_base(tmp_dir.path()).unwrap();
+        db.check_consistency().expect("inconsistent database");
+        assert!(db.by_fpr(&fpr_sign).is_some());
+        assert!(db.by_kid(&(&fpr_sign).into()).is_some());
+        assert_eq!(read_dir(&db.journal_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn journal_replay_finished_elsewhere() {
+        let (_tmp_dir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        db.merge(tpk.clone()).unwrap();
+
+        // Another process completed the operation after this one
+        // listed the journal.
+        let path = db.journal_dir.join(fpr.to_string());
+        db.replay_journal_entry(&path, &fpr).unwrap();
+
+        // Or it is still pending, and gets replayed.
+        db.journal_begin(&fpr, &tpk).unwrap();
+        db.replay_journal_entry(&path, &fpr).unwrap();
+        assert!(!path.exists());
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn journal_replay_uses_configured_policy() {
+        let (tmp_dir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .add_transport_encryption_subkey()
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let fpr_enc =
+            Fingerprint::try_from(tpk.keys().subkeys().next().unwrap().fingerprint()).unwrap();
+        db.merge(tpk.clone()).unwrap();
+        db.journal_begin(&fpr, &tpk).unwrap();
+        assert!(db.by_fpr(&fpr_enc).is_none());
+        drop(db);
+
+        // The configured policy rejects the binding signature of the
+        // encryption subkey, so its key flags are unknown, and it is
+        // linked.
+        let config = PolicyConfig {
+            hash_cutoffs: vec![("SHA512".to_owned(), "2000-01-01".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let keys_dir = tmp_dir.path().join("keys");
+        let db = Filesystem::new_internal(
+            &keys_dir,
+            &keys_dir,
+            tmp_dir.path().join("tmp"),
+            history::DEFAULT_HISTORY_RETENTION,
+            CertPolicy::from_config(&config).unwrap(),
+            false,
+        )
+        .unwrap();
+        db.check_consistency().expect("inconsistent database");
+        assert!(db.by_fpr(&fpr_enc).is_some());
+        assert_eq!(read_dir(&db.journal_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn fsck_repair() {
+        let (_tmp_dir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .add_signing_subkey()
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let fpr_sign =
+            Fingerprint::try_from(tpk.keys().subkeys().next().unwrap().fingerprint()).unwrap();
+        let email = Email::from_str("a@invalid.example.org").unwrap();
+        db.merge(tpk).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+        assert!(db.fsck().unwrap().is_empty());
+
+        let other = CertBuilder::new().generate().unwrap().0;
+        let other_fpr = Fingerprint::try_from(other.fingerprint()).unwrap();
+        remove_file(db.fingerprint_to_path_published_wkd(&fpr)).unwrap();
+        remove_file(db.link_by_fingerprint(&fpr_sign)).unwrap();
+        remove_file(db.link_wkd_by_email(&email)).unwrap();
+        db.link_fpr(&other_fpr, &fpr).unwrap();
+
+        let problems = db.fsck().unwrap();
+        for problem in &[
+            FsckProblem::MissingWkd {
+                primary: fpr.clone(),
+            },
+            FsckProblem::MissingFprLink {
+                primary: fpr.clone(),
+                fingerprint: fpr_sign,
+            },
+            FsckProblem::MissingWkdEmailLink {
+                primary: fpr.clone(),
+                email,
+            },
+            FsckProblem::WrongLinkTarget {
+                path: db.link_by_fingerprint(&other_fpr),
+                primary: fpr,
+            },
+        ] {
+            assert!(problems.contains(problem), "{} not found", problem);
+        }
+        assert!(db.check_consistency().is_err());
+
+        for problem in problems {
+            assert!(db.fsck_repair(&problem).unwrap());
+        }
+        assert_eq!(db.fsck().unwrap(), vec![]);
+        assert!(db.by_fpr(&other_fpr).is_none());
+    }
+
+    #[test]
+    fn cert_history() {
+        let (_tmp_dir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let email = Email::from_str("a@invalid.example.org").unwrap();
+        db.merge(tpk).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+
+        let kinds: Vec<CertVersionKind> = db
+            .cert_history(&fpr)
+            .unwrap()
+            .into_iter()
+            .map(|version| version.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                CertVersionKind::Full,
+                CertVersionKind::Published,
+                CertVersionKind::Published
+            ]
+        );
+
+        // The history outlives replaced versions, but not deletion.
+        let versions = db.cert_history(&fpr).unwrap();
+        assert_ne!(versions[1].content, versions[2].content);
+        assert_eq!(Some(&versions[2].content), db.by_fpr(&fpr).as_ref());
+
+        db.delete_cert(&fpr).unwrap();
+        assert!(db.cert_history(&fpr).unwrap().is_empty());
+    }
+
     #[test]
     fn uid_verification() {
         let (_tmp_dir, mut db, log_path) = open_db();
@@ -881,6 +1544,27 @@ mod tests {
         db.check_consistency().expect("inconsistent database");
     }
 
+    #[test]
+    fn quarantine() {
+        let (_tmp_dir, mut db, _log_path) = open_db();
+        test::test_quarantine(&mut db);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn delete_cert() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_delete_cert(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn read_write_log() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_read_write_log(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
     #[test]
     fn subkey_lookup() {
         let (_tmp_dir, mut db, log_path) = open_db();
@@ -0,0 +1,271 @@
+use std::fs::{
+    create_dir_all, hard_link, read, read_dir, read_to_string, remove_dir_all, remove_file,
+};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use Result;
+
+/// How long past versions of a Cert are kept, unless configured
+/// otherwise.
+pub const DEFAULT_HISTORY_RETENTION: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Which copy of a Cert a version was taken from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertVersionKind {
+    Full,
+    Published,
+}
+
+impl CertVersionKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CertVersionKind::Full => "full",
+            CertVersionKind::Published => "published",
+        }
+    }
+}
+
+impl FromStr for CertVersionKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(CertVersionKind::Full),
+            "published" => Ok(CertVersionKind::Published),
+            _ => Err(anyhow!("Unknown cert version kind: {}", s)),
+        }
+    }
+}
+
+/// A version of a Cert, as it was stored at some point in time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertVersion {
+    pub timestamp: u64,
+    pub kind: CertVersionKind,
+    pub content: String,
+}
+
+/// The name of a version in the history directory of a Cert.
+///
+/// Formatted as `<timestamp>.<sequence>.<kind>`, where the sequence
+/// number orders versions of any kind stored within the same second.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct VersionName {
+    timestamp: u64,
+    sequence: u32,
+    kind: String,
+}
+
+impl VersionName {
+    fn parse(name: &str) -> Option<Self> {
+        let mut fields = name.splitn(3, '.');
+        let timestamp = fields.next()?.parse().ok()?;
+        let sequence = fields.next()?.parse().ok()?;
+        let kind = fields.next()?;
+        CertVersionKind::from_str(kind).ok()?;
+        Some(VersionName {
+            timestamp,
+            sequence,
+            kind: kind.to_owned(),
+        })
+    }
+
+    fn file_name(&self) -> String {
+        format!("{:010}.{}.{}", self.timestamp, self.sequence, self.kind)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn version_names(cert_dir: &Path) -> Result<Vec<VersionName>> {
+    let entries = match read_dir(cert_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names: Vec<VersionName> = entries
+        .flatten()
+        .flat_map(|entry| entry.file_name().into_string())
+        .flat_map(|name| VersionName::parse(&name))
+        .collect();
+    names.sort_unstable();
+    Ok(names)
+}
+
+/// Adds the Cert stored at `path` to the history in `cert_dir`.
+///
+/// The version is hard linked, so it stays intact when `path` is
+/// replaced.  Nothing is added if the content is the same as the
+/// latest version of the same kind.  Afterwards, versions of the
+/// same kind older than `retention` are removed, except for the
+/// latest one.
+pub fn record_version(
+    cert_dir: &Path,
+    kind: CertVersionKind,
+    path: &Path,
+    retention: Duration,
+) -> Result<()> {
+    create_dir_all(cert_dir)?;
+
+    let names = version_names(cert_dir)?;
+    let latest = names.iter().rev().find(|name| name.kind == kind.as_str());
+    let unchanged = match latest {
+        Some(latest) => read(cert_dir.join(latest.file_name()))? == read(path)?,
+        None => false,
+    };
+
+    let latest = if unchanged {
+        latest.unwrap().clone()
+    } else {
+        let timestamp = now();
+        let sequence = names
+            .iter()
+            .filter(|name| name.timestamp == timestamp)
+            .map(|name| name.sequence + 1)
+            .max()
+            .unwrap_or(0);
+        let mut name = VersionName {
+            timestamp,
+            sequence,
+            kind: kind.as_str().to_owned(),
+        };
+        // The history is append-only, never replace an existing version.
+        loop {
+            match hard_link(path, cert_dir.join(name.file_name())) {
+                Ok(()) => break,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => name.sequence += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        name
+    };
+
+    let cutoff = now().saturating_sub(retention.as_secs());
+    for old in version_names(cert_dir)? {
+        if old.kind == latest.kind && old.timestamp < cutoff && old != latest {
+            remove_file(cert_dir.join(old.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns all versions in `cert_dir`, oldest first.
+pub fn read_versions(cert_dir: &Path) -> Result<Vec<CertVersion>> {
+    version_names(cert_dir)?
+        .into_iter()
+        .map(|name| {
+            Ok(CertVersion {
+                timestamp: name.timestamp,
+                kind: name.kind.parse()?,
+                content: read_to_string(cert_dir.join(name.file_name()))?,
+            })
+        })
+        .collect()
+}
+
+/// Removes the whole history in `cert_dir`.
+pub fn remove_versions(cert_dir: &Path) -> Result<()> {
+    match remove_dir_all(cert_dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{rename, write};
+    use tempfile::TempDir;
+
+    const RETENTION: Duration = DEFAULT_HISTORY_RETENTION;
+
+    #[test]
+    fn append_only() {
+        let tmpdir = TempDir::new().unwrap();
+        let cert_dir = tmpdir.path().join("history");
+        let current = tmpdir.path().join("current");
+
+        write(&current, "v1").unwrap();
+        record_version(&cert_dir, CertVersionKind::Full, &current, RETENTION).unwrap();
+        // Replace the current version, as persisting a tempfile does.
+        let next = tmpdir.path().join("next");
+        write(&next, "v2").unwrap();
+        rename(&next, &current).unwrap();
+        record_version(&cert_dir, CertVersionKind::Full, &current, RETENTION).unwrap();
+        record_version(&cert_dir, CertVersionKind::Published, &current, RETENTION).unwrap();
+
+        let versions = read_versions(&cert_dir).unwrap();
+        let contents: Vec<&str> = versions.iter().map(|v| v.content.as_str()).collect();
+        assert_eq!(contents, vec!["v1", "v2", "v2"]);
+        assert_eq!(versions[2].kind, CertVersionKind::Published);
+
+        remove_versions(&cert_dir).unwrap();
+        assert!(read_versions(&cert_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn retention() {
+        let tmpdir = TempDir::new().unwrap();
+        let cert_dir = tmpdir.path().join("history");
+        let current = tmpdir.path().join("current");
+        write(&current, "new").unwrap();
+
+        create_dir_all(&cert_dir).unwrap();
+        write(cert_dir.join("0000000001.0.full"), "old").unwrap();
+        write(cert_dir.join("0000000001.0.published"), "old").unwrap();
+
+        let retention = Duration::from_secs(3600);
+        record_version(&cert_dir, CertVersionKind::Full, &current, retention).unwrap();
+
+        let versions = read_versions(&cert_dir).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].kind, CertVersionKind::Published);
+        assert_eq!(versions[1].content, "new");
+    }
+
+    #[test]
+    fn unchanged_versions_are_not_recorded() {
+        let tmpdir = TempDir::new().unwrap();
+        let cert_dir = tmpdir.path().join("history");
+        let current = tmpdir.path().join("current");
+        // Replace the current version, as persisting a tempfile does.
+        let replace = |content: &str| {
+            let next = tmpdir.path().join("next");
+            write(&next, content).unwrap();
+            rename(&next, &current).unwrap();
+        };
+
+        replace("v1");
+        record_version(&cert_dir, CertVersionKind::Published, &current, RETENTION).unwrap();
+        record_version(&cert_dir, CertVersionKind::Published, &current, RETENTION).unwrap();
+        record_version(&cert_dir, CertVersionKind::Full, &current, RETENTION).unwrap();
+        replace("v2");
+        record_version(&cert_dir, CertVersionKind::Published, &current, RETENTION).unwrap();
+        replace("v1");
+        record_version(&cert_dir, CertVersionKind::Published, &current, RETENTION).unwrap();
+
+        let versions = read_versions(&cert_dir).unwrap();
+        let contents: Vec<(CertVersionKind, &str)> = versions
+            .iter()
+            .map(|v| (v.kind, v.content.as_str()))
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                (CertVersionKind::Published, "v1"),
+                (CertVersionKind::Full, "v1"),
+                (CertVersionKind::Published, "v2"),
+                (CertVersionKind::Published, "v1"),
+            ]
+        );
+    }
+}
@@ -18,6 +18,7 @@ extern crate chrono;
 extern crate hex;
 extern crate pathdiff;
 extern crate rand;
+extern crate rusqlite;
 extern crate serde;
 extern crate serde_json;
 extern crate tempfile;
@@ -27,7 +28,7 @@ extern crate walkdir;
 extern crate zbase32;
 
 extern crate sequoia_openpgp as openpgp;
-use openpgp::{packet::UserID, parse::Parse, types::KeyFlags, Cert};
+use openpgp::{packet::UserID, parse::Parse, policy::Policy, types::KeyFlags, Cert};
 
 pub mod types;
 use types::{Email, Fingerprint, KeyID};
@@ -36,13 +37,40 @@ pub mod sync;
 pub mod wkd;
 
 mod fs;
-pub use self::fs::Filesystem as KeyDatabase;
+pub use self::fs::{Filesystem, FsckProblem};
+
+mod sqlite;
+pub use self::sqlite::Sqlite;
+
+mod memory;
+pub use self::memory::MemoryDatabase;
+
+mod backend;
+pub use self::backend::{DatabaseConfig, KeyDatabase, KeyDatabaseGuard, KeyDatabaseTempCert};
+
+mod history;
+pub use self::history::{CertVersion, CertVersionKind, DEFAULT_HISTORY_RETENTION};
+
+mod quarantine;
+pub use self::quarantine::{FingerprintCollision, QuarantineReason, QuarantinedCert};
+
+mod write_log;
+pub use self::write_log::{WriteLogCursor, WriteLogDetails, WriteLogEntry, WriteLogOp};
 
 mod stateful_tokens;
-pub use stateful_tokens::StatefulTokens;
+pub use stateful_tokens::{FileTokenStore, StatefulTokens, TokenStore};
+
+mod sqlite_tokens;
+pub use sqlite_tokens::SqliteTokenStore;
+
+mod policy;
+pub use policy::{CertPolicy, PolicyConfig};
 
 mod openpgp_utils;
-use openpgp_utils::{is_status_revoked, tpk_clean, tpk_filter_alive_emails, tpk_to_string, POLICY};
+use openpgp_utils::{
+    is_status_revoked, tpk_cap_certifications, tpk_clean, tpk_compact, tpk_filter_alive_emails,
+    tpk_to_string, tpk_without_new_revocations,
+};
 
 #[cfg(test)]
 mod test;
@@ -124,11 +152,22 @@ pub trait Database: Sync + Send {
     type MutexGuard;
     type TempCert;
 
-    /// Lock the DB for a complex update.
+    /// Lock the given Certs for a complex update.
+    ///
+    /// Locks are taken in two phases: first all fingerprints of the
+    /// Certs involved, then all email addresses involved, each with a
+    /// single call.  Within a call, locks are acquired in a fixed
+    /// order, so concurrent updates cannot deadlock.
     ///
     /// All basic write operations are atomic so we don't need to lock
     /// read operations to ensure that we return something sane.
-    fn lock(&self) -> Result<Self::MutexGuard>;
+    fn lock_fprs(&self, fprs: &[Fingerprint]) -> Result<Self::MutexGuard>;
+
+    /// Lock the given email addresses for a complex update.
+    ///
+    /// See `lock_fprs`.  Must not be followed by another call to
+    /// `lock_fprs` or `lock_emails` while the guard is held.
+    fn lock_emails(&self, emails: &[Email]) -> Result<Self::MutexGuard>;
 
     /// Queries the database using Fingerprint, KeyID, or
     /// email-address, returning the primary fingerprint.
@@ -163,11 +202,49 @@ pub trait Database: Sync + Send {
         content: Option<Self::TempCert>,
         fpr: &Fingerprint,
     ) -> Result<()>;
-    fn write_to_quarantine(&self, fpr: &Fingerprint, content: &[u8]) -> Result<()>;
-    fn write_log_append(&self, filename: &str, fpr_primary: &Fingerprint) -> Result<()>;
+    fn write_to_quarantine(
+        &self,
+        fpr: &Fingerprint,
+        content: &[u8],
+        reason: &QuarantineReason,
+    ) -> Result<()>;
+    /// Returns all quarantined Certs.
+    fn list_quarantined(&self) -> Result<Vec<QuarantinedCert>>;
+    /// Removes a Cert from the quarantine.
+    fn remove_quarantined(&self, fpr: &Fingerprint) -> Result<()>;
+    /// Removes the full, published and WKD copies of a Cert, and its
+    /// history.
+    fn remove_tpk(&self, fpr: &Fingerprint) -> Result<()>;
+    fn write_log_append(
+        &self,
+        filename: &str,
+        fpr_primary: &Fingerprint,
+        details: &WriteLogDetails,
+    ) -> Result<()>;
+    /// Reads up to `limit` entries of the write log, starting at
+    /// `cursor` and skipping entries older than `since`.
+    ///
+    /// Returns the entries, and the cursor to continue from.
+    fn read_write_log(
+        &self,
+        since: u64,
+        cursor: &WriteLogCursor,
+        limit: usize,
+    ) -> Result<(Vec<WriteLogEntry>, WriteLogCursor)>;
 
     fn check_consistency(&self) -> Result<()>;
 
+    /// Returns the policy deciding which Certs are accepted, and which
+    /// of their components are valid.
+    fn policy(&self) -> &CertPolicy;
+
+    /// Returns the stored versions of a Cert, oldest first.
+    ///
+    /// Backends that keep no history return an empty list.
+    fn cert_history(&self, _fpr_primary: &Fingerprint) -> Result<Vec<CertVersion>> {
+        Ok(Vec::new())
+    }
+
     /// Queries the database using Fingerprint, KeyID, or
     /// email-address.
     fn lookup(&self, term: &Query) -> Result<Option<Cert>> {
@@ -188,6 +265,8 @@ pub trait Database: Sync + Send {
     /// Complex operation that updates a Cert in the database.
     ///
     /// 1. Merge new Cert with old, full Cert
+    ///    - remove superseded self-signatures
+    ///    - check the new full Cert against the policy
     ///    - if old full Cert == new full Cert, stop
     /// 2. Prepare new published Cert
     ///    - retrieve UserIDs from old published Cert
@@ -200,7 +279,10 @@ pub trait Database: Sync + Send {
     fn merge(&self, new_tpk: Cert) -> Result<ImportResult> {
         let fpr_primary = Fingerprint::try_from(new_tpk.primary_key().fingerprint())?;
 
-        let _lock = self.lock()?;
+        // Keys only found in the old full Cert have been linked by the
+        // merge that brought them in, so locking the uploaded keys is
+        // enough to guard against fingerprint collisions.
+        let _lock = self.lock_fprs(&tpk_get_fprs(&new_tpk))?;
 
         let known_uids: Vec<UserID> = new_tpk
             .userids()
@@ -211,15 +293,25 @@ pub trait Database: Sync + Send {
             .by_fpr_full(&fpr_primary)
             .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok());
         let is_update = full_tpk_old.is_some();
-        let (full_tpk_new, full_tpk_unchanged) = if let Some(full_tpk_old) = full_tpk_old {
-            let full_tpk_new = new_tpk.merge_public(full_tpk_old.clone())?;
-            let full_tpk_unchanged = full_tpk_new == full_tpk_old;
+        let policy = self.policy();
+        let (full_tpk_new, full_tpk_unchanged) = if let Some(ref full_tpk_old) = full_tpk_old {
+            let full_tpk_new = tpk_compact(new_tpk.merge_public(full_tpk_old.clone())?, policy)?;
+            let full_tpk_new = tpk_cap_certifications(
+                full_tpk_new,
+                Some(full_tpk_old),
+                policy.max_certifications(),
+                policy,
+            )?;
+            let full_tpk_unchanged = &full_tpk_new == full_tpk_old;
             (full_tpk_new, full_tpk_unchanged)
         } else {
-            (new_tpk, false)
+            let full_tpk_new = tpk_compact(new_tpk, policy)?;
+            let full_tpk_new =
+                tpk_cap_certifications(full_tpk_new, None, policy.max_certifications(), policy)?;
+            (full_tpk_new, false)
         };
 
-        let is_revoked = is_status_revoked(full_tpk_new.revocation_status(&POLICY, None));
+        let is_revoked = is_status_revoked(full_tpk_new.revocation_status(policy, None));
 
         let is_ok = is_revoked
             || full_tpk_new.keys().subkeys().next().is_some()
@@ -228,6 +320,13 @@ pub trait Database: Sync + Send {
             // self.write_to_quarantine(&fpr_primary, &tpk_to_string(&full_tpk_new)?)?;
             return Err(anyhow!("Not a well-formed key!"));
         }
+        // The owner must always be able to revoke the Cert or its
+        // components, even if it has grown up to the limits.  So the
+        // limits apply to the Cert without the new revocations.
+        policy.check_cert(
+            &tpk_without_new_revocations(full_tpk_new.clone(), full_tpk_old.as_ref())?,
+            is_revoked,
+        )?;
 
         let published_tpk_old = self
             .by_fpr(&fpr_primary)
@@ -236,6 +335,8 @@ pub trait Database: Sync + Send {
             .as_ref()
             .map(tpk_get_emails)
             .unwrap_or_default();
+        let published_hash_old = self.published_hash(&fpr_primary);
+        let _lock_emails = self.lock_emails(&published_emails)?;
 
         let unparsed_uids = full_tpk_new
             .userids()
@@ -257,7 +358,7 @@ pub trait Database: Sync + Send {
                 known_uids.contains(binding.userid()) || published_emails.contains(email)
             })
             .flat_map(|(binding, email)| {
-                if is_status_revoked(binding.revocation_status(&POLICY, None)) {
+                if is_status_revoked(binding.revocation_status(policy, None)) {
                     Some((email, EmailAddressStatus::Revoked))
                 } else if !is_revoked && published_emails.contains(&email) {
                     Some((email, EmailAddressStatus::Published))
@@ -281,17 +382,21 @@ pub trait Database: Sync + Send {
         }
 
         let published_tpk_new = if is_revoked {
-            tpk_filter_alive_emails(&full_tpk_new, &[])
+            tpk_filter_alive_emails(&full_tpk_new, &[], policy)
         } else {
-            tpk_filter_alive_emails(&full_tpk_new, &published_emails)
+            tpk_filter_alive_emails(&full_tpk_new, &published_emails, policy)
         };
 
-        let newly_revoked_emails: Vec<&Email> = published_emails
+        let published_tpk_clean = tpk_clean(&published_tpk_new, policy)?;
+
+        // Merging never publishes addresses, but unpublishes those
+        // whose user ids are revoked or gone.
+        let unpublished_emails: Vec<&Email> = published_emails
             .iter()
             .filter(|email| {
-                let has_unrevoked_userid = published_tpk_new
+                let has_unrevoked_userid = published_tpk_clean
                     .userids()
-                    .filter(|binding| !is_status_revoked(binding.revocation_status(&POLICY, None)))
+                    .filter(|binding| !is_status_revoked(binding.revocation_status(policy, None)))
                     .map(|binding| binding.userid())
                     .map(|uid| Email::try_from(uid).ok())
                     .flatten()
@@ -300,7 +405,7 @@ pub trait Database: Sync + Send {
             })
             .collect();
 
-        let fingerprints = tpk_get_linkable_fprs(&published_tpk_new);
+        let fingerprints = tpk_get_linkable_fprs(&published_tpk_new, policy);
 
         let fpr_checks = fingerprints
             .iter()
@@ -309,44 +414,74 @@ pub trait Database: Sync + Send {
             .into_iter()
             .collect::<Result<Vec<_>>>();
 
-        if fpr_checks.is_err() {
-            self.write_to_quarantine(&fpr_primary, &tpk_to_string(&full_tpk_new)?)?;
+        if let Err(ref e) = fpr_checks {
+            let collisions = fingerprints
+                .iter()
+                .filter_map(|fpr| {
+                    let by_fpr =
+                        self.lookup_primary_fingerprint(&Query::ByFingerprint(fpr.clone()));
+                    let by_kid = self.lookup_primary_fingerprint(&Query::ByKeyID(fpr.into()));
+                    by_fpr
+                        .into_iter()
+                        .chain(by_kid)
+                        .find(|primary| primary != &fpr_primary)
+                        .map(|primary| FingerprintCollision {
+                            fingerprint: fpr.clone(),
+                            primary,
+                        })
+                })
+                .collect();
+            let reason = QuarantineReason::new(e, collisions);
+            self.write_to_quarantine(&fpr_primary, &tpk_to_string(&full_tpk_new)?, &reason)?;
         }
         let fpr_checks = fpr_checks?;
 
         let fpr_not_linked = fpr_checks.into_iter().flatten();
 
+        policy.check_published_cert(&tpk_without_new_revocations(
+            published_tpk_clean.clone(),
+            full_tpk_old.as_ref(),
+        )?)?;
         let full_tpk_tmp = self.write_to_temp(&tpk_to_string(&full_tpk_new)?)?;
-        let published_tpk_clean = tpk_clean(&published_tpk_new)?;
         let published_tpk_tmp = self.write_to_temp(&tpk_to_string(&published_tpk_clean)?)?;
 
-        // these are very unlikely to fail. but if it happens,
-        // database consistency might be compromised!
-        self.move_tmp_to_full(full_tpk_tmp, &fpr_primary)?;
-        self.move_tmp_to_published(published_tpk_tmp, &fpr_primary)?;
-        self.regenerate_wkd(&fpr_primary, &published_tpk_clean)?;
-
-        let published_tpk_changed = published_tpk_old
-            .map(|tpk| tpk != published_tpk_clean)
-            .unwrap_or(true);
-        if published_tpk_changed {
-            self.update_write_log(&fpr_primary);
-        }
+        // these are very unlikely to fail. but if it happens, the
+        // journal allows restoring consistency.
+        self.journaled(&fpr_primary, &full_tpk_new, || {
+            self.move_tmp_to_full(full_tpk_tmp, &fpr_primary)?;
+            self.move_tmp_to_published(published_tpk_tmp, &fpr_primary)?;
+            self.regenerate_wkd(&fpr_primary, &published_tpk_clean)?;
+
+            let published_tpk_changed = published_tpk_old
+                .map(|tpk| tpk != published_tpk_clean)
+                .unwrap_or(true);
+            if published_tpk_changed {
+                let addresses = unpublished_emails.iter().cloned().cloned().collect();
+                self.update_write_log(
+                    &fpr_primary,
+                    WriteLogOp::Merge,
+                    addresses,
+                    published_hash_old,
+                );
+            }
 
-        for fpr in fpr_not_linked {
-            if let Err(e) = self.link_fpr(&fpr, &fpr_primary) {
-                info!("Error ensuring symlink! {} {} {:?}", &fpr, &fpr_primary, e);
+            for fpr in fpr_not_linked {
+                if let Err(e) = self.link_fpr(&fpr, &fpr_primary) {
+                    info!("Error ensuring symlink! {} {} {:?}", &fpr, &fpr_primary, e);
+                }
             }
-        }
 
-        for revoked_email in newly_revoked_emails {
-            if let Err(e) = self.unlink_email(revoked_email, &fpr_primary) {
-                info!(
-                    "Error ensuring symlink! {} {} {:?}",
-                    &fpr_primary, &revoked_email, e
-                );
+            for unpublished_email in unpublished_emails {
+                if let Err(e) = self.unlink_email(unpublished_email, &fpr_primary) {
+                    info!(
+                        "Error ensuring symlink! {} {} {:?}",
+                        &fpr_primary, &unpublished_email, e
+                    );
+                }
             }
-        }
+
+            Ok(())
+        })?;
 
         if is_update {
             Ok(ImportResult::Updated(TpkStatus {
@@ -363,14 +498,81 @@ pub trait Database: Sync + Send {
         }
     }
 
-    fn update_write_log(&self, fpr_primary: &Fingerprint) {
+    /// Records the intent to update the given Cert, before the first
+    /// write of a complex operation.
+    ///
+    /// If the operation is interrupted, the backend uses the intent to
+    /// restore consistency.  `tpk` must contain every key and user id
+    /// that may currently be linked to the Cert.  Backends whose
+    /// updates cannot be interrupted halfway need no journal.
+    fn journal_begin(&self, _fpr_primary: &Fingerprint, _tpk: &Cert) -> Result<()> {
+        Ok(())
+    }
+
+    /// Marks the complex operation started by `journal_begin` as done.
+    fn journal_commit(&self, _fpr_primary: &Fingerprint) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gives up on the complex operation started by `journal_begin`
+    /// after one of its writes failed.
+    ///
+    /// Backends that can undo the writes made so far do so.  Others
+    /// keep the intent, so that the operation is replayed.
+    fn journal_abort(&self, _fpr_primary: &Fingerprint) {}
+
+    /// Runs `update` as a complex operation on the given Cert, see
+    /// `journal_begin`.
+    fn journaled<T>(
+        &self,
+        fpr_primary: &Fingerprint,
+        tpk: &Cert,
+        update: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        self.journal_begin(fpr_primary, tpk)?;
+        match update() {
+            Ok(result) => {
+                self.journal_commit(fpr_primary)?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.journal_abort(fpr_primary);
+                Err(e)
+            }
+        }
+    }
+
+    /// Records a change of the published Cert in the write log.
+    ///
+    /// `published_before` is the hash of the published Cert before
+    /// the change, see `published_hash`.
+    fn update_write_log(
+        &self,
+        fpr_primary: &Fingerprint,
+        op: WriteLogOp,
+        addresses: Vec<Email>,
+        published_before: Option<String>,
+    ) {
+        let details = WriteLogDetails {
+            op,
+            addresses,
+            published_before,
+            published_after: self.published_hash(fpr_primary),
+        };
         let log_name = self.get_current_log_filename();
         println!("{}", log_name);
-        if let Err(e) = self.write_log_append(&log_name, fpr_primary) {
+        if let Err(e) = self.write_log_append(&log_name, fpr_primary, &details) {
             error!("Error writing to log! {} {} {}", &log_name, &fpr_primary, e);
         }
     }
 
+    /// Returns the hash of the published Cert, as recorded in the
+    /// write log.
+    fn published_hash(&self, fpr_primary: &Fingerprint) -> Option<String> {
+        self.by_primary_fpr(fpr_primary)
+            .and_then(|published| write_log::published_cert_hash(&published).ok())
+    }
+
     fn get_current_log_filename(&self) -> String {
         Utc::now().format("%Y-%m-%d").to_string()
     }
@@ -385,7 +587,8 @@ pub trait Database: Sync + Send {
             .ok_or_else(|| anyhow!("Key not in database!"))
             .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()))?;
 
-        let is_revoked = is_status_revoked(tpk_full.revocation_status(&POLICY, None));
+        let policy = self.policy();
+        let is_revoked = is_status_revoked(tpk_full.revocation_status(policy, None));
 
         let unparsed_uids = tpk_full
             .userids()
@@ -410,7 +613,7 @@ pub trait Database: Sync + Send {
                 if let Ok(email) = Email::try_from(uid) {
                     if !known_addresses.contains(&email) {
                         None
-                    } else if is_status_revoked(binding.revocation_status(&POLICY, None)) {
+                    } else if is_status_revoked(binding.revocation_status(policy, None)) {
                         Some((email, EmailAddressStatus::Revoked))
                     } else if published_uids.contains(uid) {
                         Some((email, EmailAddressStatus::Published))
@@ -448,7 +651,27 @@ pub trait Database: Sync + Send {
     /// 5. Move full and published temporary Cert to their location
     /// 6. Update all symlinks
     fn set_email_published(&self, fpr_primary: &Fingerprint, email_new: &Email) -> Result<()> {
-        let _lock = self.lock()?;
+        // The address may currently be published with another Cert,
+        // which we need to lock as well.
+        let _lock = loop {
+            let fpr_other = self.lookup_primary_fingerprint(&Query::ByEmail(email_new.clone()));
+            let mut fprs = vec![fpr_primary.clone()];
+            fprs.extend(fpr_other.clone());
+
+            let lock_fprs = self.lock_fprs(&fprs)?;
+            let lock_emails = self.lock_emails(&[email_new.clone()])?;
+
+            // Yet another Cert may have claimed the address before we
+            // got the lock.  Once we hold it, only the Certs we locked
+            // can change it.
+            let fpr_current = self.lookup_primary_fingerprint(&Query::ByEmail(email_new.clone()));
+            if fpr_current.is_none()
+                || fpr_current.as_ref() == Some(fpr_primary)
+                || fpr_current == fpr_other
+            {
+                break (lock_fprs, lock_emails);
+            }
+        };
 
         self.nolock_unlink_email_if_other(fpr_primary, email_new)?;
 
@@ -481,7 +704,8 @@ pub trait Database: Sync + Send {
         let mut published_emails = published_emails_old;
         published_emails.push(email_new.clone());
 
-        let published_tpk_new = tpk_filter_alive_emails(&full_tpk, &published_emails);
+        let published_tpk_new =
+            tpk_filter_alive_emails(&full_tpk, &published_emails, self.policy());
 
         if !published_tpk_new
             .userids()
@@ -492,22 +716,31 @@ pub trait Database: Sync + Send {
             return Err(anyhow!("Requested UserID not found!"));
         }
 
-        let published_tpk_clean = tpk_clean(&published_tpk_new)?;
+        let published_tpk_clean = tpk_clean(&published_tpk_new, self.policy())?;
+        self.policy().check_published_cert(&published_tpk_clean)?;
         let published_tpk_tmp = self.write_to_temp(&tpk_to_string(&published_tpk_clean)?)?;
 
-        self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
-        self.regenerate_wkd(fpr_primary, &published_tpk_clean)?;
-
-        self.update_write_log(fpr_primary);
+        let published_hash_old = self.published_hash(fpr_primary);
+        self.journaled(fpr_primary, &full_tpk, || {
+            self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
+            self.regenerate_wkd(fpr_primary, &published_tpk_clean)?;
 
-        if let Err(e) = self.link_email(email_new, fpr_primary) {
-            info!(
-                "Error ensuring email symlink! {} -> {} {:?}",
-                &email_new, &fpr_primary, e
+            self.update_write_log(
+                fpr_primary,
+                WriteLogOp::Publish,
+                vec![email_new.clone()],
+                published_hash_old,
             );
-        }
 
-        Ok(())
+            if let Err(e) = self.link_email(email_new, fpr_primary) {
+                info!(
+                    "Error ensuring email symlink! {} -> {} {:?}",
+                    &email_new, &fpr_primary, e
+                );
+            }
+
+            Ok(())
+        })
     }
 
     fn nolock_unlink_email_if_other(
@@ -547,7 +780,14 @@ pub trait Database: Sync + Send {
         fpr_primary: &Fingerprint,
         email_remove: impl Fn(&UserID) -> bool,
     ) -> Result<()> {
-        let _lock = self.lock()?;
+        let _lock = self.lock_fprs(&[fpr_primary.clone()])?;
+        let published_emails = self
+            .by_fpr(fpr_primary)
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok())
+            .map(|tpk| tpk_get_emails(&tpk))
+            .unwrap_or_default();
+        let _lock_emails = self.lock_emails(&published_emails)?;
+
         self.nolock_set_email_unpublished_filter(fpr_primary, email_remove)
     }
 
@@ -567,7 +807,9 @@ pub trait Database: Sync + Send {
             .flatten()
             .collect();
 
-        let published_tpk_new = published_tpk_old.retain_userids(|uid| email_remove(uid.userid()));
+        let published_tpk_new = published_tpk_old
+            .clone()
+            .retain_userids(|uid| email_remove(uid.userid()));
 
         let published_emails_new: Vec<Email> = published_tpk_new
             .userids()
@@ -575,28 +817,37 @@ pub trait Database: Sync + Send {
             .flatten()
             .collect();
 
-        let unpublished_emails = published_emails_old
+        let unpublished_emails: Vec<&Email> = published_emails_old
             .iter()
-            .filter(|email| !published_emails_new.contains(email));
+            .filter(|email| !published_emails_new.contains(email))
+            .collect();
 
-        let published_tpk_clean = tpk_clean(&published_tpk_new)?;
+        let published_tpk_clean = tpk_clean(&published_tpk_new, self.policy())?;
         let published_tpk_tmp = self.write_to_temp(&tpk_to_string(&published_tpk_clean)?)?;
 
-        self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
-        self.regenerate_wkd(fpr_primary, &published_tpk_clean)?;
+        let published_hash_old = self.published_hash(fpr_primary);
+        self.journaled(fpr_primary, &published_tpk_old, || {
+            self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
+            self.regenerate_wkd(fpr_primary, &published_tpk_clean)?;
 
-        self.update_write_log(fpr_primary);
+            self.update_write_log(
+                fpr_primary,
+                WriteLogOp::Unpublish,
+                unpublished_emails.iter().cloned().cloned().collect(),
+                published_hash_old,
+            );
 
-        for unpublished_email in unpublished_emails {
-            if let Err(e) = self.unlink_email(unpublished_email, fpr_primary) {
-                info!(
-                    "Error deleting email symlink! {} -> {} {:?}",
-                    &unpublished_email, &fpr_primary, e
-                );
+            for unpublished_email in unpublished_emails {
+                if let Err(e) = self.unlink_email(unpublished_email, fpr_primary) {
+                    info!(
+                        "Error deleting email symlink! {} -> {} {:?}",
+                        &unpublished_email, &fpr_primary, e
+                    );
+                }
             }
-        }
 
-        Ok(())
+            Ok(())
+        })
     }
 
     fn set_email_unpublished(&self, fpr_primary: &Fingerprint, email_remove: &Email) -> Result<()> {
@@ -611,6 +862,79 @@ pub trait Database: Sync + Send {
         self.set_email_unpublished_filter(fpr_primary, |_| false)
     }
 
+    /// Complex operation that deletes a Cert from the database.
+    ///
+    /// 1. Load full Cert, or published Cert if there is no full one
+    ///     - if neither exists, stop
+    /// 2. Remove all email links pointing to the Cert
+    /// 3. Remove all fingerprint and key id links pointing to the Cert
+    /// 4. Remove full, published and WKD Cert
+    /// 5. Record the deletion in the write log
+    fn delete_cert(&self, fpr_primary: &Fingerprint) -> Result<()> {
+        let load_tpk = || {
+            self.by_fpr_full(fpr_primary)
+                .or_else(|| self.by_primary_fpr(fpr_primary))
+                .ok_or_else(|| anyhow!("Key not in database!"))
+                .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()))
+        };
+
+        let (_lock, tpk) = loop {
+            let tpk = load_tpk()?;
+            let lock = self.lock_fprs(&tpk_get_fprs(&tpk))?;
+
+            // A concurrent merge may have added keys before we got
+            // the lock.
+            if load_tpk()? == tpk {
+                break (lock, tpk);
+            }
+        };
+        let _lock_emails = self.lock_emails(&tpk_get_emails(&tpk))?;
+
+        let published_hash_old = self.published_hash(fpr_primary);
+        let published_emails = self
+            .by_primary_fpr(fpr_primary)
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok())
+            .map(|published_tpk| tpk_get_emails(&published_tpk))
+            .unwrap_or_default();
+
+        for email in tpk_get_emails(&tpk) {
+            self.unlink_email(&email, fpr_primary)?;
+        }
+
+        for fpr in tpk_get_fprs(&tpk) {
+            self.unlink_fpr(&fpr, fpr_primary)?;
+        }
+
+        self.remove_tpk(fpr_primary)?;
+
+        self.update_write_log(
+            fpr_primary,
+            WriteLogOp::Delete,
+            published_emails,
+            published_hash_old,
+        );
+
+        Ok(())
+    }
+
+    /// Merges a quarantined Cert, and removes it from the quarantine.
+    ///
+    /// If the collision that caused the quarantine still exists, the
+    /// Cert stays in quarantine with an updated reason.
+    fn release_quarantined(&self, fpr_primary: &Fingerprint) -> Result<ImportResult> {
+        let quarantined = self
+            .list_quarantined()?
+            .into_iter()
+            .find(|quarantined| &quarantined.fingerprint == fpr_primary)
+            .ok_or_else(|| anyhow!("Key not in quarantine!"))?;
+
+        let tpk = Cert::from_bytes(quarantined.content.as_bytes())?;
+        let result = self.merge(tpk)?;
+        self.remove_quarantined(fpr_primary)?;
+
+        Ok(result)
+    }
+
     fn regenerate_links(&self, fpr_primary: &Fingerprint) -> Result<RegenerateResult> {
         let tpk = self
             .by_primary_fpr(fpr_primary)
@@ -625,7 +949,7 @@ pub trait Database: Sync + Send {
 
         self.regenerate_wkd(fpr_primary, &tpk)?;
 
-        let fingerprints = tpk_get_linkable_fprs(&tpk);
+        let fingerprints = tpk_get_linkable_fprs(&tpk, self.policy());
 
         let fpr_checks = fingerprints
             .into_iter()
@@ -675,7 +999,14 @@ fn tpk_get_emails(cert: &Cert) -> Vec<Email> {
         .collect()
 }
 
-pub fn tpk_get_linkable_fprs(tpk: &Cert) -> Vec<Fingerprint> {
+fn tpk_get_fprs(cert: &Cert) -> Vec<Fingerprint> {
+    cert.keys()
+        .map(|amalgamation| Fingerprint::try_from(amalgamation.key().fingerprint()))
+        .flatten()
+        .collect()
+}
+
+pub fn tpk_get_linkable_fprs(tpk: &Cert, policy: &dyn Policy) -> Vec<Fingerprint> {
     let signing_capable = &KeyFlags::empty().set_signing().set_certification();
     let fpr_primary = &Fingerprint::try_from(tpk.fingerprint()).unwrap();
     tpk.keys()
@@ -685,7 +1016,7 @@ pub fn tpk_get_linkable_fprs(tpk: &Cert) -> Vec<Fingerprint> {
                 (
                     fpr,
                     bundle
-                        .binding_signature(&POLICY, None)
+                        .binding_signature(policy, None)
                         .ok()
                         .and_then(|sig| sig.key_flags()),
                 )
@@ -0,0 +1,648 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+use sync::{InProcessMutexGuard, InProcessStripes};
+use types::{Email, Fingerprint, KeyID};
+use write_log::{self, WriteLogCursor, WriteLogDetails, WriteLogEntry};
+use Result;
+use {CertPolicy, Database, QuarantineReason, QuarantinedCert, Query};
+
+use wkd;
+
+use openpgp::{parse::Parse, Cert};
+
+#[derive(Default)]
+struct Store {
+    full: HashMap<Fingerprint, String>,
+    published: HashMap<Fingerprint, String>,
+    published_wkd: HashMap<Fingerprint, Vec<u8>>,
+    quarantined: HashMap<Fingerprint, (Vec<u8>, QuarantineReason)>,
+
+    links_by_fingerprint: HashMap<Fingerprint, Fingerprint>,
+    links_by_keyid: HashMap<KeyID, Fingerprint>,
+    links_by_email: HashMap<Email, Fingerprint>,
+    links_wkd_by_domain_and_hash: HashMap<(String, String), Fingerprint>,
+}
+
+/// A database backend that keeps everything in memory.
+///
+/// This is useful for tests, and for embedding Hagrid.  The write
+/// log is only kept if a log directory is given.
+#[derive(Default)]
+pub struct MemoryDatabase {
+    fpr_locks: InProcessStripes,
+    email_locks: InProcessStripes,
+    store: Mutex<Store>,
+    keys_dir_log: Option<PathBuf>,
+    policy: CertPolicy,
+}
+
+impl MemoryDatabase {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn new_with_log_dir(keys_dir_log: impl Into<PathBuf>) -> Result<Self> {
+        let keys_dir_log = keys_dir_log.into();
+        create_dir_all(&keys_dir_log)?;
+
+        Ok(MemoryDatabase {
+            keys_dir_log: Some(keys_dir_log),
+            ..Default::default()
+        })
+    }
+
+    /// Sets the policy deciding which Certs are accepted.
+    pub fn with_policy(mut self, policy: CertPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn store(&self) -> MutexGuard<Store> {
+        self.store.lock().expect("memory store mutex poisoned")
+    }
+
+    fn published_by(&self, primary_fp: Option<Fingerprint>) -> Option<String> {
+        primary_fp.and_then(|fp| self.store().published.get(&fp).cloned())
+    }
+
+    fn published_wkd_by(&self, primary_fp: Option<Fingerprint>) -> Option<Vec<u8>> {
+        primary_fp.and_then(|fp| self.store().published_wkd.get(&fp).cloned())
+    }
+}
+
+impl Database for MemoryDatabase {
+    type MutexGuard = Vec<InProcessMutexGuard>;
+    type TempCert = Vec<u8>;
+
+    fn lock_fprs(&self, fprs: &[Fingerprint]) -> Result<Self::MutexGuard> {
+        let names: Vec<String> = fprs.iter().map(|fpr| fpr.to_string()).collect();
+        Ok(self.fpr_locks.lock(names.iter().map(String::as_str)))
+    }
+
+    fn lock_emails(&self, emails: &[Email]) -> Result<Self::MutexGuard> {
+        Ok(self.email_locks.lock(emails.iter().map(Email::as_str)))
+    }
+
+    fn write_to_temp(&self, content: &[u8]) -> Result<Self::TempCert> {
+        Ok(content.to_vec())
+    }
+
+    fn write_log_append(
+        &self,
+        filename: &str,
+        fpr_primary: &Fingerprint,
+        details: &WriteLogDetails,
+    ) -> Result<()> {
+        let keys_dir_log = match self.keys_dir_log {
+            Some(ref keys_dir_log) => keys_dir_log,
+            None => return Ok(()),
+        };
+
+        write_log::append(keys_dir_log, filename, fpr_primary, details)
+    }
+
+    fn read_write_log(
+        &self,
+        since: u64,
+        cursor: &WriteLogCursor,
+        limit: usize,
+    ) -> Result<(Vec<WriteLogEntry>, WriteLogCursor)> {
+        match self.keys_dir_log {
+            Some(ref keys_dir_log) => write_log::read_write_log(keys_dir_log, since, cursor, limit),
+            None => Ok((vec![], cursor.clone())),
+        }
+    }
+
+    fn move_tmp_to_full(&self, content: Self::TempCert, fpr: &Fingerprint) -> Result<()> {
+        let content = String::from_utf8(content)?;
+        self.store().full.insert(fpr.clone(), content);
+        Ok(())
+    }
+
+    fn move_tmp_to_published(&self, content: Self::TempCert, fpr: &Fingerprint) -> Result<()> {
+        let content = String::from_utf8(content)?;
+        self.store().published.insert(fpr.clone(), content);
+        Ok(())
+    }
+
+    fn move_tmp_to_published_wkd(
+        &self,
+        content: Option<Self::TempCert>,
+        fpr: &Fingerprint,
+    ) -> Result<()> {
+        let mut store = self.store();
+        if let Some(content) = content {
+            store.published_wkd.insert(fpr.clone(), content);
+        } else {
+            store.published_wkd.remove(fpr);
+        }
+        Ok(())
+    }
+
+    fn write_to_quarantine(
+        &self,
+        fpr: &Fingerprint,
+        content: &[u8],
+        reason: &QuarantineReason,
+    ) -> Result<()> {
+        self.store()
+            .quarantined
+            .insert(fpr.clone(), (content.to_vec(), reason.clone()));
+        Ok(())
+    }
+
+    fn list_quarantined(&self) -> Result<Vec<QuarantinedCert>> {
+        self.store()
+            .quarantined
+            .iter()
+            .map(|(fpr, (content, reason))| {
+                Ok(QuarantinedCert {
+                    fingerprint: fpr.clone(),
+                    reason: Some(reason.clone()),
+                    content: String::from_utf8(content.clone())?,
+                })
+            })
+            .collect()
+    }
+
+    fn remove_quarantined(&self, fpr: &Fingerprint) -> Result<()> {
+        self.store()
+            .quarantined
+            .remove(fpr)
+            .map(|_| ())
+            .ok_or_else(|| anyhow!("Key not in quarantine!"))
+    }
+
+    fn remove_tpk(&self, fpr: &Fingerprint) -> Result<()> {
+        let mut store = self.store();
+        store.full.remove(fpr);
+        store.published.remove(fpr);
+        store.published_wkd.remove(fpr);
+        Ok(())
+    }
+
+    fn check_link_fpr(
+        &self,
+        fpr: &Fingerprint,
+        fpr_target: &Fingerprint,
+    ) -> Result<Option<Fingerprint>> {
+        let store = self.store();
+        let link_fpr = store.links_by_fingerprint.get(fpr);
+        let link_keyid = store.links_by_keyid.get(&fpr.into());
+
+        if let Some(link_fpr_target) = link_fpr {
+            if link_fpr_target != fpr_target {
+                info!(
+                    "Fingerprint points to different key for {} (expected {}, found {})",
+                    fpr, fpr_target, link_fpr_target
+                );
+                return Err(anyhow!(format!("Fingerprint collision for key {}", fpr)));
+            }
+        }
+
+        if let Some(link_keyid_target) = link_keyid {
+            if link_keyid_target != fpr_target {
+                info!(
+                    "KeyID points to different key for {} (expected {}, found {})",
+                    fpr, fpr_target, link_keyid_target
+                );
+                return Err(anyhow!(format!("KeyID collision for key {}", fpr)));
+            }
+        }
+
+        if link_fpr.is_none() || link_keyid.is_none() {
+            Ok(Some(fpr.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn lookup_primary_fingerprint(&self, term: &Query) -> Option<Fingerprint> {
+        use super::Query::*;
+        let store = self.store();
+        match term {
+            ByFingerprint(ref fp) => store.links_by_fingerprint.get(fp).cloned(),
+            ByKeyID(ref keyid) => store.links_by_keyid.get(keyid).cloned(),
+            ByEmail(ref email) => store.links_by_email.get(email).cloned(),
+            _ => None,
+        }
+    }
+
+    fn link_email(&self, email: &Email, fpr: &Fingerprint) -> Result<()> {
+        let (wkd_hash, wkd_domain) = wkd::encode_wkd(email.as_str())?;
+
+        let mut store = self.store();
+        store.links_by_email.insert(email.clone(), fpr.clone());
+        store
+            .links_wkd_by_domain_and_hash
+            .insert((wkd_domain, wkd_hash), fpr.clone());
+        Ok(())
+    }
+
+    fn unlink_email(&self, email: &Email, fpr: &Fingerprint) -> Result<()> {
+        let wkd_key = wkd::encode_wkd(email.as_str()).map(|(hash, domain)| (domain, hash))?;
+
+        let mut store = self.store();
+        if store.links_by_email.get(email) == Some(fpr) {
+            store.links_by_email.remove(email);
+        }
+        if store.links_wkd_by_domain_and_hash.get(&wkd_key) == Some(fpr) {
+            store.links_wkd_by_domain_and_hash.remove(&wkd_key);
+        }
+        Ok(())
+    }
+
+    fn link_fpr(&self, from: &Fingerprint, primary_fpr: &Fingerprint) -> Result<()> {
+        let mut store = self.store();
+        store
+            .links_by_fingerprint
+            .insert(from.clone(), primary_fpr.clone());
+        store
+            .links_by_keyid
+            .insert(from.into(), primary_fpr.clone());
+        Ok(())
+    }
+
+    fn unlink_fpr(&self, from: &Fingerprint, primary_fpr: &Fingerprint) -> Result<()> {
+        let keyid: KeyID = from.into();
+
+        let mut store = self.store();
+        if store.links_by_fingerprint.get(from) == Some(primary_fpr) {
+            store.links_by_fingerprint.remove(from);
+        }
+        if store.links_by_keyid.get(&keyid) == Some(primary_fpr) {
+            store.links_by_keyid.remove(&keyid);
+        }
+        Ok(())
+    }
+
+    fn by_fpr_full(&self, fpr: &Fingerprint) -> Option<String> {
+        self.store().full.get(fpr).cloned()
+    }
+
+    fn by_primary_fpr(&self, fpr: &Fingerprint) -> Option<String> {
+        self.store().published.get(fpr).cloned()
+    }
+
+    fn by_fpr(&self, fpr: &Fingerprint) -> Option<String> {
+        self.published_by(self.lookup_primary_fingerprint(&Query::ByFingerprint(fpr.clone())))
+    }
+
+    fn by_email(&self, email: &Email) -> Option<String> {
+        self.published_by(self.lookup_primary_fingerprint(&Query::ByEmail(email.clone())))
+    }
+
+    fn by_email_wkd(&self, email: &Email) -> Option<Vec<u8>> {
+        let (wkd_hash, wkd_domain) = wkd::encode_wkd(email.as_str()).ok()?;
+        self.by_domain_and_hash_wkd(&wkd_domain, &wkd_hash)
+    }
+
+    fn by_domain_and_hash_wkd(&self, domain: &str, hash: &str) -> Option<Vec<u8>> {
+        let primary_fp = self
+            .store()
+            .links_wkd_by_domain_and_hash
+            .get(&(domain.to_owned(), hash.to_owned()))
+            .cloned();
+        self.published_wkd_by(primary_fp)
+    }
+
+    fn by_kid(&self, kid: &KeyID) -> Option<String> {
+        self.published_by(self.lookup_primary_fingerprint(&Query::ByKeyID(kid.clone())))
+    }
+
+    fn policy(&self) -> &CertPolicy {
+        &self.policy
+    }
+
+    /// Checks the database for consistency.
+    ///
+    /// Note that this operation may take a long time, and is
+    /// generally only useful for testing.
+    fn check_consistency(&self) -> Result<()> {
+        let store = self.store();
+
+        let mut tpks = HashMap::new();
+        for (primary_fp, armored) in store.published.iter() {
+            tpks.insert(primary_fp.clone(), Cert::from_bytes(armored.as_bytes())?);
+        }
+        let tpk_for = |primary_fp: &Fingerprint| {
+            tpks.get(primary_fp)
+                .ok_or_else(|| format_err!("No Cert with fingerprint {:?}", primary_fp))
+        };
+
+        for (primary_fp, tpk) in tpks.iter() {
+            // check that certificate exists in published wkd map
+            let should_wkd_exist = tpk.userids().next().is_some();
+            let has_wkd = store.published_wkd.contains_key(primary_fp);
+            if should_wkd_exist && !has_wkd {
+                return Err(format_err!("Missing wkd for fp {}", primary_fp));
+            };
+            if !should_wkd_exist && has_wkd {
+                return Err(format_err!("Incorrectly present wkd for fp {}", primary_fp));
+            };
+
+            // check that all subkeys are linked
+            let policy = self.policy();
+            let fingerprints = tpk
+                .keys()
+                .with_policy(policy, None)
+                .for_certification()
+                .for_signing()
+                .map(|amalgamation| amalgamation.key().fingerprint())
+                .map(Fingerprint::try_from)
+                .flatten();
+            for fpr in fingerprints {
+                let keyid: KeyID = (&fpr).into();
+                if store.links_by_fingerprint.get(&fpr) != Some(primary_fp)
+                    || store.links_by_keyid.get(&keyid) != Some(primary_fp)
+                {
+                    return Err(format_err!(
+                        "Missing link to key {} for sub {}",
+                        primary_fp,
+                        fpr
+                    ));
+                }
+            }
+
+            // check that all published uids are linked
+            let emails = tpk
+                .userids()
+                .map(|binding| binding.userid().clone())
+                .map(|userid| Email::try_from(&userid).unwrap());
+            for email in emails {
+                if store.links_by_email.get(&email) != Some(primary_fp) {
+                    return Err(format_err!(
+                        "Missing link to key {} for email {}",
+                        primary_fp,
+                        email
+                    ));
+                }
+                let (wkd_hash, wkd_domain) = wkd::encode_wkd(email.as_str())?;
+                if store
+                    .links_wkd_by_domain_and_hash
+                    .get(&(wkd_domain, wkd_hash))
+                    != Some(primary_fp)
+                {
+                    return Err(format_err!(
+                        "Missing wkd link to key {} for email {}",
+                        primary_fp,
+                        email
+                    ));
+                }
+            }
+        }
+
+        for (fpr, primary_fp) in store.links_by_fingerprint.iter() {
+            let found = tpk_for(primary_fp)?
+                .keys()
+                .map(|amalgamation| Fingerprint::try_from(amalgamation.key().fingerprint()))
+                .flatten()
+                .any(|key_fp| key_fp == *fpr);
+            if !found {
+                return Err(format_err!(
+                    "Fingerprint {} points to the wrong Cert {}, the Cert does not \
+                            contain the (sub)key",
+                    fpr,
+                    primary_fp
+                ));
+            }
+        }
+
+        for (keyid, primary_fp) in store.links_by_keyid.iter() {
+            let found = tpk_for(primary_fp)?
+                .keys()
+                .map(|amalgamation| KeyID::try_from(amalgamation.key().fingerprint()))
+                .flatten()
+                .any(|key_id| key_id == *keyid);
+            if !found {
+                return Err(format_err!(
+                    "KeyID {} points to the wrong Cert {}, the Cert does not \
+                            contain the (sub)key",
+                    keyid,
+                    primary_fp
+                ));
+            }
+        }
+
+        for (email, primary_fp) in store.links_by_email.iter() {
+            let found = tpk_for(primary_fp)?
+                .userids()
+                .any(|uidb| Email::try_from(uidb.userid()).ok().as_ref() == Some(email));
+            if !found {
+                return Err(format_err!(
+                    "Email {} points to the wrong Cert {}, the Cert does not \
+                            contain the email",
+                    email,
+                    primary_fp
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use test;
+
+    fn open_db() -> (TempDir, MemoryDatabase, PathBuf) {
+        let tmpdir = TempDir::new().unwrap();
+        let db = MemoryDatabase::new_with_log_dir(tmpdir.path()).unwrap();
+        let log_path = tmpdir.path().join(db.get_current_log_filename());
+
+        (tmpdir, db, log_path)
+    }
+
+    #[test]
+    fn concurrent_publish() {
+        use openpgp::cert::CertBuilder;
+        use std::str::FromStr;
+        use std::sync::Arc;
+        use std::thread;
+
+        let db = Arc::new(MemoryDatabase::new());
+        let email = Email::from_str("a@invalid.example.org").unwrap();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                let email = email.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        let tpk = CertBuilder::new()
+                            .add_userid("a@invalid.example.org")
+                            .add_signing_subkey()
+                            .generate()
+                            .unwrap()
+                            .0;
+                        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+                        db.merge(tpk).unwrap();
+                        db.set_email_published(&fpr, &email).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        db.check_consistency().expect("inconsistent database");
+        assert!(db.by_email(&email).is_some());
+    }
+
+    #[test]
+    fn uid_verification() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_uid_verification(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_deletion() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_uid_deletion(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn quarantine() {
+        let (_tmp_dir, mut db, _log_path) = open_db();
+        test::test_quarantine(&mut db);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn delete_cert() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_delete_cert(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn read_write_log() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_read_write_log(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn subkey_lookup() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_subkey_lookup(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn kid_lookup() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_kid_lookup(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn upload_revoked_tpk() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_upload_revoked_tpk(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_revocation() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_uid_revocation(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn regenerate() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_regenerate(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn key_reupload() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_reupload(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_replacement() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_uid_replacement(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_unlinking() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_unlink_uid(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn same_email_1() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_same_email_1(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn same_email_2() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_same_email_2(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn same_email_3() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_same_email_3(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn same_email_4() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_same_email_4(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn no_selfsig() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_no_selfsig(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn bad_uids() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_bad_uids(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn attested_key_signatures() -> Result<()> {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::attested_key_signatures(&mut db, &log_path)?;
+        db.check_consistency()?;
+        Ok(())
+    }
+
+    #[test]
+    fn nonexportable_sigs() -> Result<()> {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::nonexportable_sigs(&mut db, &log_path)?;
+        db.check_consistency()?;
+        Ok(())
+    }
+}
@@ -1,15 +1,14 @@
 use openpgp::Result;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 
 use openpgp::{
-    cert::prelude::*, policy::StandardPolicy, serialize::SerializeInto as _,
-    types::RevocationStatus, Cert,
+    cert::prelude::*, packet::Signature, policy::Policy, serialize::SerializeInto as _,
+    types::RevocationStatus, Cert, Packet,
 };
 
 use Email;
 
-pub const POLICY: StandardPolicy = StandardPolicy::new();
-
 pub fn is_status_revoked(status: RevocationStatus) -> bool {
     match status {
         RevocationStatus::Revoked(_) => true,
@@ -22,7 +21,7 @@ pub fn tpk_to_string(tpk: &Cert) -> Result<Vec<u8>> {
     tpk.armored().export_to_vec()
 }
 
-pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
+pub fn tpk_clean(tpk: &Cert, policy: &dyn Policy) -> Result<Cert> {
     // Iterate over the Cert, pushing packets we want to merge
     // into the accumulator.
     let mut acc = Vec::new();
@@ -69,7 +68,7 @@ pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
 
         // Reasoning about the currently attested certifications
         // requires a policy.
-        if let Ok(vuid) = uidb.with_policy(&POLICY, None) {
+        if let Ok(vuid) = uidb.with_policy(policy, None) {
             for s in vuid.attestation_key_signatures() {
                 acc.push(s.clone().into());
             }
@@ -82,10 +81,181 @@ pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
     Cert::from_packets(acc.into_iter())
 }
 
+/// Removes superseded self-signatures from the Cert.
+///
+/// A self-signature is superseded if it is not newer than the binding
+/// signature that is currently in effect for its component.  Newer,
+/// not yet valid self-signatures are kept, as are all revocations.
+pub fn tpk_compact(tpk: Cert, policy: &dyn Policy) -> Result<Cert> {
+    fn superseded<'a>(
+        binding: Result<&'a Signature>,
+        self_sigs: &'a [Signature],
+    ) -> impl Iterator<Item = &'a Signature> {
+        let binding = binding.ok();
+        self_sigs.iter().filter(move |sig| match binding {
+            Some(binding) => {
+                *sig != binding
+                    && sig.signature_creation_time() <= binding.signature_creation_time()
+            }
+            None => false,
+        })
+    }
+
+    // Signatures cache their digest, which neither Hash nor Eq look at.
+    #[allow(clippy::mutable_key_type)]
+    let mut remove: HashSet<Signature> = HashSet::new();
+
+    let pk_bundle = tpk.primary_key().bundle();
+    remove.extend(
+        superseded(
+            pk_bundle.binding_signature(policy, None),
+            pk_bundle.self_signatures(),
+        )
+        .cloned(),
+    );
+    for skb in tpk.keys().subkeys() {
+        let bundle = skb.bundle();
+        remove.extend(
+            superseded(
+                bundle.binding_signature(policy, None),
+                bundle.self_signatures(),
+            )
+            .cloned(),
+        );
+    }
+    for uidb in tpk.userids() {
+        let bundle = uidb.bundle();
+        remove.extend(
+            superseded(
+                bundle.binding_signature(policy, None),
+                bundle.self_signatures(),
+            )
+            .cloned(),
+        );
+    }
+
+    tpk_remove_signatures(tpk, &remove)
+}
+
+/// Limits the third-party certifications of each user ID and user
+/// attribute to `max`.
+///
+/// Certifications that are already in `tpk_old` or that the owner
+/// attested are always kept, even beyond the limit, so nothing that
+/// was stored before is lost.  Other certifications are only added
+/// while the component has fewer than `max`.
+pub fn tpk_cap_certifications(
+    tpk: Cert,
+    tpk_old: Option<&Cert>,
+    max: usize,
+    policy: &dyn Policy,
+) -> Result<Cert> {
+    let attested: Vec<Signature> = tpk
+        .userids()
+        .flat_map(|uidb| uidb.clone().with_policy(policy, None))
+        .flat_map(|vuid| vuid.attested_certifications().cloned().collect::<Vec<_>>())
+        .chain(
+            tpk.user_attributes()
+                .flat_map(|uab| uab.clone().with_policy(policy, None))
+                .flat_map(|vua| vua.attested_certifications().cloned().collect::<Vec<_>>()),
+        )
+        .collect();
+
+    // Signatures cache their digest, which neither Hash nor Eq look at.
+    #[allow(clippy::mutable_key_type)]
+    let mut keep: HashSet<&Signature> = attested.iter().collect();
+    if let Some(tpk_old) = tpk_old {
+        keep.extend(
+            tpk_old
+                .userids()
+                .flat_map(|uidb| uidb.bundle().certifications()),
+        );
+        keep.extend(
+            tpk_old
+                .user_attributes()
+                .flat_map(|uab| uab.bundle().certifications()),
+        );
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    let mut remove: HashSet<Signature> = HashSet::new();
+    let components = tpk
+        .userids()
+        .map(|uidb| uidb.bundle().certifications())
+        .chain(
+            tpk.user_attributes()
+                .map(|uab| uab.bundle().certifications()),
+        );
+    for certifications in components {
+        let kept = certifications
+            .iter()
+            .filter(|sig| keep.contains(sig))
+            .count();
+        remove.extend(
+            certifications
+                .iter()
+                .filter(|sig| !keep.contains(sig))
+                .skip(max.saturating_sub(kept))
+                .cloned(),
+        );
+    }
+
+    tpk_remove_signatures(tpk, &remove)
+}
+
+/// Removes the given signatures from the Cert.
+// Signatures cache their digest, which neither Hash nor Eq look at.
+#[allow(clippy::mutable_key_type)]
+fn tpk_remove_signatures(tpk: Cert, remove: &HashSet<Signature>) -> Result<Cert> {
+    if remove.is_empty() {
+        return Ok(tpk);
+    }
+
+    Cert::from_packets(tpk.into_packets().filter(|packet| match packet {
+        Packet::Signature(sig) => !remove.contains(sig),
+        _ => true,
+    }))
+}
+
+/// Removes the revocations the owner made of the Cert or any of its
+/// components, unless they are already in `tpk_old`.
+pub fn tpk_without_new_revocations(tpk: Cert, tpk_old: Option<&Cert>) -> Result<Cert> {
+    fn self_revocations(tpk: &Cert) -> impl Iterator<Item = &Signature> {
+        tpk.primary_key()
+            .bundle()
+            .self_revocations()
+            .iter()
+            .chain(
+                tpk.keys()
+                    .subkeys()
+                    .flat_map(|skb| skb.bundle().self_revocations()),
+            )
+            .chain(
+                tpk.userids()
+                    .flat_map(|uidb| uidb.bundle().self_revocations()),
+            )
+            .chain(
+                tpk.user_attributes()
+                    .flat_map(|uab| uab.bundle().self_revocations()),
+            )
+    }
+
+    // Signatures cache their digest, which neither Hash nor Eq look at.
+    #[allow(clippy::mutable_key_type)]
+    let old: HashSet<&Signature> = tpk_old.into_iter().flat_map(self_revocations).collect();
+    #[allow(clippy::mutable_key_type)]
+    let remove: HashSet<Signature> = self_revocations(&tpk)
+        .filter(|sig| !old.contains(sig))
+        .cloned()
+        .collect();
+
+    tpk_remove_signatures(tpk, &remove)
+}
+
 /// Filters the Cert, keeping only UserIDs that aren't revoked, and whose emails match the given list
-pub fn tpk_filter_alive_emails(tpk: &Cert, emails: &[Email]) -> Cert {
+pub fn tpk_filter_alive_emails(tpk: &Cert, emails: &[Email], policy: &dyn Policy) -> Cert {
     tpk.clone().retain_userids(|uid| {
-        if is_status_revoked(uid.revocation_status(&POLICY, None)) {
+        if is_status_revoked(uid.revocation_status(policy, None)) {
             false
         } else if let Ok(email) = Email::try_from(uid.userid()) {
             emails.contains(&email)
@@ -94,3 +264,49 @@ pub fn tpk_filter_alive_emails(tpk: &Cert, emails: &[Email]) -> Cert {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openpgp::policy::StandardPolicy;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn compact() {
+        let policy = &StandardPolicy::new();
+        let (tpk, _) = CertBuilder::general_purpose(None, Some("foo@invalid.example.com"))
+            .set_creation_time(SystemTime::now() - Duration::from_secs(60 * 60))
+            .generate()
+            .unwrap();
+        let mut signer = tpk
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()
+            .unwrap()
+            .into_keypair()
+            .unwrap();
+        let sigs = tpk
+            .set_expiration_time(policy, None, &mut signer, None)
+            .unwrap();
+        let tpk = tpk
+            .insert_packets(sigs)
+            .unwrap()
+            .strip_secret_key_material();
+
+        let uid = tpk.userids().next().unwrap();
+        assert_eq!(uid.self_signatures().count(), 2);
+        let binding = uid.binding_signature(policy, None).unwrap().clone();
+
+        let compacted = tpk_compact(tpk.clone(), policy).unwrap();
+        let uid = compacted.userids().next().unwrap();
+        assert_eq!(uid.self_signatures().collect::<Vec<_>>(), vec![&binding]);
+        assert_eq!(
+            compacted.keys().subkeys().count(),
+            tpk.keys().subkeys().count()
+        );
+
+        // Compacting again changes nothing.
+        assert_eq!(tpk_compact(compacted.clone(), policy).unwrap(), compacted);
+    }
+}
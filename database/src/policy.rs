@@ -0,0 +1,636 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use serde::Deserialize;
+
+use openpgp::cert::prelude::*;
+use openpgp::crypto::mpi::PublicKey;
+use openpgp::packet::key;
+use openpgp::packet::{Packet, Signature};
+use openpgp::policy::{AsymmetricAlgorithm, HashAlgoSecurity, Policy, StandardPolicy};
+use openpgp::serialize::SerializeInto;
+use openpgp::types::{AEADAlgorithm, HashAlgorithm, SymmetricAlgorithm};
+use openpgp::Cert;
+
+use Result;
+
+/// The default maximum size of a stored full cert in bytes.
+pub const DEFAULT_MAX_CERT_SIZE: usize = 1024 * 1024;
+/// The default maximum size of a published cert in bytes.
+pub const DEFAULT_MAX_PUBLISHED_CERT_SIZE: usize = 256 * 1024;
+/// The default maximum number of subkeys of a cert.
+pub const DEFAULT_MAX_SUBKEYS: usize = 64;
+/// The default maximum number of user IDs of a cert.
+pub const DEFAULT_MAX_USERIDS: usize = 64;
+/// The default maximum number of self-signatures per component.
+pub const DEFAULT_MAX_SELF_SIGS: usize = 8;
+/// The default maximum number of third-party certifications per user
+/// ID or user attribute.
+pub const DEFAULT_MAX_CERTIFICATIONS: usize = 32;
+
+/// The policy settings an operator can configure.
+///
+/// Unset fields keep the defaults of Sequoia's `StandardPolicy`.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Asymmetric algorithms to reject, named as by Sequoia, e.g.
+    /// "RSA1024", "DSA2048" or "BrainpoolP256".
+    pub reject_asymmetric_algos: Vec<String>,
+    /// Hash algorithms to reject in signatures made on or after the
+    /// given date, e.g. `{ SHA1 = "2020-01-01" }`.
+    pub hash_cutoffs: HashMap<String, String>,
+    /// The minimum size of RSA, DSA and ElGamal keys in bits.
+    pub min_key_bits: Option<usize>,
+    /// The maximum size of a stored full cert in bytes.
+    pub max_cert_size: Option<usize>,
+    /// The maximum size of a published cert in bytes.
+    pub max_published_cert_size: Option<usize>,
+    /// The maximum number of subkeys of a cert.
+    pub max_subkeys: Option<usize>,
+    /// The maximum number of user IDs of a cert.
+    pub max_userids: Option<usize>,
+    /// The maximum number of self-signatures of the primary key, a
+    /// subkey or a user ID, after superseded ones have been removed.
+    pub max_self_sigs: Option<usize>,
+    /// The maximum number of third-party certifications of a user ID
+    /// or user attribute.  Certifications beyond it are not stored.
+    pub max_certifications: Option<usize>,
+    /// Rejects certs whose subkeys have all expired.
+    pub reject_expired_subkeys: bool,
+}
+
+/// The policy applied to all certs in the database.
+///
+/// Sequoia's `StandardPolicy`, adjusted by a `PolicyConfig`.  Besides
+/// judging the validity of signatures and keys, it decides whether a
+/// cert may be stored at all.
+#[derive(Clone, Debug)]
+pub struct CertPolicy {
+    standard: StandardPolicy<'static>,
+    min_key_bits: Option<usize>,
+    max_cert_size: usize,
+    max_published_cert_size: usize,
+    max_subkeys: usize,
+    max_userids: usize,
+    max_self_sigs: usize,
+    max_certifications: usize,
+    reject_expired_subkeys: bool,
+}
+
+impl Default for CertPolicy {
+    fn default() -> Self {
+        CertPolicy {
+            standard: StandardPolicy::new(),
+            min_key_bits: None,
+            max_cert_size: DEFAULT_MAX_CERT_SIZE,
+            max_published_cert_size: DEFAULT_MAX_PUBLISHED_CERT_SIZE,
+            max_subkeys: DEFAULT_MAX_SUBKEYS,
+            max_userids: DEFAULT_MAX_USERIDS,
+            max_self_sigs: DEFAULT_MAX_SELF_SIGS,
+            max_certifications: DEFAULT_MAX_CERTIFICATIONS,
+            reject_expired_subkeys: false,
+        }
+    }
+}
+
+const ASYMMETRIC_ALGOS: &[AsymmetricAlgorithm] = &[
+    AsymmetricAlgorithm::RSA1024,
+    AsymmetricAlgorithm::RSA2048,
+    AsymmetricAlgorithm::RSA3072,
+    AsymmetricAlgorithm::RSA4096,
+    AsymmetricAlgorithm::ElGamal1024,
+    AsymmetricAlgorithm::ElGamal2048,
+    AsymmetricAlgorithm::ElGamal3072,
+    AsymmetricAlgorithm::ElGamal4096,
+    AsymmetricAlgorithm::DSA1024,
+    AsymmetricAlgorithm::DSA2048,
+    AsymmetricAlgorithm::DSA3072,
+    AsymmetricAlgorithm::DSA4096,
+    AsymmetricAlgorithm::NistP256,
+    AsymmetricAlgorithm::NistP384,
+    AsymmetricAlgorithm::NistP521,
+    AsymmetricAlgorithm::BrainpoolP256,
+    AsymmetricAlgorithm::BrainpoolP512,
+    AsymmetricAlgorithm::Cv25519,
+];
+
+fn parse_date(date: &str) -> Result<SystemTime> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| anyhow!("invalid date '{}': {}", date, e))?;
+    Ok(Utc.from_utc_date(&date).and_hms(0, 0, 0).into())
+}
+
+impl CertPolicy {
+    pub fn from_config(config: &PolicyConfig) -> Result<Self> {
+        let mut standard = StandardPolicy::new();
+
+        for name in &config.reject_asymmetric_algos {
+            let algo = ASYMMETRIC_ALGOS
+                .iter()
+                .find(|algo| algo.to_string().eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow!("unknown asymmetric algorithm '{}'", name))?;
+            standard.reject_asymmetric_algo(algo.clone());
+        }
+
+        for (name, date) in &config.hash_cutoffs {
+            let algo = HashAlgorithm::from_str(name)
+                .map_err(|_| anyhow!("unknown hash algorithm '{}'", name))?;
+            standard.reject_hash_at(algo, parse_date(date)?);
+        }
+
+        Ok(CertPolicy {
+            standard,
+            min_key_bits: config.min_key_bits,
+            max_cert_size: config.max_cert_size.unwrap_or(DEFAULT_MAX_CERT_SIZE),
+            max_published_cert_size: config
+                .max_published_cert_size
+                .unwrap_or(DEFAULT_MAX_PUBLISHED_CERT_SIZE),
+            max_subkeys: config.max_subkeys.unwrap_or(DEFAULT_MAX_SUBKEYS),
+            max_userids: config.max_userids.unwrap_or(DEFAULT_MAX_USERIDS),
+            max_self_sigs: config.max_self_sigs.unwrap_or(DEFAULT_MAX_SELF_SIGS),
+            max_certifications: config
+                .max_certifications
+                .unwrap_or(DEFAULT_MAX_CERTIFICATIONS),
+            reject_expired_subkeys: config.reject_expired_subkeys,
+        })
+    }
+
+    /// Checks whether the full cert may be stored.
+    ///
+    /// The limits apply to all certs, but the subkeys of revoked certs
+    /// are not checked for expiry, so that revocations of otherwise
+    /// unused certs can be distributed.
+    pub fn check_cert(&self, tpk: &Cert, is_revoked: bool) -> Result<()> {
+        check_size(tpk, self.max_cert_size)?;
+
+        let subkeys = tpk.keys().subkeys().count();
+        if subkeys > self.max_subkeys {
+            return Err(anyhow!(
+                "Cert has {} subkeys, exceeding the limit of {}",
+                subkeys,
+                self.max_subkeys
+            ));
+        }
+        let userids = tpk.userids().count();
+        if userids > self.max_userids {
+            return Err(anyhow!(
+                "Cert has {} user IDs, exceeding the limit of {}",
+                userids,
+                self.max_userids
+            ));
+        }
+        let self_sigs = tpk
+            .keys()
+            .subkeys()
+            .map(|skb| skb.self_signatures().count())
+            .chain(tpk.userids().map(|uidb| uidb.self_signatures().count()))
+            .chain(Some(tpk.primary_key().self_signatures().count()))
+            .max()
+            .unwrap_or(0);
+        if self_sigs > self.max_self_sigs {
+            return Err(anyhow!(
+                "Cert has a component with {} self-signatures, exceeding the limit of {}",
+                self_sigs,
+                self.max_self_sigs
+            ));
+        }
+
+        if self.reject_expired_subkeys && !is_revoked {
+            let mut subkeys = tpk.keys().subkeys().peekable();
+            let has_subkeys = subkeys.peek().is_some();
+            let has_alive_subkey = subkeys.any(|ka| {
+                ka.with_policy(self, None)
+                    .map(|vka| vka.alive().is_ok())
+                    .unwrap_or(false)
+            });
+            if has_subkeys && !has_alive_subkey {
+                return Err(anyhow!("All subkeys of the cert have expired"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The number of third-party certifications of a component up to
+    /// which new ones are stored.
+    pub fn max_certifications(&self) -> usize {
+        self.max_certifications
+    }
+
+    /// Checks whether the published cert may be stored.
+    pub fn check_published_cert(&self, tpk: &Cert) -> Result<()> {
+        check_size(tpk, self.max_published_cert_size)
+    }
+}
+
+fn check_size(tpk: &Cert, max_size: usize) -> Result<()> {
+    let size = tpk.to_vec()?.len();
+    if size > max_size {
+        return Err(anyhow!(
+            "Cert is {} bytes, exceeding the limit of {} bytes",
+            size,
+            max_size
+        ));
+    }
+    Ok(())
+}
+
+impl Policy for CertPolicy {
+    fn signature(&self, sig: &Signature, sec: HashAlgoSecurity) -> openpgp::Result<()> {
+        self.standard.signature(sig, sec)
+    }
+
+    fn key(&self, ka: &ValidErasedKeyAmalgamation<key::PublicParts>) -> openpgp::Result<()> {
+        self.standard.key(ka)?;
+
+        if let Some(min_key_bits) = self.min_key_bits {
+            let key = ka.key();
+            match key.mpis() {
+                PublicKey::RSA { .. } | PublicKey::DSA { .. } | PublicKey::ElGamal { .. } => {
+                    let bits = key.mpis().bits().unwrap_or(0);
+                    if bits < min_key_bits {
+                        return Err(anyhow!(
+                            "{} bit {} key is shorter than {} bits",
+                            bits,
+                            key.pk_algo(),
+                            min_key_bits
+                        ));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn symmetric_algorithm(&self, algo: SymmetricAlgorithm) -> openpgp::Result<()> {
+        self.standard.symmetric_algorithm(algo)
+    }
+
+    fn aead_algorithm(&self, algo: AEADAlgorithm) -> openpgp::Result<()> {
+        self.standard.aead_algorithm(algo)
+    }
+
+    fn packet(&self, packet: &Packet) -> openpgp::Result<()> {
+        self.standard.packet(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openpgp::cert::CertBuilder;
+    use std::time::Duration;
+
+    #[test]
+    fn default_accepts() {
+        let (tpk, _) = CertBuilder::general_purpose(None, Some("foo@invalid.example.com"))
+            .generate()
+            .unwrap();
+        let policy = CertPolicy::default();
+        assert!(tpk.with_policy(&policy, None).is_ok());
+        assert!(policy.check_cert(&tpk, false).is_ok());
+    }
+
+    #[test]
+    fn from_config() {
+        // The default cipher suite uses Curve25519 and SHA512.
+        let (tpk, _) = CertBuilder::general_purpose(None, Some("foo@invalid.example.com"))
+            .generate()
+            .unwrap();
+
+        let policy = CertPolicy::from_config(&PolicyConfig {
+            reject_asymmetric_algos: vec!["cv25519".to_owned()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(tpk.with_policy(&policy, None).is_err());
+
+        let policy = CertPolicy::from_config(&PolicyConfig {
+            hash_cutoffs: vec![("SHA512".to_owned(), "2000-01-01".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(tpk.with_policy(&policy, None).is_err());
+
+        let policy = CertPolicy::from_config(&PolicyConfig {
+            hash_cutoffs: vec![("SHA512".to_owned(), "2100-01-01".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(tpk.with_policy(&policy, None).is_ok());
+
+        assert!(CertPolicy::from_config(&PolicyConfig {
+            reject_asymmetric_algos: vec!["RSA9000".to_owned()],
+            ..Default::default()
+        })
+        .is_err());
+        assert!(CertPolicy::from_config(&PolicyConfig {
+            hash_cutoffs: vec![("SHA1".to_owned(), "soon".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn min_key_bits() {
+        let (tpk, _) = CertBuilder::general_purpose(
+            openpgp::cert::CipherSuite::RSA2k,
+            Some("foo@invalid.example.com"),
+        )
+        .generate()
+        .unwrap();
+
+        let config = PolicyConfig {
+            min_key_bits: Some(3072),
+            ..Default::default()
+        };
+        let policy = CertPolicy::from_config(&config).unwrap();
+        assert!(tpk.with_policy(&policy, None).is_err());
+        assert!(tpk.with_policy(&CertPolicy::default(), None).is_ok());
+    }
+
+    #[test]
+    fn max_cert_size() {
+        let (tpk, _) = CertBuilder::general_purpose(None, Some("foo@invalid.example.com"))
+            .generate()
+            .unwrap();
+        let size = tpk.to_vec().unwrap().len();
+
+        let policy = |max_cert_size| {
+            CertPolicy::from_config(&PolicyConfig {
+                max_cert_size: Some(max_cert_size),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        assert!(policy(size).check_cert(&tpk, false).is_ok());
+        assert!(policy(size - 1).check_cert(&tpk, false).is_err());
+        assert!(policy(size - 1).check_cert(&tpk, true).is_err());
+    }
+
+    #[test]
+    fn reject_expired_subkeys() {
+        let (tpk, _) = CertBuilder::new()
+            .set_creation_time(SystemTime::now() - Duration::from_secs(24 * 60 * 60))
+            .add_userid("foo@invalid.example.com")
+            .add_subkey(
+                openpgp::types::KeyFlags::empty().set_transport_encryption(),
+                Duration::from_secs(60),
+                None,
+            )
+            .generate()
+            .unwrap();
+        let config = PolicyConfig {
+            reject_expired_subkeys: true,
+            ..Default::default()
+        };
+        let policy = CertPolicy::from_config(&config).unwrap();
+        assert!(policy.check_cert(&tpk, false).is_err());
+        assert!(policy.check_cert(&tpk, true).is_ok());
+        assert!(CertPolicy::default().check_cert(&tpk, false).is_ok());
+
+        let (tpk, _) = CertBuilder::general_purpose(None, Some("foo@invalid.example.com"))
+            .generate()
+            .unwrap();
+        assert!(policy.check_cert(&tpk, false).is_ok());
+    }
+
+    #[test]
+    fn limits() {
+        let (tpk, _) = CertBuilder::new()
+            .add_userid("foo@invalid.example.com")
+            .add_userid("bar@invalid.example.com")
+            .add_transport_encryption_subkey()
+            .add_signing_subkey()
+            .generate()
+            .unwrap();
+
+        let policy = |config| CertPolicy::from_config(&config).unwrap();
+        assert!(CertPolicy::default().check_cert(&tpk, false).is_ok());
+        assert!(policy(PolicyConfig {
+            max_userids: Some(1),
+            ..Default::default()
+        })
+        .check_cert(&tpk, false)
+        .is_err());
+        assert!(policy(PolicyConfig {
+            max_subkeys: Some(1),
+            ..Default::default()
+        })
+        .check_cert(&tpk, true)
+        .is_err());
+        assert!(policy(PolicyConfig {
+            max_self_sigs: Some(0),
+            ..Default::default()
+        })
+        .check_cert(&tpk, false)
+        .is_err());
+
+        let size = tpk.to_vec().unwrap().len();
+        let policy = policy(PolicyConfig {
+            max_published_cert_size: Some(size - 1),
+            ..Default::default()
+        });
+        assert!(policy.check_cert(&tpk, false).is_ok());
+        assert!(policy.check_published_cert(&tpk).is_err());
+    }
+
+    #[test]
+    fn merge() {
+        use {Database, MemoryDatabase};
+
+        let (tpk, _) = CertBuilder::general_purpose(
+            openpgp::cert::CipherSuite::RSA2k,
+            Some("foo@invalid.example.com"),
+        )
+        .generate()
+        .unwrap();
+
+        let config = PolicyConfig {
+            min_key_bits: Some(3072),
+            max_cert_size: Some(1024),
+            ..Default::default()
+        };
+        let db = MemoryDatabase::new().with_policy(CertPolicy::from_config(&config).unwrap());
+        assert!(db.merge(tpk.clone()).is_err());
+        assert!(MemoryDatabase::new().merge(tpk).is_ok());
+    }
+
+    #[test]
+    fn merge_at_limit() {
+        use openpgp::packet::signature::SignatureBuilder;
+        use openpgp::packet::UserID;
+        use openpgp::parse::Parse;
+        use openpgp::types::{ReasonForRevocation, RevocationStatus, SignatureType};
+        use std::convert::TryFrom;
+        use types::Fingerprint;
+        use {Database, MemoryDatabase};
+
+        let t0 = SystemTime::now() - Duration::from_secs(60 * 60);
+        let (alice, _) = CertBuilder::new()
+            .set_creation_time(t0)
+            .add_userid("alice@invalid.example.com")
+            .generate()
+            .unwrap();
+        let (bob, _) = CertBuilder::new()
+            .set_creation_time(t0)
+            .add_userid("bob@invalid.example.com")
+            .generate()
+            .unwrap();
+        let fpr = Fingerprint::try_from(bob.fingerprint()).unwrap();
+        let signer = |tpk: &Cert| {
+            tpk.primary_key()
+                .key()
+                .clone()
+                .parts_into_secret()
+                .unwrap()
+                .into_keypair()
+                .unwrap()
+        };
+
+        // The stored Cert is exactly at the limit.
+        let size = bob
+            .clone()
+            .strip_secret_key_material()
+            .to_vec()
+            .unwrap()
+            .len();
+        let config = PolicyConfig {
+            max_cert_size: Some(size),
+            ..Default::default()
+        };
+        let db = MemoryDatabase::new().with_policy(CertPolicy::from_config(&config).unwrap());
+        db.merge(bob.clone()).unwrap();
+
+        // Third-party certifications that would push the Cert over the
+        // limit are rejected.
+        let mut alice_signer = signer(&alice);
+        let certification = bob
+            .userids()
+            .next()
+            .unwrap()
+            .userid()
+            .bind(
+                &mut alice_signer,
+                &bob,
+                SignatureBuilder::new(SignatureType::GenericCertification),
+            )
+            .unwrap();
+        let certified = bob.clone().insert_packets(certification).unwrap();
+        assert!(db.merge(certified).is_err());
+
+        // Anything else the owner adds is still rejected.
+        let mut bob_signer = signer(&bob);
+        let second_uid = UserID::from("bob2@invalid.example.com");
+        let binding = second_uid
+            .bind(
+                &mut bob_signer,
+                &bob,
+                SignatureBuilder::new(SignatureType::PositiveCertification),
+            )
+            .unwrap();
+        let with_uid = bob
+            .clone()
+            .insert_packets(vec![Packet::from(second_uid), binding.into()])
+            .unwrap();
+        assert!(db.merge(with_uid.clone()).is_err());
+
+        // Also together with a revocation.
+        let revocation = bob
+            .revoke(&mut bob_signer, ReasonForRevocation::KeyCompromised, b"")
+            .unwrap();
+        let revoked_with_uid = with_uid.insert_packets(revocation.clone()).unwrap();
+        assert!(db.merge(revoked_with_uid).is_err());
+
+        // But a revocation alone is accepted.
+        let revoked = bob.clone().insert_packets(revocation).unwrap();
+        assert!(revoked.to_vec().unwrap().len() > size);
+        db.merge(revoked).unwrap();
+        let stored = Cert::from_bytes(&db.by_fpr_full(&fpr).unwrap()).unwrap();
+        assert!(matches!(
+            stored.revocation_status(&CertPolicy::default(), None),
+            RevocationStatus::Revoked(_)
+        ));
+    }
+
+    #[test]
+    fn max_certifications() {
+        use openpgp::packet::signature::SignatureBuilder;
+        use openpgp::parse::Parse;
+        use openpgp::types::SignatureType;
+        use std::convert::TryFrom;
+        use types::Fingerprint;
+        use {Database, MemoryDatabase};
+
+        let t0 = SystemTime::now() - Duration::from_secs(60 * 60);
+        let (alice, _) = CertBuilder::new()
+            .set_creation_time(t0)
+            .add_userid("alice@invalid.example.com")
+            .generate()
+            .unwrap();
+        let (bob, _) = CertBuilder::new()
+            .set_creation_time(t0)
+            .add_userid("bob@invalid.example.com")
+            .generate()
+            .unwrap();
+        let fpr = Fingerprint::try_from(bob.fingerprint()).unwrap();
+        let mut alice_signer = alice
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()
+            .unwrap()
+            .into_keypair()
+            .unwrap();
+        let mut certification = |i| {
+            bob.userids()
+                .next()
+                .unwrap()
+                .userid()
+                .bind(
+                    &mut alice_signer,
+                    &bob,
+                    SignatureBuilder::new(SignatureType::GenericCertification)
+                        .set_signature_creation_time(t0 + Duration::from_secs(i))
+                        .unwrap(),
+                )
+                .unwrap()
+        };
+        let first = vec![certification(1), certification(2), certification(3)];
+        let second = certification(4);
+
+        let config = PolicyConfig {
+            max_certifications: Some(2),
+            ..Default::default()
+        };
+        let db = MemoryDatabase::new().with_policy(CertPolicy::from_config(&config).unwrap());
+        let stored_certifications = || {
+            let stored = Cert::from_bytes(&db.by_fpr_full(&fpr).unwrap()).unwrap();
+            let uid = stored.userids().next().unwrap();
+            uid.certifications().cloned().collect::<Vec<_>>()
+        };
+
+        // Certifications beyond the limit are not stored.
+        db.merge(bob.clone().insert_packets(first).unwrap())
+            .unwrap();
+        let stored = stored_certifications();
+        assert_eq!(stored.len(), 2);
+
+        // Stored certifications are never dropped to make room for new
+        // ones.
+        db.merge(bob.clone().insert_packets(second).unwrap())
+            .unwrap();
+        assert_eq!(stored_certifications(), stored);
+    }
+}
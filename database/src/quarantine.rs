@@ -0,0 +1,46 @@
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use types::Fingerprint;
+
+/// A fingerprint of a Cert that is already linked to another Cert.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FingerprintCollision {
+    pub fingerprint: Fingerprint,
+    /// The primary fingerprint of the Cert it is linked to.
+    pub primary: Fingerprint,
+}
+
+/// Why a Cert was quarantined.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QuarantineReason {
+    /// When the Cert was quarantined.
+    pub timestamp: u64,
+    /// The error that stopped the merge.
+    pub error: String,
+    pub collisions: Vec<FingerprintCollision>,
+}
+
+impl QuarantineReason {
+    pub fn new(error: &anyhow::Error, collisions: Vec<FingerprintCollision>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        QuarantineReason {
+            timestamp,
+            error: error.to_string(),
+            collisions,
+        }
+    }
+}
+
+/// A Cert that could not be merged, and awaits review.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuarantinedCert {
+    pub fingerprint: Fingerprint,
+    /// Missing for Certs quarantined before reasons were recorded.
+    pub reason: Option<QuarantineReason>,
+    pub content: String,
+}
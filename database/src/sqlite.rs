@@ -0,0 +1,929 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use sync::{FlockMutexGuard, FlockStripes};
+use types::{Email, Fingerprint, KeyID};
+use write_log::{self, WriteLogCursor, WriteLogDetails, WriteLogEntry};
+use Result;
+use {CertPolicy, Database, QuarantineReason, QuarantinedCert, Query};
+
+use wkd;
+
+use openpgp::{parse::Parse, Cert};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS certs (
+        primary_fingerprint TEXT NOT NULL PRIMARY KEY,
+        full TEXT,
+        published TEXT,
+        published_wkd BLOB
+    );
+
+    CREATE TABLE IF NOT EXISTS cert_fingerprints (
+        fingerprint TEXT NOT NULL PRIMARY KEY,
+        primary_fingerprint TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS cert_keyids (
+        keyid TEXT NOT NULL PRIMARY KEY,
+        primary_fingerprint TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS emails (
+        email TEXT NOT NULL PRIMARY KEY,
+        wkd_domain TEXT NOT NULL,
+        wkd_hash TEXT NOT NULL,
+        primary_fingerprint TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS emails_wkd ON emails (wkd_domain, wkd_hash);
+
+    CREATE TABLE IF NOT EXISTS quarantined (
+        primary_fingerprint TEXT NOT NULL PRIMARY KEY,
+        content BLOB NOT NULL,
+        reason TEXT NOT NULL
+    );
+";
+
+/// A database backend that keeps all Certs and lookup indexes in a
+/// single SQLite file.
+///
+/// The write log is kept in the same line-based daily files as with
+/// the filesystem backend.
+///
+/// Complex operations run in a transaction, which the thread that
+/// started it has to itself.  Other threads wait for it to finish
+/// before using the connection.
+pub struct Sqlite {
+    keys_dir_log: PathBuf,
+    fpr_locks: FlockStripes,
+    email_locks: FlockStripes,
+    policy: CertPolicy,
+
+    conn: Mutex<Connection>,
+    /// The thread whose transaction is open on `conn`, if any.
+    txn_owner: Mutex<Option<ThreadId>>,
+    txn_done: Condvar,
+}
+
+impl Sqlite {
+    pub fn new_from_base(base_dir: impl Into<PathBuf>) -> Result<Self> {
+        let base_dir: PathBuf = base_dir.into();
+        create_dir_all(&base_dir)?;
+
+        let db_file = base_dir.join("keys.sqlite");
+        let keys_dir_log = base_dir.join("log");
+        create_dir_all(&keys_dir_log)?;
+        let fpr_locks = FlockStripes::new(base_dir.join("locks").join("fpr"))?;
+        let email_locks = FlockStripes::new(base_dir.join("locks").join("email"))?;
+
+        let conn = Connection::open(&db_file)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+        conn.execute_batch(SCHEMA)?;
+
+        info!("Opened sqlite database.");
+        info!("db_file: '{}'", db_file.display());
+        Ok(Sqlite {
+            keys_dir_log,
+            fpr_locks,
+            email_locks,
+            policy: CertPolicy::default(),
+            conn: Mutex::new(conn),
+            txn_owner: Mutex::new(None),
+            txn_done: Condvar::new(),
+        })
+    }
+
+    /// Sets the policy deciding which Certs are accepted.
+    pub fn with_policy(mut self, policy: CertPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Locks the connection, once no other thread has a transaction
+    /// open on it.
+    fn conn(&self) -> MutexGuard<Connection> {
+        let me = thread::current().id();
+        let mut owner = self.txn_owner();
+        while owner.map(|owner| owner != me).unwrap_or(false) {
+            owner = self
+                .txn_done
+                .wait(owner)
+                .expect("sqlite transaction mutex poisoned");
+        }
+        self.conn.lock().expect("sqlite connection mutex poisoned")
+    }
+
+    fn txn_owner(&self) -> MutexGuard<Option<ThreadId>> {
+        self.txn_owner
+            .lock()
+            .expect("sqlite transaction mutex poisoned")
+    }
+
+    /// Ends the transaction this thread opened in `journal_begin` with
+    /// the given statement, and lets other threads continue.
+    fn end_transaction(&self, sql: &str) -> Result<()> {
+        let result = {
+            let conn = self.conn();
+            let result = conn.execute_batch(sql);
+            if !conn.is_autocommit() {
+                let _ = conn.execute_batch("ROLLBACK");
+            }
+            result
+        };
+
+        *self.txn_owner() = None;
+        self.txn_done.notify_all();
+        Ok(result?)
+    }
+
+    /// Runs a query that returns at most one value.
+    ///
+    /// Lookups cannot report errors, so they are logged and treated
+    /// like a missing value.
+    fn query_optional<T: rusqlite::types::FromSql>(
+        &self,
+        sql: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Option<T> {
+        self.conn()
+            .query_row(sql, params, |row| row.get(0))
+            .optional()
+            .unwrap_or_else(|e| {
+                error!("Error querying sqlite database: {}", e);
+                None
+            })
+    }
+
+    fn query_string(&self, sql: &str, key: &str) -> Option<String> {
+        self.query_optional(sql, params![key])
+    }
+
+    fn query_bytes(&self, sql: &str, key: &str) -> Option<Vec<u8>> {
+        self.query_optional(sql, params![key])
+    }
+
+    /// Returns all (link, primary fingerprint) pairs of the given
+    /// index table.
+    fn read_links(&self, sql: &str) -> Result<Vec<(String, Fingerprint)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut links = Vec::new();
+        for row in rows {
+            let (link, primary_fp) = row?;
+            links.push((link, Fingerprint::from_str(&primary_fp)?));
+        }
+        Ok(links)
+    }
+
+    /// Loads the published Cert for the given primary fingerprint
+    /// into the cache, failing if there is none.
+    fn cached_published<'a>(
+        &self,
+        tpks: &'a mut HashMap<Fingerprint, Cert>,
+        primary_fp: &Fingerprint,
+    ) -> Result<&'a Cert> {
+        if !tpks.contains_key(primary_fp) {
+            let tpk = self
+                .by_primary_fpr(primary_fp)
+                .ok_or_else(|| format_err!("No Cert with fingerprint {:?}", primary_fp))
+                .and_then(|armored| Cert::from_bytes(armored.as_bytes()))?;
+            tpks.insert(primary_fp.clone(), tpk);
+        }
+        Ok(&tpks[primary_fp])
+    }
+}
+
+impl Database for Sqlite {
+    type MutexGuard = Vec<FlockMutexGuard>;
+    type TempCert = Vec<u8>;
+
+    fn lock_fprs(&self, fprs: &[Fingerprint]) -> Result<Self::MutexGuard> {
+        let names: Vec<String> = fprs.iter().map(|fpr| fpr.to_string()).collect();
+        self.fpr_locks.lock(names.iter().map(String::as_str))
+    }
+
+    fn lock_emails(&self, emails: &[Email]) -> Result<Self::MutexGuard> {
+        self.email_locks.lock(emails.iter().map(Email::as_str))
+    }
+
+    fn write_to_temp(&self, content: &[u8]) -> Result<Self::TempCert> {
+        Ok(content.to_vec())
+    }
+
+    fn write_log_append(
+        &self,
+        filename: &str,
+        fpr_primary: &Fingerprint,
+        details: &WriteLogDetails,
+    ) -> Result<()> {
+        write_log::append(&self.keys_dir_log, filename, fpr_primary, details)
+    }
+
+    fn read_write_log(
+        &self,
+        since: u64,
+        cursor: &WriteLogCursor,
+        limit: usize,
+    ) -> Result<(Vec<WriteLogEntry>, WriteLogCursor)> {
+        write_log::read_write_log(&self.keys_dir_log, since, cursor, limit)
+    }
+
+    fn move_tmp_to_full(&self, content: Self::TempCert, fpr: &Fingerprint) -> Result<()> {
+        let content = String::from_utf8(content)?;
+        self.conn().execute(
+            "INSERT INTO certs (primary_fingerprint, full) VALUES (?1, ?2)
+             ON CONFLICT(primary_fingerprint) DO UPDATE SET full = excluded.full",
+            params![fpr.to_string(), content],
+        )?;
+        Ok(())
+    }
+
+    fn move_tmp_to_published(&self, content: Self::TempCert, fpr: &Fingerprint) -> Result<()> {
+        let content = String::from_utf8(content)?;
+        self.conn().execute(
+            "INSERT INTO certs (primary_fingerprint, published) VALUES (?1, ?2)
+             ON CONFLICT(primary_fingerprint) DO UPDATE SET published = excluded.published",
+            params![fpr.to_string(), content],
+        )?;
+        Ok(())
+    }
+
+    fn move_tmp_to_published_wkd(
+        &self,
+        content: Option<Self::TempCert>,
+        fpr: &Fingerprint,
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO certs (primary_fingerprint, published_wkd) VALUES (?1, ?2)
+             ON CONFLICT(primary_fingerprint) DO UPDATE SET published_wkd = excluded.published_wkd",
+            params![fpr.to_string(), content],
+        )?;
+        Ok(())
+    }
+
+    fn write_to_quarantine(
+        &self,
+        fpr: &Fingerprint,
+        content: &[u8],
+        reason: &QuarantineReason,
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO quarantined (primary_fingerprint, content, reason)
+             VALUES (?1, ?2, ?3)",
+            params![fpr.to_string(), content, serde_json::to_string(reason)?],
+        )?;
+        Ok(())
+    }
+
+    fn list_quarantined(&self) -> Result<Vec<QuarantinedCert>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT primary_fingerprint, content, reason FROM quarantined")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Vec<u8>>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+
+        let mut quarantined = Vec::new();
+        for row in rows {
+            let (fingerprint, content, reason) = row?;
+            quarantined.push(QuarantinedCert {
+                fingerprint: Fingerprint::from_str(&fingerprint)?,
+                reason: Some(serde_json::from_str(&reason)?),
+                content: String::from_utf8(content)?,
+            });
+        }
+        Ok(quarantined)
+    }
+
+    fn remove_quarantined(&self, fpr: &Fingerprint) -> Result<()> {
+        let removed = self.conn().execute(
+            "DELETE FROM quarantined WHERE primary_fingerprint = ?1",
+            params![fpr.to_string()],
+        )?;
+        if removed == 0 {
+            return Err(anyhow!("Key not in quarantine!"));
+        }
+        Ok(())
+    }
+
+    fn remove_tpk(&self, fpr: &Fingerprint) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM certs WHERE primary_fingerprint = ?1",
+            params![fpr.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn check_link_fpr(
+        &self,
+        fpr: &Fingerprint,
+        fpr_target: &Fingerprint,
+    ) -> Result<Option<Fingerprint>> {
+        let link_fpr = self.lookup_primary_fingerprint(&Query::ByFingerprint(fpr.clone()));
+        let link_keyid = self.lookup_primary_fingerprint(&Query::ByKeyID(fpr.into()));
+
+        if let Some(ref link_fpr_target) = link_fpr {
+            if link_fpr_target != fpr_target {
+                info!(
+                    "Fingerprint points to different key for {} (expected {}, found {})",
+                    fpr, fpr_target, link_fpr_target
+                );
+                return Err(anyhow!(format!("Fingerprint collision for key {}", fpr)));
+            }
+        }
+
+        if let Some(ref link_keyid_target) = link_keyid {
+            if link_keyid_target != fpr_target {
+                info!(
+                    "KeyID points to different key for {} (expected {}, found {})",
+                    fpr, fpr_target, link_keyid_target
+                );
+                return Err(anyhow!(format!("KeyID collision for key {}", fpr)));
+            }
+        }
+
+        if link_fpr.is_none() || link_keyid.is_none() {
+            Ok(Some(fpr.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn lookup_primary_fingerprint(&self, term: &Query) -> Option<Fingerprint> {
+        use super::Query::*;
+        let primary_fp = match term {
+            ByFingerprint(ref fp) => self.query_string(
+                "SELECT primary_fingerprint FROM cert_fingerprints WHERE fingerprint = ?1",
+                &fp.to_string(),
+            ),
+            ByKeyID(ref keyid) => self.query_string(
+                "SELECT primary_fingerprint FROM cert_keyids WHERE keyid = ?1",
+                &keyid.to_string(),
+            ),
+            ByEmail(ref email) => self.query_string(
+                "SELECT primary_fingerprint FROM emails WHERE email = ?1",
+                email.as_str(),
+            ),
+            _ => return None,
+        };
+        primary_fp.and_then(|fp| Fingerprint::from_str(&fp).ok())
+    }
+
+    fn link_email(&self, email: &Email, fpr: &Fingerprint) -> Result<()> {
+        let (wkd_hash, wkd_domain) = wkd::encode_wkd(email.as_str())?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO emails (email, wkd_domain, wkd_hash, primary_fingerprint)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![email.as_str(), wkd_domain, wkd_hash, fpr.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn unlink_email(&self, email: &Email, fpr: &Fingerprint) -> Result<()> {
+        self.conn().execute(
+            "DELETE FROM emails WHERE email = ?1 AND primary_fingerprint = ?2",
+            params![email.as_str(), fpr.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn link_fpr(&self, from: &Fingerprint, primary_fpr: &Fingerprint) -> Result<()> {
+        let keyid: KeyID = from.into();
+        let mut conn = self.conn();
+        let sp = conn.savepoint()?;
+        sp.execute(
+            "INSERT OR REPLACE INTO cert_fingerprints (fingerprint, primary_fingerprint)
+             VALUES (?1, ?2)",
+            params![from.to_string(), primary_fpr.to_string()],
+        )?;
+        sp.execute(
+            "INSERT OR REPLACE INTO cert_keyids (keyid, primary_fingerprint) VALUES (?1, ?2)",
+            params![keyid.to_string(), primary_fpr.to_string()],
+        )?;
+        sp.commit()?;
+        Ok(())
+    }
+
+    fn unlink_fpr(&self, from: &Fingerprint, primary_fpr: &Fingerprint) -> Result<()> {
+        let keyid: KeyID = from.into();
+        let mut conn = self.conn();
+        let sp = conn.savepoint()?;
+        sp.execute(
+            "DELETE FROM cert_fingerprints WHERE fingerprint = ?1 AND primary_fingerprint = ?2",
+            params![from.to_string(), primary_fpr.to_string()],
+        )?;
+        sp.execute(
+            "DELETE FROM cert_keyids WHERE keyid = ?1 AND primary_fingerprint = ?2",
+            params![keyid.to_string(), primary_fpr.to_string()],
+        )?;
+        sp.commit()?;
+        Ok(())
+    }
+
+    fn by_fpr_full(&self, fpr: &Fingerprint) -> Option<String> {
+        self.query_string(
+            "SELECT full FROM certs WHERE primary_fingerprint = ?1",
+            &fpr.to_string(),
+        )
+    }
+
+    fn by_primary_fpr(&self, fpr: &Fingerprint) -> Option<String> {
+        self.query_string(
+            "SELECT published FROM certs WHERE primary_fingerprint = ?1",
+            &fpr.to_string(),
+        )
+    }
+
+    fn by_fpr(&self, fpr: &Fingerprint) -> Option<String> {
+        self.query_string(
+            "SELECT certs.published FROM cert_fingerprints
+             JOIN certs USING (primary_fingerprint)
+             WHERE cert_fingerprints.fingerprint = ?1",
+            &fpr.to_string(),
+        )
+    }
+
+    fn by_email(&self, email: &Email) -> Option<String> {
+        self.query_string(
+            "SELECT certs.published FROM emails
+             JOIN certs USING (primary_fingerprint)
+             WHERE emails.email = ?1",
+            email.as_str(),
+        )
+    }
+
+    fn by_email_wkd(&self, email: &Email) -> Option<Vec<u8>> {
+        self.query_bytes(
+            "SELECT certs.published_wkd FROM emails
+             JOIN certs USING (primary_fingerprint)
+             WHERE emails.email = ?1",
+            email.as_str(),
+        )
+    }
+
+    fn by_domain_and_hash_wkd(&self, domain: &str, hash: &str) -> Option<Vec<u8>> {
+        self.query_optional(
+            "SELECT certs.published_wkd FROM emails
+             JOIN certs USING (primary_fingerprint)
+             WHERE emails.wkd_domain = ?1 AND emails.wkd_hash = ?2",
+            params![domain, hash],
+        )
+    }
+
+    fn by_kid(&self, kid: &KeyID) -> Option<String> {
+        self.query_string(
+            "SELECT certs.published FROM cert_keyids
+             JOIN certs USING (primary_fingerprint)
+             WHERE cert_keyids.keyid = ?1",
+            &kid.to_string(),
+        )
+    }
+
+    fn policy(&self) -> &CertPolicy {
+        &self.policy
+    }
+
+    /// Starts a transaction for the complex operation, so that it
+    /// takes effect completely or not at all.
+    fn journal_begin(&self, _fpr_primary: &Fingerprint, _tpk: &Cert) -> Result<()> {
+        let mut owner = self.txn_owner();
+        while owner.is_some() {
+            owner = self
+                .txn_done
+                .wait(owner)
+                .expect("sqlite transaction mutex poisoned");
+        }
+        self.conn
+            .lock()
+            .expect("sqlite connection mutex poisoned")
+            .execute_batch("BEGIN IMMEDIATE")?;
+        *owner = Some(thread::current().id());
+        Ok(())
+    }
+
+    fn journal_commit(&self, _fpr_primary: &Fingerprint) -> Result<()> {
+        self.end_transaction("COMMIT")
+    }
+
+    fn journal_abort(&self, fpr_primary: &Fingerprint) {
+        if let Err(e) = self.end_transaction("ROLLBACK") {
+            error!("Error rolling back update of {}: {}", fpr_primary, e);
+        }
+    }
+
+    /// Checks the database for consistency.
+    ///
+    /// Note that this operation may take a long time, and is
+    /// generally only useful for testing.
+    fn check_consistency(&self) -> Result<()> {
+        // A cache of all Certs, for quick lookups.
+        let mut tpks = HashMap::new();
+
+        let published: Vec<(Fingerprint, bool)> = {
+            let conn = self.conn();
+            let mut stmt = conn.prepare(
+                "SELECT primary_fingerprint, published_wkd IS NOT NULL FROM certs
+                 WHERE published IS NOT NULL",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+            })?;
+            let mut published = Vec::new();
+            for row in rows {
+                let (primary_fp, has_wkd) = row?;
+                published.push((Fingerprint::from_str(&primary_fp)?, has_wkd));
+            }
+            published
+        };
+
+        for (primary_fp, has_wkd) in published {
+            let tpk = self.cached_published(&mut tpks, &primary_fp)?;
+
+            // check that certificate exists in published wkd column
+            let should_wkd_exist = tpk.userids().next().is_some();
+            if should_wkd_exist && !has_wkd {
+                return Err(format_err!("Missing wkd for fp {}", primary_fp));
+            };
+            if !should_wkd_exist && has_wkd {
+                return Err(format_err!("Incorrectly present wkd for fp {}", primary_fp));
+            };
+
+            // check that all subkeys are linked
+            let policy = self.policy();
+            let fingerprints = tpk
+                .keys()
+                .with_policy(policy, None)
+                .for_certification()
+                .for_signing()
+                .map(|amalgamation| amalgamation.key().fingerprint())
+                .map(Fingerprint::try_from)
+                .flatten();
+            for fpr in fingerprints {
+                if let Some(missing_fpr) = self.check_link_fpr(&fpr, &primary_fp)? {
+                    return Err(format_err!(
+                        "Missing link to key {} for sub {}",
+                        primary_fp,
+                        missing_fpr
+                    ));
+                }
+            }
+
+            // check that all published uids are linked
+            let emails = tpk
+                .userids()
+                .map(|binding| binding.userid().clone())
+                .map(|userid| Email::try_from(&userid).unwrap());
+            for email in emails {
+                let linked_fp = self.lookup_primary_fingerprint(&Query::ByEmail(email.clone()));
+                if linked_fp.as_ref() != Some(&primary_fp) {
+                    return Err(format_err!(
+                        "Missing link to key {} for email {}",
+                        primary_fp,
+                        email
+                    ));
+                }
+            }
+        }
+
+        let fpr_links =
+            self.read_links("SELECT fingerprint, primary_fingerprint FROM cert_fingerprints")?;
+        for (fpr, primary_fp) in fpr_links {
+            let tpk = self.cached_published(&mut tpks, &primary_fp)?;
+            let found = tpk
+                .keys()
+                .any(|amalgamation| amalgamation.key().fingerprint().to_hex() == fpr);
+            if !found {
+                return Err(format_err!(
+                    "Fingerprint {} points to the wrong Cert {}, the Cert does not \
+                            contain the (sub)key",
+                    fpr,
+                    primary_fp
+                ));
+            }
+        }
+
+        let keyid_links = self.read_links("SELECT keyid, primary_fingerprint FROM cert_keyids")?;
+        for (keyid, primary_fp) in keyid_links {
+            let tpk = self.cached_published(&mut tpks, &primary_fp)?;
+            let found = tpk
+                .keys()
+                .any(|amalgamation| amalgamation.key().keyid().to_hex() == keyid);
+            if !found {
+                return Err(format_err!(
+                    "KeyID {} points to the wrong Cert {}, the Cert does not \
+                            contain the (sub)key",
+                    keyid,
+                    primary_fp
+                ));
+            }
+        }
+
+        let email_links = self.read_links("SELECT email, primary_fingerprint FROM emails")?;
+        for (email, primary_fp) in email_links {
+            let tpk = self.cached_published(&mut tpks, &primary_fp)?;
+            let email = Email::from_str(&email)?;
+            let found = tpk
+                .userids()
+                .any(|uidb| Email::try_from(uidb.userid()).unwrap() == email);
+            if !found {
+                return Err(format_err!(
+                    "Email {} points to the wrong Cert {}, the Cert does not \
+                            contain the email",
+                    email,
+                    primary_fp
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openpgp::cert::CertBuilder;
+    use openpgp_utils::tpk_to_string;
+    use tempfile::TempDir;
+    use test;
+
+    #[test]
+    fn init() {
+        let tmpdir = TempDir::new().unwrap();
+        let _ = Sqlite::new_from_base(tmpdir.path()).unwrap();
+    }
+
+    fn open_db() -> (TempDir, Sqlite, PathBuf) {
+        let tmpdir = TempDir::new().unwrap();
+        let db = Sqlite::new_from_base(tmpdir.path()).unwrap();
+        let log_path = db.keys_dir_log.join(db.get_current_log_filename());
+
+        (tmpdir, db, log_path)
+    }
+
+    #[test]
+    fn new() {
+        let (_tmp_dir, db, _log_path) = open_db();
+        let k1 = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let k2 = CertBuilder::new()
+            .add_userid("b@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+
+        assert!(!db
+            .merge(k1)
+            .unwrap()
+            .into_tpk_status()
+            .email_status
+            .is_empty());
+        assert!(!db
+            .merge(k2.clone())
+            .unwrap()
+            .into_tpk_status()
+            .email_status
+            .is_empty());
+        assert!(!db.merge(k2).unwrap().into_tpk_status().email_status.len() > 0);
+    }
+
+    #[test]
+    fn reopen() {
+        let tmpdir = TempDir::new().unwrap();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+
+        {
+            let db = Sqlite::new_from_base(tmpdir.path()).unwrap();
+            db.merge(tpk).unwrap();
+        }
+
+        let db = Sqlite::new_from_base(tmpdir.path()).unwrap();
+        assert!(db.by_fpr(&fpr).is_some());
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn aborted_update() {
+        let (_tmp_dir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+
+        let result: Result<()> = db.journaled(&fpr, &tpk, || {
+            let tmp = db.write_to_temp(&tpk_to_string(&tpk)?)?;
+            db.move_tmp_to_full(tmp, &fpr)?;
+            db.link_fpr(&fpr, &fpr)?;
+            Err(anyhow!("interrupted"))
+        });
+        assert!(result.is_err());
+
+        // Nothing of the update remains, and the database is usable.
+        assert!(db.by_fpr_full(&fpr).is_none());
+        assert!(db
+            .lookup_primary_fingerprint(&Query::ByFingerprint(fpr.clone()))
+            .is_none());
+        db.merge(tpk).unwrap();
+        assert!(db.by_fpr_full(&fpr).is_some());
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn transaction_excludes_other_threads() {
+        use std::sync::Arc;
+
+        let (_tmp_dir, db, _log_path) = open_db();
+        let db = Arc::new(db);
+        let tpk = CertBuilder::new().generate().unwrap().0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+
+        db.journal_begin(&fpr, &tpk).unwrap();
+        db.link_fpr(&fpr, &fpr).unwrap();
+
+        // Another thread must neither see nor join the transaction.
+        let reader = {
+            let db = db.clone();
+            let fpr = fpr.clone();
+            thread::spawn(move || db.lookup_primary_fingerprint(&Query::ByFingerprint(fpr)))
+        };
+        thread::sleep(std::time::Duration::from_millis(100));
+        db.journal_abort(&fpr);
+
+        assert!(reader.join().unwrap().is_none());
+    }
+
+    #[test]
+    fn uid_verification() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_uid_verification(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_deletion() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_uid_deletion(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn quarantine() {
+        let (_tmp_dir, mut db, _log_path) = open_db();
+        test::test_quarantine(&mut db);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn delete_cert() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_delete_cert(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn read_write_log() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_read_write_log(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn subkey_lookup() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_subkey_lookup(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn kid_lookup() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_kid_lookup(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn upload_revoked_tpk() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_upload_revoked_tpk(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_revocation() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_uid_revocation(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn regenerate() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_regenerate(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn key_reupload() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_reupload(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_replacement() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_uid_replacement(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_unlinking() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_unlink_uid(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn same_email_1() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_same_email_1(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn same_email_2() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_same_email_2(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn same_email_3() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_same_email_3(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn same_email_4() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_same_email_4(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn no_selfsig() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_no_selfsig(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn bad_uids() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_bad_uids(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn attested_key_signatures() -> Result<()> {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::attested_key_signatures(&mut db, &log_path)?;
+        db.check_consistency()?;
+        Ok(())
+    }
+
+    #[test]
+    fn nonexportable_sigs() -> Result<()> {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::nonexportable_sigs(&mut db, &log_path)?;
+        db.check_consistency()?;
+        Ok(())
+    }
+}
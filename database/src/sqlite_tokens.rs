@@ -0,0 +1,163 @@
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::str;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use stateful_tokens::{random_token, TokenStore, TokenTtls};
+use Result;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS tokens (
+        token_type TEXT NOT NULL,
+        token TEXT NOT NULL,
+        payload BLOB NOT NULL,
+        created INTEGER NOT NULL,
+        PRIMARY KEY (token_type, token)
+    );
+
+    CREATE INDEX IF NOT EXISTS tokens_created ON tokens (token_type, created);
+";
+
+/// A token store keeping all tokens in a single SQLite file.
+///
+/// Several processes can share the file.  Creation times are stored
+/// in milliseconds since the epoch.
+pub struct SqliteTokenStore {
+    conn: Mutex<Connection>,
+    ttls: TokenTtls,
+}
+
+fn millis_since_epoch(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+impl SqliteTokenStore {
+    pub fn new(token_dir: impl Into<PathBuf>) -> Result<Self> {
+        let token_dir = token_dir.into();
+        create_dir_all(&token_dir)?;
+
+        let db_file = token_dir.join("tokens.sqlite");
+        let conn = Connection::open(&db_file)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+
+        info!("Opened sqlite token store");
+        info!("db_file: '{}'", db_file.display());
+
+        Ok(SqliteTokenStore {
+            conn: Mutex::new(conn),
+            ttls: TokenTtls::default(),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<Connection> {
+        self.conn.lock().expect("sqlite connection mutex poisoned")
+    }
+}
+
+impl TokenStore for SqliteTokenStore {
+    fn set_ttl(&mut self, token_type: &str, ttl: Duration) {
+        self.ttls.set(token_type, ttl);
+    }
+
+    fn new_token(&self, token_type: &str, payload: &[u8]) -> Result<String> {
+        let name = random_token();
+        self.conn().execute(
+            "INSERT INTO tokens (token_type, token, payload, created) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                token_type,
+                name,
+                payload,
+                millis_since_epoch(SystemTime::now())
+            ],
+        )?;
+
+        Ok(name)
+    }
+
+    fn pop_token(&self, token_type: &str, token: &str) -> Result<String> {
+        let conn = self.conn();
+        let (payload, created): (Vec<u8>, i64) = conn
+            .query_row(
+                "SELECT payload, created FROM tokens WHERE token_type = ?1 AND token = ?2",
+                params![token_type, token],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("Token not found"))?;
+
+        // Another process may have popped the token in the meantime.
+        let removed = conn.execute(
+            "DELETE FROM tokens WHERE token_type = ?1 AND token = ?2",
+            params![token_type, token],
+        )?;
+        if removed == 0 {
+            return Err(anyhow!("Token not found"));
+        }
+
+        let created = SystemTime::UNIX_EPOCH + Duration::from_millis(created as u64);
+        if self.ttls.is_expired(token_type, created) {
+            return Err(anyhow!("Token expired"));
+        }
+
+        Ok(str::from_utf8(&payload)?.to_string())
+    }
+
+    fn gc(&self) -> Result<usize> {
+        let conn = self.conn();
+        let token_types = {
+            let mut stmt = conn.prepare("SELECT DISTINCT token_type FROM tokens")?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+
+        let mut count = 0;
+        for token_type in token_types {
+            let cutoff = SystemTime::now()
+                .checked_sub(self.ttls.get(&token_type))
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            count += conn.execute(
+                "DELETE FROM tokens WHERE token_type = ?1 AND created < ?2",
+                params![token_type, millis_since_epoch(cutoff)],
+            )?;
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stateful_tokens::tests;
+    use tempfile::TempDir;
+
+    #[test]
+    fn pop_token() {
+        let tmpdir = TempDir::new().unwrap();
+        tests::test_pop_token(&mut SqliteTokenStore::new(tmpdir.path()).unwrap());
+    }
+
+    #[test]
+    fn expiry() {
+        let tmpdir = TempDir::new().unwrap();
+        tests::test_expiry(&mut SqliteTokenStore::new(tmpdir.path()).unwrap());
+    }
+
+    #[test]
+    fn shared_between_stores() {
+        let tmpdir = TempDir::new().unwrap();
+        let store1 = SqliteTokenStore::new(tmpdir.path()).unwrap();
+        let store2 = SqliteTokenStore::new(tmpdir.path()).unwrap();
+
+        let token = store1.new_token("verify", b"payload").unwrap();
+        assert_eq!(store2.pop_token("verify", &token).unwrap(), "payload");
+        assert!(store1.pop_token("verify", &token).is_err());
+    }
+}
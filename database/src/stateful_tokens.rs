@@ -1,16 +1,84 @@
-use std::fs::{create_dir_all, remove_file, File};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read, read_dir, remove_file, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use std::str;
 
+use sqlite_tokens::SqliteTokenStore;
 use Result;
 
-pub struct StatefulTokens {
+/// How long tokens stay valid, unless configured per type.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Stores tokens that carry state, such as pending email
+/// verifications.
+///
+/// Each token expires after the TTL configured for its type.
+pub trait TokenStore: Sync + Send {
+    /// Sets how long tokens of the given type stay valid.
+    fn set_ttl(&mut self, token_type: &str, ttl: Duration);
+
+    /// Stores `payload`, and returns the new token referring to it.
+    fn new_token(&self, token_type: &str, payload: &[u8]) -> Result<String>;
+
+    /// Removes the token, and returns its payload.
+    ///
+    /// Fails if the token does not exist, or has expired.
+    fn pop_token(&self, token_type: &str, token: &str) -> Result<String>;
+
+    /// Removes all expired tokens.
+    ///
+    /// Returns the number of tokens removed.
+    fn gc(&self) -> Result<usize>;
+}
+
+/// The TTLs configured per token type.
+#[derive(Default)]
+pub(crate) struct TokenTtls(HashMap<String, Duration>);
+
+impl TokenTtls {
+    pub(crate) fn set(&mut self, token_type: &str, ttl: Duration) {
+        self.0.insert(token_type.to_owned(), ttl);
+    }
+
+    pub(crate) fn get(&self, token_type: &str) -> Duration {
+        self.0.get(token_type).copied().unwrap_or(DEFAULT_TOKEN_TTL)
+    }
+
+    /// Returns whether a token created at `created` has outlived its
+    /// TTL.
+    pub(crate) fn is_expired(&self, token_type: &str, created: SystemTime) -> bool {
+        // A creation time in the future counts as fresh.
+        let age = SystemTime::now()
+            .duration_since(created)
+            .unwrap_or_default();
+        age > self.get(token_type)
+    }
+}
+
+/// Returns a new random token name.
+pub(crate) fn random_token() -> String {
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+
+    let mut rng = thread_rng();
+    // samples from [a-zA-Z0-9]
+    // 43 chars ~ 256 bit
+    rng.sample_iter(&Alphanumeric).take(43).collect()
+}
+
+/// A token store keeping one file per token.
+///
+/// Each file starts with a line holding the creation time of the
+/// token, in milliseconds since the epoch, followed by the payload.
+pub struct FileTokenStore {
     token_dir: PathBuf,
+    ttls: TokenTtls,
 }
 
-impl StatefulTokens {
+impl FileTokenStore {
     pub fn new(token_dir: impl Into<PathBuf>) -> Result<Self> {
         let token_dir = token_dir.into();
         create_dir_all(&token_dir)?;
@@ -18,38 +86,199 @@ impl StatefulTokens {
         info!("Opened stateful token store");
         info!("token_dir: '{}'", token_dir.display());
 
-        Ok(StatefulTokens { token_dir })
+        Ok(FileTokenStore {
+            token_dir,
+            ttls: TokenTtls::default(),
+        })
+    }
+
+    /// Reads the token stored at `path`, returning its creation time
+    /// and payload.
+    fn read_token(path: &Path) -> Result<(SystemTime, Vec<u8>)> {
+        let mut buf = read(path)?;
+        let header = buf
+            .iter()
+            .position(|&b| b == b'\n')
+            .and_then(|end| Some((end, str::from_utf8(&buf[..end]).ok()?.parse().ok()?)));
+        match header {
+            Some((end, millis)) => {
+                let payload = buf.split_off(end + 1);
+                Ok((
+                    SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+                    payload,
+                ))
+            }
+            // Tokens written before the creation time was recorded
+            // expire one TTL after they were last written.
+            None => Ok((path.metadata()?.modified()?, buf)),
+        }
     }
+}
 
-    pub fn new_token(&self, token_type: &str, payload: &[u8]) -> Result<String> {
-        use rand::distributions::Alphanumeric;
-        use rand::{thread_rng, Rng};
+impl TokenStore for FileTokenStore {
+    fn set_ttl(&mut self, token_type: &str, ttl: Duration) {
+        self.ttls.set(token_type, ttl);
+    }
 
-        let mut rng = thread_rng();
-        // samples from [a-zA-Z0-9]
-        // 43 chars ~ 256 bit
-        let name: String = rng.sample_iter(&Alphanumeric).take(43).collect();
+    fn new_token(&self, token_type: &str, payload: &[u8]) -> Result<String> {
+        let name = random_token();
         let dir = self.token_dir.join(token_type);
         create_dir_all(&dir)?;
 
+        let created = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
         let mut fd = File::create(dir.join(&name))?;
+        writeln!(fd, "{}", created.as_millis())?;
         fd.write_all(payload)?;
 
         Ok(name)
     }
 
-    pub fn pop_token(&self, token_type: &str, token: &str) -> Result<String> {
+    fn pop_token(&self, token_type: &str, token: &str) -> Result<String> {
         let path = self.token_dir.join(token_type).join(token);
-        let buf = {
-            let mut fd = File::open(&path)?;
-            let mut buf = Vec::default();
-
-            fd.read_to_end(&mut buf)?;
-            buf.into_boxed_slice()
-        };
+        let (created, payload) = Self::read_token(&path)?;
 
         remove_file(path)?;
 
-        Ok(str::from_utf8(&buf)?.to_string())
+        if self.ttls.is_expired(token_type, created) {
+            return Err(anyhow!("Token expired"));
+        }
+
+        Ok(String::from_utf8(payload)?)
+    }
+
+    fn gc(&self) -> Result<usize> {
+        let mut count = 0;
+        for type_entry in read_dir(&self.token_dir)? {
+            let type_entry = type_entry?;
+            if !type_entry.file_type()?.is_dir() {
+                continue;
+            }
+            let token_type = type_entry.file_name().to_string_lossy().into_owned();
+
+            for entry in read_dir(type_entry.path())? {
+                let path = entry?.path();
+                // The token may have been popped in the meantime.
+                let expired = Self::read_token(&path)
+                    .map(|(created, _)| self.ttls.is_expired(&token_type, created));
+                if let Ok(true) = expired {
+                    if remove_file(&path).is_ok() {
+                        count += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+/// A token store with its backend chosen at runtime.
+pub enum StatefulTokens {
+    File(FileTokenStore),
+    Sqlite(SqliteTokenStore),
+}
+
+macro_rules! dispatch {
+    ($self:ident, $store:ident => $e:expr) => {
+        match $self {
+            StatefulTokens::File($store) => $e,
+            StatefulTokens::Sqlite($store) => $e,
+        }
+    };
+}
+
+impl From<FileTokenStore> for StatefulTokens {
+    fn from(store: FileTokenStore) -> Self {
+        StatefulTokens::File(store)
+    }
+}
+
+impl From<SqliteTokenStore> for StatefulTokens {
+    fn from(store: SqliteTokenStore) -> Self {
+        StatefulTokens::Sqlite(store)
+    }
+}
+
+impl TokenStore for StatefulTokens {
+    fn set_ttl(&mut self, token_type: &str, ttl: Duration) {
+        dispatch!(self, store => store.set_ttl(token_type, ttl))
+    }
+
+    fn new_token(&self, token_type: &str, payload: &[u8]) -> Result<String> {
+        dispatch!(self, store => store.new_token(token_type, payload))
+    }
+
+    fn pop_token(&self, token_type: &str, token: &str) -> Result<String> {
+        dispatch!(self, store => store.pop_token(token_type, token))
+    }
+
+    fn gc(&self) -> Result<usize> {
+        dispatch!(self, store => store.gc())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    pub fn test_pop_token(tokens: &mut impl TokenStore) {
+        let token = tokens.new_token("verify", b"payload").unwrap();
+        assert_eq!(tokens.pop_token("verify", &token).unwrap(), "payload");
+        assert!(tokens.pop_token("verify", &token).is_err());
+        assert!(tokens.pop_token("other", "does-not-exist").is_err());
+    }
+
+    pub fn test_expiry(tokens: &mut impl TokenStore) {
+        tokens.set_ttl("verify", Duration::from_secs(0));
+
+        let expired = tokens.new_token("verify", b"payload").unwrap();
+        let other = tokens.new_token("other", b"payload").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert!(tokens.pop_token("verify", &expired).is_err());
+        // Expired tokens are removed when popped.
+        assert_eq!(tokens.gc().unwrap(), 0);
+
+        tokens.new_token("verify", b"payload").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(tokens.gc().unwrap(), 1);
+        assert_eq!(tokens.gc().unwrap(), 0);
+        assert_eq!(tokens.pop_token("other", &other).unwrap(), "payload");
+    }
+
+    #[test]
+    fn file_pop_token() {
+        let tmpdir = TempDir::new().unwrap();
+        test_pop_token(&mut FileTokenStore::new(tmpdir.path()).unwrap());
+    }
+
+    #[test]
+    fn file_expiry() {
+        let tmpdir = TempDir::new().unwrap();
+        test_expiry(&mut FileTokenStore::new(tmpdir.path()).unwrap());
+    }
+
+    #[test]
+    fn file_expiry_uses_creation_time() {
+        let tmpdir = TempDir::new().unwrap();
+        let mut tokens = FileTokenStore::new(tmpdir.path()).unwrap();
+        tokens.set_ttl("verify", Duration::from_secs(3600));
+
+        // A token created two hours ago, whose file was just touched.
+        let created = SystemTime::now() - Duration::from_secs(2 * 3600);
+        let millis = created
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        create_dir_all(tmpdir.path().join("verify")).unwrap();
+        let mut fd = File::create(tmpdir.path().join("verify").join("old")).unwrap();
+        writeln!(fd, "{}", millis).unwrap();
+        fd.write_all(b"payload").unwrap();
+
+        let fresh = tokens.new_token("verify", b"payload").unwrap();
+        assert_eq!(tokens.gc().unwrap(), 1);
+        assert!(tokens.pop_token("verify", "old").is_err());
+        assert_eq!(tokens.pop_token("verify", &fresh).unwrap(), "payload");
     }
 }
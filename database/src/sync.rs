@@ -1,6 +1,7 @@
-use std::fs::File;
+use std::fs::{create_dir_all, File, OpenOptions};
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 
 use fs2::FileExt;
 
@@ -56,6 +57,122 @@ impl Drop for FlockMutexGuard {
     }
 }
 
+/// A minimalistic in-process mutex.
+///
+/// Unlike std's `MutexGuard`, the guard does not borrow the mutex, so
+/// it can be handed out by `Database::lock_fprs`.
+// The flag is paired with a `Condvar`, so it has to live in a `Mutex`.
+#[allow(clippy::mutex_atomic)]
+#[derive(Default)]
+pub struct InProcessMutex {
+    locked: Mutex<bool>,
+    released: Condvar,
+}
+
+pub struct InProcessMutexGuard {
+    mutex: Arc<InProcessMutex>,
+}
+
+#[allow(clippy::mutex_atomic)]
+impl InProcessMutexGuard {
+    pub fn lock(mutex: &Arc<InProcessMutex>) -> Self {
+        let mut locked = mutex.locked.lock().unwrap();
+        while *locked {
+            locked = mutex.released.wait(locked).unwrap();
+        }
+        *locked = true;
+        Self {
+            mutex: mutex.clone(),
+        }
+    }
+}
+
+#[allow(clippy::mutex_atomic)]
+impl Drop for InProcessMutexGuard {
+    fn drop(&mut self) {
+        *self.mutex.locked.lock().unwrap() = false;
+        self.mutex.released.notify_one();
+    }
+}
+
+/// Number of stripes each set of fine-grained locks is spread over.
+const LOCK_STRIPES: u64 = 1024;
+
+/// Maps lock names onto stripes, in the order they must be acquired.
+///
+/// Taking all locks of one set in ascending order is what keeps
+/// concurrent callers from deadlocking.  Names sharing a stripe are
+/// only locked once, as locking a stripe twice would block forever.
+fn stripes<'a>(names: impl IntoIterator<Item = &'a str>) -> Vec<u64> {
+    let mut stripes: Vec<u64> = names
+        .into_iter()
+        .map(|name| {
+            // FNV-1a, which, unlike std's hasher, is guaranteed to be
+            // stable across processes.
+            name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            }) % LOCK_STRIPES
+        })
+        .collect();
+    stripes.sort_unstable();
+    stripes.dedup();
+    stripes
+}
+
+/// A set of fine-grained flock-based locks.
+///
+/// Names are hashed onto a fixed number of lock files in a directory.
+/// All locks needed for an operation must be taken in a single call.
+pub struct FlockStripes {
+    dir: PathBuf,
+}
+
+impl FlockStripes {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn lock<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Vec<FlockMutexGuard>> {
+        stripes(names)
+            .into_iter()
+            .map(|stripe| {
+                let path = self.dir.join(stripe.to_string());
+                OpenOptions::new().create(true).write(true).open(&path)?;
+                FlockMutexGuard::lock(path)
+            })
+            .collect()
+    }
+}
+
+/// A set of fine-grained in-process locks.
+///
+/// The in-process counterpart to `FlockStripes`.
+pub struct InProcessStripes {
+    mutexes: Vec<Arc<InProcessMutex>>,
+}
+
+impl Default for InProcessStripes {
+    fn default() -> Self {
+        Self {
+            mutexes: (0..LOCK_STRIPES).map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+impl InProcessStripes {
+    pub fn lock<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> Vec<InProcessMutexGuard> {
+        stripes(names)
+            .into_iter()
+            .map(|stripe| InProcessMutexGuard::lock(&self.mutexes[stripe as usize]))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,4 +217,58 @@ mod tests {
     fn flock_nonexistent() {
         assert!(FlockMutexGuard::lock("nonexistent").is_err());
     }
+
+    #[test]
+    fn in_process_exclusive() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+
+        let mutex = Arc::new(InProcessMutex::default());
+        let released = Arc::new(AtomicBool::new(false));
+
+        let lock = InProcessMutexGuard::lock(&mutex);
+        let waiter = {
+            let released = released.clone();
+            thread::spawn(move || {
+                let _lock = InProcessMutexGuard::lock(&mutex);
+                assert!(released.load(Ordering::SeqCst));
+            })
+        };
+
+        released.store(true, Ordering::SeqCst);
+        drop(lock);
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn stripes_ordered() {
+        let names = [
+            "c@example.org",
+            "a@example.org",
+            "b@example.org",
+            "a@example.org",
+        ];
+        let stripes = stripes(names.iter().cloned());
+        assert_eq!(stripes.len(), 3);
+        assert!(stripes.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn flock_stripes() {
+        let tempdir = TempDir::new().unwrap();
+        let locks = FlockStripes::new(tempdir.path().join("locks")).unwrap();
+
+        let held = locks.lock(vec!["a", "b", "a"]).unwrap();
+        assert_eq!(held.len(), 2);
+        let lock_file = tempdir
+            .path()
+            .join("locks")
+            .join(stripes(vec!["a"])[0].to_string());
+        assert!(File::open(&lock_file)
+            .unwrap()
+            .try_lock_exclusive()
+            .is_err());
+        drop(held);
+        assert!(File::open(&lock_file).unwrap().try_lock_exclusive().is_ok());
+    }
 }
@@ -17,12 +17,14 @@
 use anyhow::Result;
 use std::convert::{TryFrom, TryInto};
 use std::str::FromStr;
+use std::time::SystemTime;
 
 use openpgp::cert::{CertBuilder, UserIDRevocationBuilder};
 use openpgp::types::{KeyFlags, ReasonForRevocation, SignatureType};
 use openpgp::{
     packet::{signature::*, UserID},
     parse::Parse,
+    policy::StandardPolicy,
     types::RevocationStatus,
     Cert, Packet,
 };
@@ -30,9 +32,11 @@ use std::fs;
 use std::path::Path;
 use types::{Email, Fingerprint, KeyID};
 use Database;
+use FingerprintCollision;
 use Query;
+use {WriteLogCursor, WriteLogDetails, WriteLogOp};
 
-use openpgp_utils::POLICY;
+const POLICY: StandardPolicy = StandardPolicy::new();
 
 use EmailAddressStatus;
 use TpkStatus;
@@ -509,6 +513,200 @@ pub fn test_uid_deletion(db: &mut impl Database, log_path: &Path) {
     assert_eq!(tpk.keys().subkeys().count(), n_subkeys);
 }
 
+pub fn test_quarantine(db: &mut impl Database) {
+    let tpk_a = CertBuilder::new()
+        .add_userid("a@example.com")
+        .generate()
+        .unwrap()
+        .0;
+    let tpk_b = CertBuilder::new()
+        .add_userid("b@example.com")
+        .add_signing_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let fpr_a = Fingerprint::try_from(tpk_a.fingerprint()).unwrap();
+    let fpr_b = Fingerprint::try_from(tpk_b.fingerprint()).unwrap();
+    let fpr_b_sign =
+        Fingerprint::try_from(tpk_b.keys().subkeys().next().unwrap().fingerprint()).unwrap();
+
+    db.merge(tpk_a).unwrap();
+    assert!(db.list_quarantined().unwrap().is_empty());
+
+    // make the subkey of b collide with a
+    db.link_fpr(&fpr_b_sign, &fpr_a).unwrap();
+    assert!(db.merge(tpk_b).is_err());
+
+    let quarantined = db.list_quarantined().unwrap();
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].fingerprint, fpr_b);
+    let reason = quarantined[0].reason.as_ref().unwrap();
+    assert_eq!(
+        reason.collisions,
+        vec![FingerprintCollision {
+            fingerprint: fpr_b_sign.clone(),
+            primary: fpr_a.clone(),
+        }]
+    );
+
+    // releasing fails while the collision exists
+    assert!(db.release_quarantined(&fpr_b).is_err());
+    assert_eq!(db.list_quarantined().unwrap().len(), 1);
+
+    db.unlink_fpr(&fpr_b_sign, &fpr_a).unwrap();
+    db.release_quarantined(&fpr_b).unwrap();
+    assert!(db.list_quarantined().unwrap().is_empty());
+    assert!(db.by_fpr(&fpr_b_sign).is_some());
+    assert!(db.release_quarantined(&fpr_b).is_err());
+
+    // purging removes the entry without merging
+    let tpk_c = CertBuilder::new()
+        .add_signing_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let fpr_c = Fingerprint::try_from(tpk_c.fingerprint()).unwrap();
+    let fpr_c_sign =
+        Fingerprint::try_from(tpk_c.keys().subkeys().next().unwrap().fingerprint()).unwrap();
+    db.link_fpr(&fpr_c_sign, &fpr_a).unwrap();
+    assert!(db.merge(tpk_c).is_err());
+    db.unlink_fpr(&fpr_c_sign, &fpr_a).unwrap();
+
+    db.remove_quarantined(&fpr_c).unwrap();
+    assert!(db.list_quarantined().unwrap().is_empty());
+    assert!(db.remove_quarantined(&fpr_c).is_err());
+    assert!(db.by_fpr(&fpr_c).is_none());
+}
+
+pub fn test_delete_cert(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let str_uid2 = "Test B <test_b@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_userid(str_uid2)
+        .add_signing_subkey()
+        .add_transport_encryption_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let fpr_sign: Fingerprint = tpk
+        .keys()
+        .with_policy(&POLICY, None)
+        .for_signing()
+        .map(|amalgamation| amalgamation.key().fingerprint().try_into().unwrap())
+        .next()
+        .unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+    let email2 = Email::from_str(str_uid2).unwrap();
+
+    // upload key and publish one uid
+    db.merge(tpk).unwrap();
+    db.set_email_published(&fpr, &email1).unwrap();
+    assert!(db.by_email_wkd(&email1).is_some());
+
+    // deleting a key that is not in the database fails
+    let other = CertBuilder::new().generate().unwrap().0;
+    let other_fpr = Fingerprint::try_from(other.fingerprint()).unwrap();
+    assert!(db.delete_cert(&other_fpr).is_err());
+
+    db.delete_cert(&fpr).unwrap();
+    check_log_entry(log_path, &fpr);
+
+    // all copies and links are gone
+    assert!(db.by_fpr_full(&fpr).is_none());
+    assert!(db.by_primary_fpr(&fpr).is_none());
+    assert!(db.by_fpr(&fpr).is_none());
+    assert!(db.by_fpr(&fpr_sign).is_none());
+    assert!(db.by_kid(&(&fpr).into()).is_none());
+    assert!(db.by_kid(&(&fpr_sign).into()).is_none());
+    check_mail_none(db, &email1);
+    check_mail_none(db, &email2);
+    assert!(db.lookup(&Query::ByFingerprint(fpr)).unwrap().is_none());
+
+    // the key can be uploaded again afterwards
+    let tpk_status = db
+        .merge(
+            CertBuilder::new()
+                .add_userid(str_uid1)
+                .generate()
+                .unwrap()
+                .0,
+        )
+        .unwrap()
+        .into_tpk_status();
+    assert_eq!(
+        vec![(email1, EmailAddressStatus::NotPublished)],
+        tpk_status.email_status
+    );
+}
+
+pub fn test_read_write_log(db: &mut impl Database, _log_path: &Path) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let tpk1 = CertBuilder::new()
+        .add_userid("a@example.com")
+        .generate()
+        .unwrap()
+        .0;
+    let tpk2 = CertBuilder::new()
+        .add_userid("b@example.com")
+        .generate()
+        .unwrap()
+        .0;
+    let fpr1 = Fingerprint::try_from(tpk1.fingerprint()).unwrap();
+    let fpr2 = Fingerprint::try_from(tpk2.fingerprint()).unwrap();
+
+    db.merge(tpk1).unwrap();
+    db.merge(tpk2).unwrap();
+
+    let start = WriteLogCursor::at_timestamp(now).unwrap();
+    let (entries, cursor) = db.read_write_log(now, &start, 1).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].fingerprint, fpr1);
+    assert!(entries[0].timestamp >= now);
+
+    let (entries, cursor) = db.read_write_log(now, &cursor, 10).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].fingerprint, fpr2);
+
+    let (entries, _) = db.read_write_log(now, &cursor, 10).unwrap();
+    assert!(entries.is_empty());
+
+    let email = Email::from_str("a@example.com").unwrap();
+    db.set_email_published(&fpr1, &email).unwrap();
+    db.set_email_unpublished(&fpr1, &email).unwrap();
+    db.delete_cert(&fpr1).unwrap();
+
+    let (entries, _) = db.read_write_log(now, &start, 10).unwrap();
+    let details: Vec<WriteLogDetails> = entries
+        .into_iter()
+        .map(|entry| entry.details.unwrap())
+        .collect();
+    let ops: Vec<WriteLogOp> = details.iter().map(|details| details.op).collect();
+    assert_eq!(
+        ops,
+        vec![
+            WriteLogOp::Merge,
+            WriteLogOp::Merge,
+            WriteLogOp::Publish,
+            WriteLogOp::Unpublish,
+            WriteLogOp::Delete
+        ]
+    );
+    assert_eq!(details[0].published_before, None);
+    assert!(details[0].published_after.is_some());
+    assert_eq!(details[2].addresses, vec![email.clone()]);
+    assert_eq!(details[2].published_before, details[0].published_after);
+    assert_ne!(details[2].published_before, details[2].published_after);
+    assert_eq!(details[3].addresses, vec![email]);
+    assert_eq!(details[3].published_after, details[0].published_after);
+    assert_eq!(details[4].published_before, details[3].published_after);
+    assert_eq!(details[4].published_after, None);
+}
+
 pub fn test_subkey_lookup(db: &mut impl Database, _log_path: &Path) {
     let tpk = CertBuilder::new()
         .add_userid("Testy <test@example.com>")
@@ -1302,7 +1500,7 @@ pub fn test_no_selfsig(db: &mut impl Database, log_path: &Path) {
 /// Makes sure that attested key signatures are correctly handled.
 pub fn attested_key_signatures(db: &mut impl Database, log_path: &Path) -> Result<()> {
     use openpgp::types::*;
-    use std::time::{Duration, SystemTime};
+    use std::time::Duration;
     let t0 = SystemTime::now() - Duration::new(5 * 60, 0);
     let t1 = SystemTime::now() - Duration::new(4 * 60, 0);
 
@@ -1458,7 +1656,7 @@ pub fn attested_key_signatures(db: &mut impl Database, log_path: &Path) -> Resul
 
 fn check_log_entry(log_path: &Path, fpr: &Fingerprint) {
     let log_data = fs::read_to_string(log_path).unwrap();
-    let last_entry = log_data.lines().last().unwrap().split(' ').last().unwrap();
+    let last_entry = log_data.lines().last().unwrap().split(' ').nth(1).unwrap();
     assert_eq!(last_entry, fpr.to_string());
 }
 
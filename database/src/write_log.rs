@@ -0,0 +1,396 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{create_dir_all, read_dir, read_to_string, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use std::time::SystemTime;
+
+use chrono::prelude::{NaiveDate, TimeZone, Utc};
+use hex;
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use openpgp::types::HashAlgorithm;
+
+use types::{Email, Fingerprint};
+use Result;
+
+const LOG_DATE_FORMAT: &str = "%Y-%m-%d";
+/// The subdirectory of the log directory holding the details logs.
+const DETAILS_DIR: &str = "details";
+
+/// The kind of operation that changed a published Cert.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteLogOp {
+    Merge,
+    Publish,
+    Unpublish,
+    Delete,
+    /// Consistency was restored after an interrupted operation.
+    Replay,
+}
+
+/// What the write log records about a change.
+///
+/// The daily log files keep their `<timestamp> <fingerprint>` lines.
+/// The details are written to a file of the same name in the
+/// `details` subdirectory, one JSON object per line.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WriteLogDetails {
+    pub op: WriteLogOp,
+    /// The addresses that were published or unpublished by the
+    /// change.
+    pub addresses: Vec<Email>,
+    /// Hash of the published Cert before the change, if any.
+    pub published_before: Option<String>,
+    /// Hash of the published Cert after the change, if any.
+    pub published_after: Option<String>,
+}
+
+/// One entry of the write log: a change to a published Cert.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteLogEntry {
+    pub timestamp: u64,
+    pub fingerprint: Fingerprint,
+    /// Missing for entries written before details were recorded.
+    pub details: Option<WriteLogDetails>,
+}
+
+/// A line of the details log.
+#[derive(Serialize, Deserialize)]
+struct DetailsLine {
+    timestamp: u64,
+    fingerprint: String,
+    #[serde(flatten)]
+    details: WriteLogDetails,
+}
+
+/// Returns the hex-encoded SHA256 hash of a published Cert, as
+/// recorded in the write log.
+pub fn published_cert_hash(published: &str) -> Result<String> {
+    let mut hash = HashAlgorithm::SHA256.context()?;
+    hash.update(published.as_bytes());
+    let mut digest = vec![0; hash.digest_size()];
+    hash.digest(&mut digest)?;
+    Ok(hex::encode(digest))
+}
+
+/// Records a change happening now in the log file `filename` in
+/// `log_dir`.
+pub fn append(
+    log_dir: &Path,
+    filename: &str,
+    fpr_primary: &Fingerprint,
+    details: &WriteLogDetails,
+) -> Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // The details go first, so that readers of the log find them.
+    let details_dir = log_dir.join(DETAILS_DIR);
+    create_dir_all(&details_dir)?;
+    let mut details_line = serde_json::to_string(&DetailsLine {
+        timestamp,
+        fingerprint: fpr_primary.to_string(),
+        details: details.clone(),
+    })?;
+    details_line.push('\n');
+    append_line(&details_dir.join(filename), &details_line)?;
+
+    let fingerprint_line = format!("{:010} {}\n", timestamp, fpr_primary);
+    append_line(&log_dir.join(filename), &fingerprint_line)
+}
+
+fn append_line(path: &Path, line: &str) -> Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// A position in the write log.
+///
+/// Points at the next entry to read, given as the log file and the
+/// line within it.  Formatted as `<YYYY-MM-DD>:<line>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteLogCursor {
+    date: NaiveDate,
+    line: usize,
+}
+
+impl WriteLogCursor {
+    /// Returns a cursor at the start of the log file covering
+    /// `timestamp`, or `None` if it is out of range.
+    pub fn at_timestamp(timestamp: u64) -> Option<Self> {
+        let timestamp = Utc
+            .timestamp_opt(i64::try_from(timestamp).ok()?, 0)
+            .single()?;
+        Some(WriteLogCursor {
+            date: timestamp.date().naive_utc(),
+            line: 0,
+        })
+    }
+
+    fn filename(&self) -> String {
+        self.date.format(LOG_DATE_FORMAT).to_string()
+    }
+}
+
+impl fmt::Display for WriteLogCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.filename(), self.line)
+    }
+}
+
+impl FromStr for WriteLogCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let date = parts.next().unwrap_or_default();
+        let line = parts
+            .next()
+            .ok_or_else(|| anyhow!("Malformed cursor: {}", s))?;
+
+        Ok(WriteLogCursor {
+            date: NaiveDate::parse_from_str(date, LOG_DATE_FORMAT)?,
+            line: line.parse()?,
+        })
+    }
+}
+
+fn parse_line(line: &str) -> Option<(u64, Fingerprint)> {
+    let mut fields = line.splitn(2, ' ');
+    let timestamp = fields.next()?.parse().ok()?;
+    let fingerprint = fields.next()?.parse().ok()?;
+    Some((timestamp, fingerprint))
+}
+
+/// Complete lines of a log file, or none if it does not exist.
+///
+/// A line without newline is still being written.
+fn complete_lines(path: &Path) -> Result<Vec<String>> {
+    match read_to_string(path) {
+        Ok(content) => Ok(content
+            .split_inclusive('\n')
+            .filter(|line| line.ends_with('\n'))
+            .map(|line| line.trim_end().to_owned())
+            .collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The details of a day's changes, in the order they were logged.
+///
+/// Changes of a Cert are logged while it is locked, so its entries in
+/// the log and the details log are in the same order.
+fn read_details(path: &Path) -> Result<HashMap<(u64, String), VecDeque<WriteLogDetails>>> {
+    let mut details: HashMap<_, VecDeque<_>> = HashMap::new();
+    for line in complete_lines(path)? {
+        match serde_json::from_str::<DetailsLine>(&line) {
+            Ok(line) => details
+                .entry((line.timestamp, line.fingerprint))
+                .or_default()
+                .push_back(line.details),
+            Err(_) => warn!("Skipping malformed write log details: {:?}", line),
+        }
+    }
+    Ok(details)
+}
+
+/// Reads up to `limit` entries from the write log in `log_dir`.
+///
+/// Reading starts at `cursor`.  Entries older than `since` are
+/// skipped.  Returns the entries and a cursor pointing past them,
+/// which can be used to continue reading once more entries have
+/// been written.
+pub fn read_write_log(
+    log_dir: &Path,
+    since: u64,
+    cursor: &WriteLogCursor,
+    limit: usize,
+) -> Result<(Vec<WriteLogEntry>, WriteLogCursor)> {
+    let mut dates: Vec<NaiveDate> = read_dir(log_dir)?
+        .flatten()
+        .flat_map(|entry| entry.file_name().into_string())
+        .flat_map(|name| NaiveDate::parse_from_str(&name, LOG_DATE_FORMAT))
+        .filter(|date| *date >= cursor.date)
+        .collect();
+    dates.sort_unstable();
+
+    let mut entries = Vec::new();
+    let mut next = cursor.clone();
+    for date in dates {
+        if entries.len() >= limit {
+            break;
+        }
+        if date != next.date {
+            next = WriteLogCursor { date, line: 0 };
+        }
+
+        let lines = complete_lines(&log_dir.join(next.filename()))?;
+        let mut details = read_details(&log_dir.join(DETAILS_DIR).join(next.filename()))?;
+        for (n, line) in lines.iter().enumerate() {
+            if n >= next.line && entries.len() >= limit {
+                return Ok((entries, next));
+            }
+
+            let (timestamp, fingerprint) = match parse_line(line) {
+                Some(entry) => entry,
+                None => {
+                    if n >= next.line {
+                        warn!("Skipping malformed write log line: {:?}", line);
+                        next.line += 1;
+                    }
+                    continue;
+                }
+            };
+            // Skipped lines use up their details, too.
+            let entry_details = details
+                .get_mut(&(timestamp, fingerprint.to_string()))
+                .and_then(VecDeque::pop_front);
+            if n < next.line {
+                continue;
+            }
+
+            next.line += 1;
+            if timestamp >= since {
+                entries.push(WriteLogEntry {
+                    timestamp,
+                    fingerprint,
+                    details: entry_details,
+                });
+            }
+        }
+    }
+
+    Ok((entries, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    const FPR1: &str = "0123456789ABCDEF0123456789ABCDEF01234567";
+    const FPR2: &str = "89ABCDEF0123456789ABCDEF0123456789ABCDEF";
+
+    #[test]
+    fn cursor_roundtrip() {
+        let cursor: WriteLogCursor = "2021-03-04:17".parse().unwrap();
+        assert_eq!(cursor.to_string(), "2021-03-04:17");
+        assert!("../../etc/passwd:0".parse::<WriteLogCursor>().is_err());
+        assert!("2021-03-04".parse::<WriteLogCursor>().is_err());
+    }
+
+    #[test]
+    fn paging() {
+        let tmpdir = TempDir::new().unwrap();
+        write(
+            tmpdir.path().join("2021-01-01"),
+            format!("1609459200 {}\n1609459300 {}\n", FPR1, FPR2),
+        )
+        .unwrap();
+        write(
+            tmpdir.path().join("2021-01-02"),
+            format!("1609545600 {}\n1609545700 {}", FPR2, FPR1),
+        )
+        .unwrap();
+        write(tmpdir.path().join("not-a-log"), "garbage").unwrap();
+
+        let start = WriteLogCursor::at_timestamp(1609459250).unwrap();
+        assert_eq!(start.to_string(), "2021-01-01:0");
+        assert!(WriteLogCursor::at_timestamp(99999999999999).is_none());
+        assert!(WriteLogCursor::at_timestamp(u64::MAX).is_none());
+
+        let (entries, cursor) = read_write_log(tmpdir.path(), 1609459250, &start, 1).unwrap();
+        assert_eq!(
+            entries,
+            vec![WriteLogEntry {
+                timestamp: 1609459300,
+                fingerprint: FPR2.parse().unwrap(),
+                details: None,
+            }]
+        );
+        assert_eq!(cursor.to_string(), "2021-01-01:2");
+
+        // The last line is incomplete, and must not be returned yet.
+        let (entries, cursor) = read_write_log(tmpdir.path(), 0, &cursor, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].timestamp, 1609545600);
+        assert_eq!(cursor.to_string(), "2021-01-02:1");
+
+        let (entries, cursor) = read_write_log(tmpdir.path(), 0, &cursor, 10).unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(cursor.to_string(), "2021-01-02:1");
+    }
+
+    #[test]
+    fn details() {
+        let tmpdir = TempDir::new().unwrap();
+        let details = |op| WriteLogDetails {
+            op,
+            addresses: vec!["a@example.org".parse().unwrap()],
+            published_before: None,
+            published_after: Some(published_cert_hash("cert").unwrap()),
+        };
+        let fpr1 = FPR1.parse().unwrap();
+        let fpr2 = FPR2.parse().unwrap();
+        append(
+            tmpdir.path(),
+            "2021-01-01",
+            &fpr1,
+            &details(WriteLogOp::Merge),
+        )
+        .unwrap();
+        append(
+            tmpdir.path(),
+            "2021-01-01",
+            &fpr2,
+            &details(WriteLogOp::Merge),
+        )
+        .unwrap();
+        append(
+            tmpdir.path(),
+            "2021-01-01",
+            &fpr1,
+            &details(WriteLogOp::Publish),
+        )
+        .unwrap();
+
+        // The log keeps the old format.
+        let log = read_to_string(tmpdir.path().join("2021-01-01")).unwrap();
+        for line in log.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields.len(), 2);
+            assert_eq!(fields[0].len(), 10);
+        }
+        let details_log = read_to_string(tmpdir.path().join("details/2021-01-01")).unwrap();
+        assert!(details_log.contains(r#""op":"publish""#));
+
+        let start: WriteLogCursor = "2021-01-01:0".parse().unwrap();
+        let (entries, cursor) = read_write_log(tmpdir.path(), 0, &start, 10).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].fingerprint, fpr1);
+        assert_eq!(entries[0].details, Some(details(WriteLogOp::Merge)));
+        assert_eq!(entries[1].fingerprint, fpr2);
+        assert_eq!(entries[2].details, Some(details(WriteLogOp::Publish)));
+
+        // Details stay with their entry when paging.
+        let cursor2: WriteLogCursor = "2021-01-01:2".parse().unwrap();
+        let (entries, _) = read_write_log(tmpdir.path(), 0, &cursor2, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].details, Some(details(WriteLogOp::Publish)));
+        assert_eq!(cursor.to_string(), "2021-01-01:3");
+    }
+}
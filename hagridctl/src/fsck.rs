@@ -0,0 +1,45 @@
+use anyhow::Result;
+
+use serde_json;
+
+use HagridConfig;
+
+/// Checks the database, and prints one JSON object per problem.
+///
+/// With `repair`, each problem is fixed if possible, and the object
+/// records whether it was.
+pub fn do_fsck(config: &HagridConfig, repair: bool) -> Result<()> {
+    let db = config.open_filesystem("fsck")?;
+
+    let problems = db.fsck()?;
+    let mut count_unrepaired = 0;
+
+    for problem in &problems {
+        let mut report = serde_json::to_value(problem)?;
+        let repaired = repair
+            && match db.fsck_repair(problem) {
+                Ok(repaired) => repaired,
+                Err(e) => {
+                    report["error"] = e.to_string().into();
+                    false
+                }
+            };
+        if repair {
+            report["repaired"] = repaired.into();
+        }
+        if !repaired {
+            count_unrepaired += 1;
+        }
+        println!("{}", report);
+    }
+
+    if count_unrepaired != 0 {
+        return Err(anyhow!(
+            "{} problems found, {} not repaired",
+            problems.len(),
+            count_unrepaired
+        ));
+    }
+
+    Ok(())
+}
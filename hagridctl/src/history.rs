@@ -0,0 +1,48 @@
+use anyhow::Result;
+
+use std::str::FromStr;
+
+use openpgp::parse::Parse;
+use openpgp::Cert;
+use time;
+
+use database::{CertVersionKind, Database, Query};
+use HagridConfig;
+
+pub fn do_history(config: &HagridConfig, term: &str, armor: bool) -> Result<()> {
+    let db = config.open_db(false)?;
+
+    let query = Query::from_str(term)?;
+    let fpr = db
+        .lookup_primary_fingerprint(&query)
+        .ok_or_else(|| anyhow!("No key found for {}", term))?;
+
+    let versions = db.cert_history(&fpr)?;
+    println!("{}: {} versions", fpr, versions.len());
+
+    for version in versions {
+        let timestamp = time::at_utc(time::Timespec::new(version.timestamp as i64, 0));
+        let kind = match version.kind {
+            CertVersionKind::Full => "full",
+            CertVersionKind::Published => "published",
+        };
+        let tpk = Cert::from_bytes(version.content.as_bytes())?;
+
+        println!();
+        println!(
+            "{} {}: {} user ids, {} subkeys",
+            timestamp.rfc3339(),
+            kind,
+            tpk.userids().count(),
+            tpk.keys().subkeys().count()
+        );
+        for uid in tpk.userids() {
+            println!("  {}", String::from_utf8_lossy(uid.userid().value()));
+        }
+        if armor {
+            print!("{}", version.content);
+        }
+    }
+
+    Ok(())
+}
@@ -20,14 +20,12 @@ use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
 use HagridConfig;
 
-// parsing TPKs takes time, so we benefit from some parallelism. however, the
-// database is locked during the entire merge operation, so we get diminishing
-// returns after the first few threads.
-const NUM_THREADS_MAX: usize = 3;
-
+// parsing TPKs takes time, so we benefit from some parallelism. the database
+// only locks the keys and addresses involved in each merge, so we can use all
+// cores.
 #[allow(clippy::needless_collect)]
 pub fn do_import(config: &HagridConfig, dry_run: bool, input_files: Vec<PathBuf>) -> Result<()> {
-    let num_threads = min(NUM_THREADS_MAX, input_files.len());
+    let num_threads = min(num_cpus::get(), input_files.len());
     let input_file_chunks = setup_chunks(input_files, num_threads);
 
     let multi_progress = Arc::new(MultiProgress::new());
@@ -120,12 +118,7 @@ fn import_from_files(
     input_files: Vec<PathBuf>,
     multi_progress: Arc<MultiProgress>,
 ) -> Result<()> {
-    let db = KeyDatabase::new_internal(
-        config.keys_internal_dir.as_ref().unwrap(),
-        config.keys_external_dir.as_ref().unwrap(),
-        config.tmp_dir.as_ref().unwrap(),
-        dry_run,
-    )?;
+    let db = config.open_db(dry_run)?;
 
     for input_file in input_files {
         import_from_file(&db, &input_file, &multi_progress)?;
@@ -211,7 +204,7 @@ mod import_tests {
     fn import() {
         let root = tempdir().unwrap();
 
-        let db = KeyDatabase::new_from_base(root.path().to_path_buf()).unwrap();
+        let db = Filesystem::new_from_base(root.path().to_path_buf()).unwrap();
 
         // Generate a key and import it.
         let (tpk, _) = openpgp::tpk::TPKBuilder::autocrypt(
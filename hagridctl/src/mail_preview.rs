@@ -0,0 +1,187 @@
+use anyhow::Result;
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use gettext::Catalog;
+use hagrid_mail::Templates;
+
+use HagridConfig;
+
+pub use hagrid_mail::TEMPLATES;
+
+/// Loads the translation catalogs in `po_dir`.
+///
+/// The catalogs are compiled with `msgfmt`, like hagrid's are when it
+/// is built.
+fn load_catalogs(po_dir: &Path) -> Result<Vec<(String, Catalog)>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(po_dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "po") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut catalogs = Vec::new();
+    for path in paths {
+        let output = Command::new("msgfmt")
+            .arg("--output-file=-")
+            .arg(&path)
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "msgfmt failed on {}: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        let lang = path.file_stem().unwrap().to_string_lossy().into_owned();
+        catalogs.push((lang, Catalog::parse(&output.stdout[..])?));
+    }
+    Ok(catalogs)
+}
+
+/// Loads the configured templates, and the sender and base URI to
+/// render them with.
+fn load_templates(config: &HagridConfig, po_dir: &Path) -> Result<(Templates, String, String)> {
+    let template_dir = config
+        .email_template_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("dist/email-templates"));
+    let base_uri = config
+        .base_uri
+        .clone()
+        .unwrap_or_else(|| "http://localhost:8080".to_owned());
+    let from = config
+        .from
+        .clone()
+        .unwrap_or_else(|| "noreply@localhost".to_owned());
+    let templates = Templates::load(&template_dir, load_catalogs(po_dir)?)?;
+    Ok((templates, from, base_uri))
+}
+
+pub fn do_preview(
+    config: &HagridConfig,
+    po_dir: &Path,
+    template: &str,
+    lang: &str,
+    eml: Option<&Path>,
+) -> Result<()> {
+    let (templates, from, base_uri) = load_templates(config, po_dir)?;
+    let message = templates.sample_mail(template, lang, &from, &base_uri)?;
+
+    if let Some(path) = eml {
+        fs::write(path, &message)?;
+        println!("Wrote {}", path.display());
+    } else {
+        io::stdout().write_all(&message)?;
+    }
+
+    Ok(())
+}
+
+pub fn do_check_all(config: &HagridConfig, po_dir: &Path) -> Result<()> {
+    let (templates, from, base_uri) = load_templates(config, po_dir)?;
+    check_all(templates, &from, &base_uri)
+}
+
+/// Renders every template in every language, without falling back
+/// to the default template when a localized one is broken.
+fn check_all(templates: Templates, from: &str, base_uri: &str) -> Result<()> {
+    let templates = templates.with_checks();
+
+    let mut failed = 0;
+    let mut checked = 0;
+    for lang in templates.languages() {
+        for template in TEMPLATES {
+            checked += 1;
+            if let Err(e) = templates.sample_mail(template, lang, from, base_uri) {
+                println!("{} {}: {}", lang, template, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} templates failed to render",
+            failed,
+            checked
+        ));
+    }
+    println!("All {} templates rendered", checked);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const BASE_URI: &str = "http://localhost:8080";
+    const FROM: &str = "noreply@localhost";
+
+    fn load(template_dir: &TempDir) -> Templates {
+        let catalogs = vec![("en", Catalog::empty()), ("de", Catalog::empty())];
+        Templates::load(template_dir.path(), catalogs).unwrap()
+    }
+
+    /// Copies hagrid's default email templates into a new directory.
+    fn copy_templates() -> TempDir {
+        let template_dir = TempDir::new().unwrap();
+        for template in TEMPLATES {
+            for part in &["htm", "txt"] {
+                let name = format!("{}.{}.hbs", template, part);
+                fs::copy(
+                    Path::new("../dist/email-templates").join(&name),
+                    template_dir.path().join(&name),
+                )
+                .unwrap();
+            }
+        }
+        template_dir
+    }
+
+    #[test]
+    fn check_all_templates() {
+        let template_dir = copy_templates();
+        let templates = load(&template_dir);
+        check_all(templates, FROM, BASE_URI).unwrap();
+    }
+
+    #[test]
+    fn check_all_broken_default_template() {
+        let template_dir = copy_templates();
+        fs::write(
+            template_dir.path().join("manage.htm.hbs"),
+            "{{#if}}{{primary_fp}}{{/if}}",
+        )
+        .unwrap();
+
+        let templates = load(&template_dir);
+        assert!(check_all(templates, FROM, BASE_URI).is_err());
+    }
+
+    #[test]
+    fn check_all_broken_localized_template() {
+        let template_dir = copy_templates();
+        fs::create_dir(template_dir.path().join("de")).unwrap();
+        fs::write(
+            template_dir.path().join("de/welcome.txt.hbs"),
+            "{{no_such_helper primary_fp}}",
+        )
+        .unwrap();
+
+        // Sending would fall back to the default template, but
+        // checking must not.
+        let templates = load(&template_dir);
+        assert!(templates
+            .sample_mail("welcome", "de", FROM, BASE_URI)
+            .is_ok());
+        assert!(check_all(templates, FROM, BASE_URI).is_err());
+    }
+}
@@ -1,24 +1,41 @@
+#[macro_use]
 extern crate anyhow;
 extern crate clap;
+extern crate gettext;
 extern crate hagrid_database as database;
+extern crate hagrid_mail;
 extern crate sequoia_openpgp as openpgp;
 extern crate tempfile;
 #[macro_use]
 extern crate serde_derive;
 extern crate indicatif;
+extern crate num_cpus;
+extern crate serde_json;
+extern crate time;
 extern crate toml;
 extern crate walkdir;
 
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::Result;
 
+use database::{
+    CertPolicy, DatabaseConfig, Filesystem, KeyDatabase, PolicyConfig, DEFAULT_HISTORY_RETENTION,
+};
+
 use clap::{App, Arg, SubCommand};
 
+mod fsck;
+mod history;
 mod import;
+mod mail_preview;
+mod quarantine;
 mod regenerate;
+mod tokens;
 
 #[derive(Deserialize)]
 pub struct HagridConfigs {
@@ -32,13 +49,74 @@ pub struct HagridConfigs {
 // see also https://github.com/SergioBenitez/Rocket/issues/228
 #[derive(Deserialize, Clone)]
 pub struct HagridConfig {
+    #[serde(rename = "base-URI")]
+    base_uri: Option<String>,
+    from: Option<String>,
     _template_dir: Option<PathBuf>,
+    email_template_dir: Option<PathBuf>,
+    database: Option<String>,
     keys_internal_dir: Option<PathBuf>,
     keys_external_dir: Option<PathBuf>,
     _assets_dir: Option<PathBuf>,
-    _token_dir: Option<PathBuf>,
+    token_dir: Option<PathBuf>,
+    token_store: Option<String>,
     tmp_dir: Option<PathBuf>,
     _maintenance_file: Option<PathBuf>,
+    history_retention_days: Option<u64>,
+    stateful_token_ttl: Option<HashMap<String, u64>>,
+    policy: Option<PolicyConfig>,
+}
+
+impl HagridConfig {
+    fn history_retention(&self) -> Duration {
+        self.history_retention_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+            .unwrap_or(DEFAULT_HISTORY_RETENTION)
+    }
+
+    fn policy(&self) -> Result<CertPolicy> {
+        match self.policy {
+            Some(ref policy) => CertPolicy::from_config(policy),
+            None => Ok(CertPolicy::default()),
+        }
+    }
+
+    fn backend(&self) -> &str {
+        self.database.as_deref().unwrap_or("filesystem")
+    }
+
+    /// Opens the database with the backend hagrid is configured to use.
+    fn open_db(&self, dry_run: bool) -> Result<KeyDatabase> {
+        let dir = |dir: &Option<PathBuf>, name: &str| {
+            dir.clone()
+                .ok_or_else(|| anyhow!("{} is not configured", name))
+        };
+        KeyDatabase::open(DatabaseConfig {
+            backend: self.backend().to_owned(),
+            keys_internal_dir: dir(&self.keys_internal_dir, "keys_internal_dir")?,
+            keys_external_dir: dir(&self.keys_external_dir, "keys_external_dir")?,
+            tmp_dir: dir(&self.tmp_dir, "tmp_dir")?,
+            history_retention: self.history_retention(),
+            policy: self.policy()?,
+            dry_run,
+        })
+    }
+
+    /// Opens the database for commands that only work on the
+    /// filesystem backend.
+    fn open_filesystem(&self, command: &str) -> Result<Filesystem> {
+        if self.backend() != "filesystem" {
+            return Err(anyhow!(
+                "{} is not supported by the {} backend",
+                command,
+                self.backend()
+            ));
+        }
+        match self.open_db(false)? {
+            KeyDatabase::Filesystem(db) => Ok(db),
+            _ => unreachable!(),
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -63,6 +141,91 @@ fn main() -> Result<()> {
                 .possible_values(&["dev", "stage", "prod"]),
         )
         .subcommand(SubCommand::with_name("regenerate").about("Regenerate symlink directory"))
+        .subcommand(SubCommand::with_name("gc-tokens").about("Remove expired verification tokens"))
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check the database for inconsistencies")
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("fix problems where possible"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Show past versions of a key")
+                .arg(
+                    Arg::with_name("armor")
+                        .short("a")
+                        .long("armor")
+                        .help("print each version as armored key"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .required(true)
+                        .help("fingerprint, key id or email address"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("quarantine")
+                .about("Review keys quarantined because of fingerprint collisions")
+                .subcommand(SubCommand::with_name("list").about("List quarantined keys"))
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show a quarantined key and why it was quarantined")
+                        .arg(Arg::with_name("fingerprint").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("release")
+                        .about("Import a quarantined key, once its collision is resolved")
+                        .arg(Arg::with_name("fingerprint").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("purge")
+                        .about("Discard a quarantined key")
+                        .arg(Arg::with_name("fingerprint").required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mail-preview")
+                .about("Render email templates with sample data")
+                .arg(
+                    Arg::with_name("template")
+                        .short("t")
+                        .long("template")
+                        .takes_value(true)
+                        .possible_values(mail_preview::TEMPLATES)
+                        .required_unless("check-all"),
+                )
+                .arg(
+                    Arg::with_name("lang")
+                        .short("l")
+                        .long("lang")
+                        .takes_value(true)
+                        .default_value("en"),
+                )
+                .arg(
+                    Arg::with_name("eml")
+                        .long("eml")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .help("write the complete mail to FILE"),
+                )
+                .arg(
+                    Arg::with_name("check-all")
+                        .long("check-all")
+                        .conflicts_with_all(&["template", "eml"])
+                        .help("fail if any template doesn't render in any locale"),
+                )
+                .arg(
+                    Arg::with_name("po-dir")
+                        .long("po-dir")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .default_value("po/hagrid")
+                        .help("directory with the translation catalogs, compiled with msgfmt"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("import")
                 .about("Import keys into Hagrid")
@@ -101,6 +264,39 @@ fn main() -> Result<()> {
         import::do_import(&config, dry_run, keyrings)?;
     } else if let Some(_matches) = matches.subcommand_matches("regenerate") {
         regenerate::do_regenerate(&config)?;
+    } else if let Some(_matches) = matches.subcommand_matches("gc-tokens") {
+        tokens::do_gc_tokens(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("fsck") {
+        let repair = matches.occurrences_of("repair") > 0;
+        fsck::do_fsck(&config, repair)?;
+    } else if let Some(matches) = matches.subcommand_matches("quarantine") {
+        match matches.subcommand() {
+            ("list", Some(_)) => quarantine::do_list(&config)?,
+            ("show", Some(m)) => quarantine::do_show(&config, m.value_of("fingerprint").unwrap())?,
+            ("release", Some(m)) => {
+                quarantine::do_release(&config, m.value_of("fingerprint").unwrap())?
+            }
+            ("purge", Some(m)) => {
+                quarantine::do_purge(&config, m.value_of("fingerprint").unwrap())?
+            }
+            _ => println!("{}", matches.usage()),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("mail-preview") {
+        let po_dir = Path::new(matches.value_of("po-dir").unwrap());
+        if matches.occurrences_of("check-all") > 0 {
+            mail_preview::do_check_all(&config, po_dir)?;
+        } else {
+            mail_preview::do_preview(
+                &config,
+                po_dir,
+                matches.value_of("template").unwrap(),
+                matches.value_of("lang").unwrap(),
+                matches.value_of("eml").map(Path::new),
+            )?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("history") {
+        let armor = matches.occurrences_of("armor") > 0;
+        history::do_history(&config, matches.value_of("key").unwrap(), armor)?;
     } else {
         println!("{}", matches.usage());
     }
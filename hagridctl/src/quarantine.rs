@@ -0,0 +1,113 @@
+use anyhow::Result;
+
+use std::str::FromStr;
+
+use openpgp::parse::Parse;
+use openpgp::Cert;
+use time;
+
+use database::types::Fingerprint;
+use database::{Database, ImportResult, KeyDatabase, QuarantinedCert};
+use HagridConfig;
+
+fn find_quarantined(db: &KeyDatabase, fpr: &Fingerprint) -> Result<QuarantinedCert> {
+    db.list_quarantined()?
+        .into_iter()
+        .find(|quarantined| &quarantined.fingerprint == fpr)
+        .ok_or_else(|| anyhow!("{} is not in quarantine", fpr))
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    time::at_utc(time::Timespec::new(timestamp as i64, 0))
+        .rfc3339()
+        .to_string()
+}
+
+pub fn do_list(config: &HagridConfig) -> Result<()> {
+    let db = config.open_db(false)?;
+
+    let mut quarantined = db.list_quarantined()?;
+    quarantined.sort_by_key(|quarantined| {
+        quarantined
+            .reason
+            .as_ref()
+            .map(|reason| reason.timestamp)
+            .unwrap_or(0)
+    });
+
+    for quarantined in quarantined {
+        match quarantined.reason {
+            Some(reason) => {
+                let collisions: Vec<String> = reason
+                    .collisions
+                    .iter()
+                    .map(|collision| format!("{} -> {}", collision.fingerprint, collision.primary))
+                    .collect();
+                println!(
+                    "{} {} {}",
+                    quarantined.fingerprint,
+                    format_timestamp(reason.timestamp),
+                    collisions.join(", ")
+                );
+            }
+            None => println!("{} (no reason recorded)", quarantined.fingerprint),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn do_show(config: &HagridConfig, fpr: &str) -> Result<()> {
+    let db = config.open_db(false)?;
+    let quarantined = find_quarantined(&db, &Fingerprint::from_str(fpr)?)?;
+
+    println!("{}", quarantined.fingerprint);
+    match quarantined.reason {
+        Some(reason) => {
+            println!("quarantined: {}", format_timestamp(reason.timestamp));
+            println!("error: {}", reason.error);
+            for collision in reason.collisions {
+                println!(
+                    "collision: {} is linked to {}",
+                    collision.fingerprint, collision.primary
+                );
+            }
+        }
+        None => println!("no reason recorded"),
+    }
+
+    let tpk = Cert::from_bytes(quarantined.content.as_bytes())?;
+    for uid in tpk.userids() {
+        println!("user id: {}", String::from_utf8_lossy(uid.userid().value()));
+    }
+    for key in tpk.keys().subkeys() {
+        println!("subkey: {}", key.fingerprint());
+    }
+    println!();
+    print!("{}", quarantined.content);
+
+    Ok(())
+}
+
+pub fn do_release(config: &HagridConfig, fpr: &str) -> Result<()> {
+    let db = config.open_db(false)?;
+    let fpr = Fingerprint::from_str(fpr)?;
+
+    match db.release_quarantined(&fpr)? {
+        ImportResult::New(_) => println!("{}: released, new key", fpr),
+        ImportResult::Updated(_) => println!("{}: released, key updated", fpr),
+        ImportResult::Unchanged(_) => println!("{}: released, key unchanged", fpr),
+    }
+
+    Ok(())
+}
+
+pub fn do_purge(config: &HagridConfig, fpr: &str) -> Result<()> {
+    let db = config.open_db(false)?;
+    let fpr = Fingerprint::from_str(fpr)?;
+
+    db.remove_quarantined(&fpr)?;
+    println!("{}: purged", fpr);
+
+    Ok(())
+}
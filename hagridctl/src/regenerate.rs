@@ -7,7 +7,7 @@ use indicatif::{ProgressBar, ProgressStyle};
 use walkdir::WalkDir;
 
 use database::types::Fingerprint;
-use database::{Database, KeyDatabase, RegenerateResult};
+use database::{Database, Filesystem, RegenerateResult};
 use HagridConfig;
 
 struct RegenerateStats<'a> {
@@ -72,12 +72,7 @@ impl<'a> RegenerateStats<'a> {
 }
 
 pub fn do_regenerate(config: &HagridConfig) -> Result<()> {
-    let db = KeyDatabase::new_internal(
-        config.keys_internal_dir.as_ref().unwrap(),
-        config.keys_external_dir.as_ref().unwrap(),
-        config.tmp_dir.as_ref().unwrap(),
-        false,
-    )?;
+    let db = config.open_filesystem("regenerate")?;
 
     let published_dir = config
         .keys_external_dir
@@ -113,7 +108,7 @@ pub fn do_regenerate(config: &HagridConfig) -> Result<()> {
 }
 
 fn regenerate_dir_recursively(
-    db: &KeyDatabase,
+    db: &Filesystem,
     stats: &mut RegenerateStats,
     dir: &Path,
 ) -> Result<()> {
@@ -124,7 +119,7 @@ fn regenerate_dir_recursively(
         .filter(|e| e.file_type().is_file())
         .map(|entry| entry.into_path())
     {
-        let fpr = KeyDatabase::path_to_primary(&path).unwrap();
+        let fpr = Filesystem::path_to_primary(&path).unwrap();
         let result = db.regenerate_links(&fpr);
         stats.update(result, fpr);
     }
@@ -0,0 +1,25 @@
+use anyhow::Result;
+
+use std::time::Duration;
+
+use database::{FileTokenStore, SqliteTokenStore, StatefulTokens, TokenStore};
+use HagridConfig;
+
+pub fn do_gc_tokens(config: &HagridConfig) -> Result<()> {
+    let token_dir = config.token_dir.as_ref().unwrap();
+    let mut tokens: StatefulTokens = match config.token_store.as_deref().unwrap_or("file") {
+        "file" => FileTokenStore::new(token_dir)?.into(),
+        "sqlite" => SqliteTokenStore::new(token_dir)?.into(),
+        token_store => return Err(anyhow!("Unknown token store: {}", token_store)),
+    };
+    if let Some(ref ttls) = config.stateful_token_ttl {
+        for (token_type, ttl) in ttls {
+            tokens.set_ttl(token_type, Duration::from_secs(*ttl));
+        }
+    }
+
+    let count = tokens.gc()?;
+    println!("removed {} expired tokens", count);
+
+    Ok(())
+}
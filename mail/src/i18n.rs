@@ -5,11 +5,15 @@ use handlebars::{
 use std::io;
 
 pub struct I18NHelper {
-    catalogs: Vec<(&'static str, gettext::Catalog)>,
+    catalogs: Vec<(String, gettext::Catalog)>,
 }
 
 impl I18NHelper {
-    pub fn new(catalogs: Vec<(&'static str, gettext::Catalog)>) -> Self {
+    pub fn new<L: Into<String>>(catalogs: Vec<(L, gettext::Catalog)>) -> Self {
+        let catalogs = catalogs
+            .into_iter()
+            .map(|(lang, catalog)| (lang.into(), catalog))
+            .collect();
         Self { catalogs }
     }
 
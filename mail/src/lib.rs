@@ -0,0 +1,526 @@
+//! Rendering of the mail hagrid sends.
+//!
+//! hagrid sends the mail, and hagridctl previews and checks the
+//! templates with the same code.
+
+#[macro_use]
+extern crate anyhow;
+extern crate gettext;
+extern crate glob;
+extern crate handlebars;
+extern crate lettre;
+extern crate rfc2047;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate url;
+extern crate uuid;
+
+#[cfg(test)]
+extern crate tempfile;
+
+use std::path::Path;
+
+use anyhow::Result;
+use gettext::Catalog;
+use handlebars::Handlebars;
+use lettre::builder::{EmailBuilder, Mailbox, MimeMultipartType, PartBuilder};
+use lettre::SendableEmail;
+use rfc2047::rfc2047_encode;
+use serde::Serialize;
+use uuid::Uuid;
+
+mod i18n;
+pub use i18n::I18NHelper;
+
+/// The templates mail is sent with.
+pub const TEMPLATES: &[&str] = &["verify", "manage", "welcome"];
+
+const SAMPLE_FINGERPRINT: &str = "CBCD8F030588653EEDD7E2659B7DD433F254904A";
+const SAMPLE_USERID: &str = "alice@example.org";
+const SAMPLE_TOKEN: &str = "a0ei3iVQXCfuxZGvAmHQV6vAHmCmbuzwwRdyPbwb";
+
+pub mod context {
+    #[derive(Serialize, Clone)]
+    pub struct Verification {
+        pub lang: String,
+        pub primary_fp: String,
+        pub uri: String,
+        pub userid: String,
+        pub base_uri: String,
+        pub domain: String,
+    }
+
+    impl Verification {
+        pub fn new(
+            lang: &str,
+            primary_fp: String,
+            userid: &str,
+            base_uri: &str,
+            token: &str,
+            domain: &str,
+        ) -> Self {
+            Verification {
+                lang: lang.to_owned(),
+                primary_fp,
+                uri: format!("{}/verify/{}", base_uri, token),
+                userid: userid.to_owned(),
+                base_uri: base_uri.to_owned(),
+                domain: domain.to_owned(),
+            }
+        }
+    }
+
+    #[derive(Serialize, Clone)]
+    pub struct Manage {
+        pub lang: String,
+        pub primary_fp: String,
+        pub uri: String,
+        pub base_uri: String,
+        pub domain: String,
+    }
+
+    impl Manage {
+        pub fn new(
+            lang: &str,
+            primary_fp: String,
+            base_uri: &str,
+            link_path: &str,
+            domain: &str,
+        ) -> Self {
+            Manage {
+                lang: lang.to_owned(),
+                primary_fp,
+                uri: format!("{}{}", base_uri, link_path),
+                base_uri: base_uri.to_owned(),
+                domain: domain.to_owned(),
+            }
+        }
+    }
+
+    #[derive(Serialize, Clone)]
+    pub struct Welcome {
+        pub lang: String,
+        pub primary_fp: String,
+        pub uri: String,
+        pub base_uri: String,
+        pub domain: String,
+    }
+
+    impl Welcome {
+        pub fn new(
+            lang: &str,
+            primary_fp: String,
+            base_uri: &str,
+            token: &str,
+            domain: &str,
+        ) -> Self {
+            Welcome {
+                lang: lang.to_owned(),
+                primary_fp,
+                uri: format!("{}/upload/{}", base_uri, token),
+                base_uri: base_uri.to_owned(),
+                domain: domain.to_owned(),
+            }
+        }
+    }
+}
+
+/// The mail templates, and the catalogs to translate them with.
+pub struct Templates {
+    handlebars: Handlebars<'static>,
+    catalogs: Vec<(String, Catalog)>,
+    check: bool,
+}
+
+impl Templates {
+    /// Loads the templates in `template_dir`.
+    ///
+    /// The first catalog is used for languages there is none for.
+    pub fn load<L: Into<String>>(template_dir: &Path, catalogs: Vec<(L, Catalog)>) -> Result<Self> {
+        let catalogs: Vec<(String, Catalog)> = catalogs
+            .into_iter()
+            .map(|(lang, catalog)| (lang.into(), catalog))
+            .collect();
+        if catalogs.is_empty() {
+            return Err(anyhow!("No catalogs to translate mail with"));
+        }
+
+        let mut handlebars = Handlebars::new();
+        let i18n_helper = I18NHelper::new(catalogs.clone());
+        handlebars.register_helper("text", Box::new(i18n_helper));
+
+        let mut glob_path = template_dir.join("**").join("*");
+        glob_path.set_extension("hbs");
+        let glob_path = glob_path.to_str().expect("valid glob path string");
+
+        for path in glob::glob(glob_path).unwrap().flatten() {
+            let template_name = path.strip_prefix(template_dir)?.with_extension("");
+            handlebars.register_template_file(&template_name.to_string_lossy(), &path)?;
+        }
+
+        Ok(Templates {
+            handlebars,
+            catalogs,
+            check: false,
+        })
+    }
+
+    /// Makes a localized template that fails to render an error,
+    /// instead of falling back to the default template.
+    ///
+    /// This is for checking templates, not for sending mail.
+    pub fn with_checks(mut self) -> Self {
+        self.check = true;
+        self
+    }
+
+    /// Returns the languages mail can be sent in.
+    pub fn languages(&self) -> Vec<&str> {
+        self.catalogs
+            .iter()
+            .map(|(lang, _)| lang.as_str())
+            .collect()
+    }
+
+    fn catalog(&self, lang: &str) -> &Catalog {
+        self.catalogs
+            .iter()
+            .find(|(candidate, _)| candidate == lang)
+            .map(|(_, catalog)| catalog)
+            .unwrap_or(&self.catalogs[0].1)
+    }
+
+    /// Returns the subject of mail sent with `template`.
+    ///
+    /// The subjects are marked for translation in hagrid's
+    /// `gettext_strings`.
+    pub fn subject(
+        &self,
+        template: &str,
+        lang: &str,
+        userid: &str,
+        domain: &str,
+    ) -> Result<String> {
+        let catalog = self.catalog(lang);
+        let subject = match template {
+            "verify" => catalog
+                .pgettext(
+                    "Subject for verification email, {0} = userid, {1} = keyserver domain",
+                    "Verify {0} for your key on {1}",
+                )
+                .replace("{0}", userid)
+                .replace("{1}", domain),
+            "manage" => catalog
+                .pgettext(
+                    "Subject for manage email, {} = keyserver domain",
+                    "Manage your key on {}",
+                )
+                .replace("{}", domain),
+            "welcome" => catalog
+                .pgettext(
+                    "Subject for welcome email, {} = keyserver domain",
+                    "Your key upload on {}",
+                )
+                .replace("{}", domain),
+            _ => return Err(anyhow!("Unknown template {}", template)),
+        };
+        Ok(subject)
+    }
+
+    /// Renders the HTML and text parts of `template`.
+    pub fn render(
+        &self,
+        template: &str,
+        locale: &str,
+        ctx: impl Serialize,
+    ) -> Result<(String, String)> {
+        let html = self.render_part(template, locale, "htm", &ctx)?;
+        let txt = self.render_part(template, locale, "txt", &ctx)?;
+
+        Ok((html, txt))
+    }
+
+    /// Renders the localized version of a template, falling back to
+    /// the default one if that is missing or fails to render.
+    ///
+    /// With checks, a localized template that fails to render is an
+    /// error, and errors say which template failed.
+    fn render_part(
+        &self,
+        template: &str,
+        locale: &str,
+        part: &str,
+        ctx: &impl Serialize,
+    ) -> Result<String> {
+        let localized = format!("{}/{}.{}", locale, template, part);
+        match self.handlebars.render(&localized, ctx) {
+            Ok(rendered) => return Ok(rendered),
+            Err(e) if self.check && self.handlebars.get_template(&localized).is_some() => {
+                return Err(anyhow!("{}: {}", localized, e));
+            }
+            Err(_) => (),
+        }
+
+        let name = format!("{}.{}", template, part);
+        self.handlebars.render(&name, ctx).map_err(|e| {
+            if self.check {
+                anyhow!("{}: {}", name, e)
+            } else {
+                anyhow!("Email template failed to render")
+            }
+        })
+    }
+
+    /// Builds the mail `template` would send in the given language,
+    /// filled in with sample data.
+    ///
+    /// The mail is rendered and built exactly like mail that is sent,
+    /// and returned as the complete message.
+    pub fn sample_mail(
+        &self,
+        template: &str,
+        lang: &str,
+        from: &str,
+        base_uri: &str,
+    ) -> Result<Vec<u8>> {
+        if !self.catalogs.iter().any(|(candidate, _)| candidate == lang) {
+            return Err(anyhow!("Unknown language {}", lang));
+        }
+        let domain = domain_of(base_uri)?;
+        let fingerprint = SAMPLE_FINGERPRINT.to_owned();
+
+        let (html, txt) = match template {
+            "verify" => self.render(
+                template,
+                lang,
+                context::Verification::new(
+                    lang,
+                    fingerprint,
+                    SAMPLE_USERID,
+                    base_uri,
+                    SAMPLE_TOKEN,
+                    &domain,
+                ),
+            )?,
+            "manage" => self.render(
+                template,
+                lang,
+                context::Manage::new(
+                    lang,
+                    fingerprint,
+                    base_uri,
+                    &format!("/manage/{}", SAMPLE_TOKEN),
+                    &domain,
+                ),
+            )?,
+            "welcome" => self.render(
+                template,
+                lang,
+                context::Welcome::new(lang, fingerprint, base_uri, SAMPLE_TOKEN, &domain),
+            )?,
+            _ => return Err(anyhow!("Unknown template {}", template)),
+        };
+        let subject = self.subject(template, lang, SAMPLE_USERID, &domain)?;
+
+        let email = build(
+            from.into(),
+            &[SAMPLE_USERID],
+            &subject,
+            &domain,
+            html,
+            txt,
+            None::<fn(&[u8]) -> Result<String>>,
+        )?;
+        Ok(email.message_to_string()?.into_bytes())
+    }
+}
+
+/// Builds a mail with the HTML and text parts as alternatives.
+///
+/// If `encrypt` is given, the parts are encrypted with it, and sent
+/// as described in RFC 3156.
+pub fn build<E>(
+    from: Mailbox,
+    to: &[&str],
+    subject: &str,
+    domain: &str,
+    html: String,
+    txt: String,
+    encrypt: Option<E>,
+) -> Result<SendableEmail>
+where
+    E: FnOnce(&[u8]) -> Result<String>,
+{
+    // build this ourselves, as a temporary workaround for https://github.com/lettre/lettre/issues/400
+    let text = PartBuilder::new()
+        .body(txt)
+        .header(("Content-Type", "text/plain; charset=utf-8"))
+        .header(("Content-Transfer-Encoding", "8bit"))
+        .build();
+
+    let html = PartBuilder::new()
+        .body(html)
+        .header(("Content-Type", "text/html; charset=utf-8"))
+        .header(("Content-Transfer-Encoding", "8bit"))
+        .build();
+
+    let email = EmailBuilder::new()
+        .from(from)
+        .subject(rfc2047_encode(subject))
+        .message_id(format!("<{}@{}>", Uuid::new_v4(), domain));
+
+    let email = match encrypt {
+        None => email
+            .message_type(MimeMultipartType::Alternative)
+            .header(("Content-Transfer-Encoding", "8bit"))
+            .child(text)
+            .child(html),
+        Some(encrypt) => {
+            let body = PartBuilder::new()
+                .message_type(MimeMultipartType::Alternative)
+                .child(text)
+                .child(html)
+                .build();
+            let ciphertext = encrypt(body.as_string().as_bytes())?;
+            let boundary = Uuid::new_v4().to_simple().to_string();
+            email
+                .header((
+                    "Content-Type",
+                    format!(
+                        "multipart/encrypted; protocol=\"application/pgp-encrypted\"; boundary=\"{}\"",
+                        boundary
+                    ),
+                ))
+                .header(("Content-Transfer-Encoding", "7bit"))
+                .body(pgp_mime_body(&boundary, &ciphertext))
+        }
+    };
+
+    let email = to.iter().fold(email, |email, to| email.to(to.to_string()));
+
+    Ok(email.build()?.into())
+}
+
+/// Wraps an encrypted message as described in RFC 3156, section 4.
+fn pgp_mime_body(boundary: &str, ciphertext: &str) -> String {
+    format!(
+        "--{boundary}\r\n\
+         Content-Type: application/pgp-encrypted\r\n\
+         Content-Description: PGP/MIME version identification\r\n\
+         \r\n\
+         Version: 1\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n\
+         Content-Description: OpenPGP encrypted message\r\n\
+         Content-Disposition: inline; filename=\"encrypted.asc\"\r\n\
+         \r\n\
+         {ciphertext}\r\n\
+         --{boundary}--\r\n",
+        boundary = boundary,
+        ciphertext = ciphertext,
+    )
+}
+
+/// Returns the host part of `base_uri`, which mail is sent from.
+pub fn domain_of(base_uri: &str) -> Result<String> {
+    Ok(url::Url::parse(base_uri)?
+        .host_str()
+        .ok_or_else(|| anyhow!("No host in base-URI"))?
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    const BASEDIR: &str = "http://localhost/";
+    const FROM: &str = "test@localhost";
+
+    fn catalogs() -> Vec<(&'static str, Catalog)> {
+        vec![("en", Catalog::empty()), ("de", Catalog::empty())]
+    }
+
+    /// Copies hagrid's default email templates into a new directory.
+    fn copy_templates() -> TempDir {
+        let template_dir = TempDir::new().unwrap();
+        for template in TEMPLATES {
+            for part in &["htm", "txt"] {
+                let name = format!("{}.{}.hbs", template, part);
+                fs::copy(
+                    Path::new("../dist/email-templates").join(&name),
+                    template_dir.path().join(&name),
+                )
+                .unwrap();
+            }
+        }
+        template_dir
+    }
+
+    #[test]
+    fn sample_mail() {
+        let template_dir = copy_templates();
+        let templates = Templates::load(template_dir.path(), catalogs()).unwrap();
+        for template in TEMPLATES {
+            let message = templates
+                .sample_mail(template, "de", FROM, BASEDIR)
+                .unwrap();
+            let mail_content = String::from_utf8(message).unwrap();
+            assert!(mail_content.contains("Content-Type: text/plain; charset=utf-8"));
+            assert!(mail_content.contains("Content-Type: text/html; charset=utf-8"));
+            assert!(mail_content.contains("From: <test@localhost>"));
+            assert!(mail_content.contains("To: <alice@example.org>"));
+            assert!(mail_content.contains("lang=\"de\""));
+            assert!(mail_content.contains(SAMPLE_FINGERPRINT));
+        }
+        assert!(templates
+            .sample_mail("verify", "xx", FROM, BASEDIR)
+            .is_err());
+        assert!(templates.sample_mail("nope", "en", FROM, BASEDIR).is_err());
+    }
+
+    #[test]
+    fn subject() {
+        let template_dir = copy_templates();
+        let templates = Templates::load(template_dir.path(), catalogs()).unwrap();
+        assert_eq!(
+            templates
+                .subject("verify", "en", "alice@example.org", "localhost")
+                .unwrap(),
+            "Verify alice@example.org for your key on localhost"
+        );
+        assert_eq!(
+            templates
+                .subject("welcome", "de", "alice@example.org", "localhost")
+                .unwrap(),
+            "Your key upload on localhost"
+        );
+    }
+
+    #[test]
+    fn broken_localized_template() {
+        let template_dir = copy_templates();
+        fs::create_dir(template_dir.path().join("de")).unwrap();
+        fs::write(
+            template_dir.path().join("de/verify.txt.hbs"),
+            "{{no_such_helper primary_fp}}",
+        )
+        .unwrap();
+
+        // Sending falls back to the default template...
+        let templates = Templates::load(template_dir.path(), catalogs()).unwrap();
+        assert!(templates.sample_mail("verify", "de", FROM, BASEDIR).is_ok());
+
+        // ...but checking templates does not.
+        let templates = templates.with_checks();
+        let err = templates
+            .sample_mail("verify", "de", FROM, BASEDIR)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("de/verify.txt:"));
+        assert!(templates.sample_mail("verify", "en", FROM, BASEDIR).is_ok());
+        assert!(templates.sample_mail("manage", "de", FROM, BASEDIR).is_ok());
+    }
+}
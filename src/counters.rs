@@ -13,6 +13,11 @@ lazy_static! {
         "Sent verification mails",
         &["type", "domain"]
     );
+    static ref MAIL_QUEUE: LabelCounter = LabelCounter::new(
+        "hagrid_mail_queue",
+        "Delivery attempts of queued mails",
+        &["result"]
+    );
     static ref KEY_ADDRESS_PUBLISHED: LabelCounter = LabelCounter::new(
         "hagrid_key_address_published",
         "Verified email addresses",
@@ -29,6 +34,7 @@ pub fn register_counters(registry: &prometheus::Registry) {
     KEY_UPLOAD.register(registry);
 
     MAIL_SENT.register(registry);
+    MAIL_QUEUE.register(registry);
 
     KEY_ADDRESS_PUBLISHED.register(registry);
     KEY_ADDRESS_UNPUBLISHED.register(registry);
@@ -43,6 +49,10 @@ pub fn inc_mail_sent(mail_type: &str, email: &Email) {
     MAIL_SENT.inc(&[mail_type, &anonymized_adddress]);
 }
 
+pub fn inc_mail_queue(result: &str) {
+    MAIL_QUEUE.inc(&[result]);
+}
+
 pub fn inc_address_published(email: &Email) {
     let anonymized_adddress = anonymize_utils::anonymize_address_fallback(email);
     KEY_ADDRESS_PUBLISHED.inc(&[&anonymized_adddress]);
@@ -10,7 +10,9 @@ extern crate structopt;
 use structopt::StructOpt;
 
 extern crate hagrid_database as database;
-use crate::database::{Database, KeyDatabase, Query};
+use crate::database::{
+    CertPolicy, Database, DatabaseConfig, KeyDatabase, Query, DEFAULT_HISTORY_RETENTION,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -22,6 +24,10 @@ pub struct Opt {
     #[structopt(parse(from_os_str))]
     base: PathBuf,
 
+    /// Database backend, one of "filesystem" or "sqlite".
+    #[structopt(long = "database", default_value = "filesystem")]
+    database: String,
+
     /// E-Mail address, Fingerprint, or KeyID of the TPK to delete.
     /// If a Fingerprint or KeyID is given, --all is implied.
     query: String,
@@ -50,7 +56,16 @@ fn main() {
 
 fn real_main() -> Result<()> {
     let opt = Opt::from_args();
-    let db = KeyDatabase::new_from_base(opt.base.canonicalize()?)?;
+    let base = opt.base.canonicalize()?;
+    let db = KeyDatabase::open(DatabaseConfig {
+        backend: opt.database,
+        keys_internal_dir: base.join("keys"),
+        keys_external_dir: base.join("keys"),
+        tmp_dir: base.join("tmp"),
+        history_retention: DEFAULT_HISTORY_RETENTION,
+        policy: CertPolicy::default(),
+        dry_run: false,
+    })?;
     delete(&db, &opt.query.parse()?, opt.all_bindings, opt.all)
 }
 
@@ -84,26 +99,7 @@ fn delete(db: &KeyDatabase, query: &Query, all_bindings: bool, mut all: bool) ->
 
     // Now delete the key(s) itself.
     if all {
-        // TODO
-        /*for skb in tpk.subkeys() {
-            results.push(
-                (skb.subkey().fingerprint().to_keyid().to_string(),
-                 db.unlink_kid(&skb.subkey().fingerprint().try_into()?,
-                               &fp)));
-            results.push(
-                (skb.subkey().fingerprint().to_string(),
-                 db.unlink_fpr(&skb.subkey().fingerprint().try_into()?,
-                               &fp)));
-        }
-
-        results.push(
-            (tpk.fingerprint().to_keyid().to_string(),
-             db.unlink_kid(&tpk.fingerprint().try_into()?,
-                           &fp)));
-        results.push(
-            (tpk.fingerprint().to_string(),
-             db.update(&fp, None)));
-        */
+        results.push((fp.to_string(), db.delete_cert(&fp)));
     }
 
     let mut err = Ok(());
@@ -50,7 +50,6 @@ impl Convert<chrono::DateTime<chrono::offset::Utc>> for Timestamp {
     }
 }
 
-#[allow(clippy::redundant_pattern_matching)]
 pub fn dump<W>(
     input: &mut (dyn io::Read + Sync + Send),
     output: &mut dyn io::Write,
@@ -61,14 +60,79 @@ pub fn dump<W>(
 ) -> Result<Kind>
 where
     W: Into<Option<usize>>,
+{
+    let width = width.into().unwrap_or(80);
+    let mut dumper = PacketDumper::new(width, mpis);
+
+    let result = parse(
+        input,
+        hex,
+        sk,
+        |depth, header, packet, map, additional_fields| {
+            dumper.packet(output, depth, header, packet, map, additional_fields)
+        },
+    );
+
+    match result {
+        Ok(kind) => {
+            dumper.flush(output)?;
+            Ok(kind)
+        }
+        Err(e) => {
+            let _ = dumper.flush(output);
+            Err(e)
+        }
+    }
+}
+
+/// Dumps the packets in `input` as a tree of serializable packets.
+pub fn dump_json(
+    input: &mut (dyn io::Read + Sync + Send),
+    mpis: bool,
+    hex: bool,
+) -> Result<(Kind, Vec<JsonPacket>)> {
+    let dumper = PacketDumper::new(0, mpis);
+    let mut packets: Vec<JsonPacket> = Vec::new();
+
+    let kind = parse(
+        input,
+        hex,
+        None,
+        |depth, header, packet, map, additional_fields| {
+            let packet = dumper.json_packet(
+                Some(&header),
+                &packet,
+                map.as_ref(),
+                additional_fields.unwrap_or_default(),
+            );
+            if depth == 0 {
+                packets.push(packet);
+            } else {
+                packets.last_mut().unwrap().append(depth - 1, packet);
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok((kind, packets))
+}
+
+/// Parses the packets in `input`, and hands each one to `handle`
+/// along with its recursion depth.
+#[allow(clippy::redundant_pattern_matching)]
+fn parse<F>(
+    input: &mut (dyn io::Read + Sync + Send),
+    hex: bool,
+    sk: Option<&SessionKey>,
+    mut handle: F,
+) -> Result<Kind>
+where
+    F: FnMut(usize, Header, Packet, Option<Map>, Option<Vec<String>>) -> Result<()>,
 {
     let mut ppr = self::openpgp::parse::PacketParserBuilder::from_reader(input)?
         .map(hex)
         .build()?;
     let mut message_encrypted = false;
-    let width = width.into().unwrap_or(80);
-    let mut dumper = PacketDumper::new(width, mpis);
-
     while let PacketParserResult::Some(mut pp) = ppr {
         let additional_fields = match pp.packet {
             Packet::Literal(_) => {
@@ -146,8 +210,7 @@ where
         let recursion_depth = pp.recursion_depth();
         let packet = pp.packet.clone();
 
-        dumper.packet(
-            output,
+        handle(
             recursion_depth as usize,
             header,
             packet,
@@ -155,18 +218,10 @@ where
             additional_fields,
         )?;
 
-        let (_, ppr_) = match pp.recurse() {
-            Ok(v) => Ok(v),
-            Err(e) => {
-                let _ = dumper.flush(output);
-                Err(e)
-            }
-        }?;
+        let (_, ppr_) = pp.recurse()?;
         ppr = ppr_;
     }
 
-    dumper.flush(output)?;
-
     if let PacketParserResult::EOF(eof) = ppr {
         if eof.is_message().is_ok() {
             Ok(Kind::Message {
@@ -221,6 +276,71 @@ impl Node {
     }
 }
 
+/// A packet in the JSON dump.
+#[derive(Serialize, Debug)]
+pub struct JsonPacket {
+    /// The packet type, e.g. "Public-Key Packet".
+    pub tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<JsonHeader>,
+    /// Fields specific to the packet type.
+    pub fields: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub additional_fields: Vec<String>,
+    /// The packet's bytes, split into fields.  Only with `hex`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub map: Option<Vec<JsonMapField>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<JsonPacket>,
+}
+
+impl JsonPacket {
+    fn append(&mut self, depth: usize, packet: JsonPacket) {
+        if depth == 0 {
+            self.children.push(packet);
+        } else {
+            self.children
+                .iter_mut()
+                .last()
+                .unwrap()
+                .append(depth - 1, packet);
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct JsonHeader {
+    /// "old" or "new".
+    pub ctb: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_bytes: Option<usize>,
+    pub length: JsonBodyLength,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonBodyLength {
+    Full(u32),
+    /// The length of the first chunk.
+    Partial(u32),
+    Indeterminate,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JsonMapField {
+    pub name: String,
+    pub hex: String,
+}
+
+/// A signature subpacket in the JSON dump.
+#[derive(Serialize, Debug)]
+pub struct JsonSubpacket {
+    /// The subpacket type, e.g. "SignatureCreationTime".
+    pub tag: String,
+    pub critical: bool,
+    pub value: serde_json::Value,
+}
+
 pub struct PacketDumper {
     width: usize,
     mpis: bool,
@@ -1046,6 +1166,401 @@ impl PacketDumper {
         Ok(())
     }
 
+    /// Returns the serializable form of a packet, without children.
+    pub fn json_packet(
+        &self,
+        header: Option<&Header>,
+        p: &Packet,
+        map: Option<&Map>,
+        additional_fields: Vec<String>,
+    ) -> JsonPacket {
+        use self::openpgp::Packet::*;
+        use serde_json::json;
+
+        let tag = match p.kind() {
+            Some(tag) => tag.to_string(),
+            None => "Unknown or Unsupported Packet".into(),
+        };
+
+        let header = header.map(|h| JsonHeader {
+            ctb: if let CTB::Old(_) = h.ctb() {
+                "old"
+            } else {
+                "new"
+            },
+            header_bytes: map.map(|map| {
+                map.iter()
+                    .take(2)
+                    .map(|f| f.as_bytes().len())
+                    .sum::<usize>()
+            }),
+            length: match h.length() {
+                BodyLength::Full(n) => JsonBodyLength::Full(*n),
+                BodyLength::Partial(n) => JsonBodyLength::Partial(*n),
+                BodyLength::Indeterminate => JsonBodyLength::Indeterminate,
+            },
+        });
+
+        fn json_key<P, R>(pd: &PacketDumper, k: &Key<P, R>) -> serde_json::Value
+        where
+            P: key::KeyParts,
+            R: key::KeyRole,
+        {
+            let mut fields = json!({
+                "version": k.version(),
+                "creation_time": k.creation_time().convert().to_rfc3339(),
+                "pk_algo": k.pk_algo().to_string(),
+                "bits": k.mpis().bits(),
+                "fingerprint": k.fingerprint().to_hex(),
+                "keyid": k.keyid().to_hex(),
+            });
+
+            match k.mpis() {
+                mpi::PublicKey::EdDSA { curve, .. } | mpi::PublicKey::ECDSA { curve, .. } => {
+                    fields["curve"] = curve.to_string().into();
+                }
+                mpi::PublicKey::ECDH {
+                    curve, hash, sym, ..
+                } => {
+                    fields["curve"] = curve.to_string().into();
+                    fields["hash_algo"] = hash.to_string().into();
+                    fields["sym_algo"] = sym.to_string().into();
+                }
+                _ => (),
+            }
+
+            if pd.mpis {
+                fields["public_key"] = match k.mpis() {
+                    mpi::PublicKey::RSA { e, n } => {
+                        pd.json_mpis(&[e.value(), n.value()], &["e", "n"])
+                    }
+                    mpi::PublicKey::DSA { p, q, g, y } => pd.json_mpis(
+                        &[p.value(), q.value(), g.value(), y.value()],
+                        &["p", "q", "g", "y"],
+                    ),
+                    mpi::PublicKey::ElGamal { p, g, y } => {
+                        pd.json_mpis(&[p.value(), g.value(), y.value()], &["p", "g", "y"])
+                    }
+                    mpi::PublicKey::EdDSA { q, .. }
+                    | mpi::PublicKey::ECDSA { q, .. }
+                    | mpi::PublicKey::ECDH { q, .. } => pd.json_mpis(&[q.value()], &["q"]),
+                    mpi::PublicKey::Unknown { mpis, rest } => {
+                        let mut values = pd.json_unknown_mpis(mpis);
+                        values["rest"] = hex::encode(rest).into();
+                        values
+                    }
+
+                    // crypto::mpi:Publickey is non-exhaustive
+                    u => format!("{:?}", u).into(),
+                };
+            }
+
+            if let Some(secrets) = k.optional_secret() {
+                fields["secret_key"] = match secrets {
+                    SecretKeyMaterial::Unencrypted(_) => json!({ "encrypted": false }),
+                    SecretKeyMaterial::Encrypted(ref e) => json!({
+                        "encrypted": true,
+                        "s2k": format!("{:?}", e.s2k()),
+                        "sym_algo": e.algo().to_string(),
+                    }),
+                };
+            }
+
+            fields
+        }
+
+        let fields = match p {
+            Unknown(ref u) => json!({
+                "tag": u.tag().to_string(),
+                "error": u.error().to_string(),
+            }),
+
+            PublicKey(ref k) => json_key(self, k),
+            PublicSubkey(ref k) => json_key(self, k),
+            SecretKey(ref k) => json_key(self, k),
+            SecretSubkey(ref k) => json_key(self, k),
+
+            Signature(ref s) => {
+                let mut fields = json!({
+                    "version": s.version(),
+                    "type": s.typ().to_string(),
+                    "pk_algo": s.pk_algo().to_string(),
+                    "hash_algo": s.hash_algo().to_string(),
+                    "hashed_area": s
+                        .hashed_area()
+                        .iter()
+                        .map(|pkt| self.json_subpacket(pkt, s))
+                        .collect::<Vec<_>>(),
+                    "unhashed_area": s
+                        .unhashed_area()
+                        .iter()
+                        .map(|pkt| self.json_subpacket(pkt, s))
+                        .collect::<Vec<_>>(),
+                    "digest_prefix": hex::encode(s.digest_prefix()),
+                    "level": s.level(),
+                });
+                if self.mpis {
+                    fields["signature"] = match s.mpis() {
+                        mpi::Signature::RSA { s } => self.json_mpis(&[s.value()], &["s"]),
+                        mpi::Signature::DSA { r, s }
+                        | mpi::Signature::ElGamal { r, s }
+                        | mpi::Signature::EdDSA { r, s }
+                        | mpi::Signature::ECDSA { r, s } => {
+                            self.json_mpis(&[r.value(), s.value()], &["r", "s"])
+                        }
+                        mpi::Signature::Unknown { mpis, rest } => {
+                            let mut values = self.json_unknown_mpis(mpis);
+                            values["rest"] = hex::encode(rest).into();
+                            values
+                        }
+
+                        // crypto::mpi::Signature is non-exhaustive.
+                        u => format!("{:?}", u).into(),
+                    };
+                }
+                fields
+            }
+
+            OnePassSig(ref o) => json!({
+                "version": o.version(),
+                "type": o.typ().to_string(),
+                "pk_algo": o.pk_algo().to_string(),
+                "hash_algo": o.hash_algo().to_string(),
+                "issuer": o.issuer().to_hex(),
+                "last": o.last(),
+            }),
+
+            Trust(ref p) => json!({ "value": hex::encode(p.value()) }),
+
+            UserID(ref u) => json!({ "value": String::from_utf8_lossy(u.value()) }),
+
+            UserAttribute(ref u) => {
+                use self::openpgp::packet::user_attribute::{Image, Subpacket};
+
+                let subpackets: Vec<String> = u
+                    .subpackets()
+                    .map(|subpacket| match subpacket {
+                        Ok(Subpacket::Image(image)) => match image {
+                            Image::JPEG(data) => format!("JPEG: {} bytes", data.len()),
+                            Image::Private(n, data) => {
+                                format!("Private image({}): {} bytes", n, data.len())
+                            }
+                            Image::Unknown(n, data) => {
+                                format!("Unknown image({}): {} bytes", n, data.len())
+                            }
+                        },
+                        Ok(Subpacket::Unknown(n, data)) => {
+                            format!("Unknown subpacket({}): {} bytes", n, data.len())
+                        }
+                        Err(e) => format!("Invalid subpacket encoding: {}", e),
+                    })
+                    .collect();
+                json!({ "subpackets": subpackets })
+            }
+
+            Marker(_) => json!({}),
+
+            Literal(ref l) => json!({
+                "format": l.format().to_string(),
+                "filename": l.filename().map(String::from_utf8_lossy),
+                "date": l.date().map(|timestamp| timestamp.convert().to_rfc3339()),
+            }),
+
+            CompressedData(ref c) => json!({ "algorithm": c.algo().to_string() }),
+
+            PKESK(ref p) => {
+                let mut fields = json!({
+                    "version": p.version(),
+                    "recipient": p.recipient().to_hex(),
+                    "pk_algo": p.pk_algo().to_string(),
+                });
+                if self.mpis {
+                    fields["esk"] = match p.esk() {
+                        mpi::Ciphertext::RSA { c } => self.json_mpis(&[c.value()], &["c"]),
+                        mpi::Ciphertext::ElGamal { e, c } => {
+                            self.json_mpis(&[e.value(), c.value()], &["e", "c"])
+                        }
+                        mpi::Ciphertext::ECDH { e, key } => {
+                            self.json_mpis(&[e.value(), key], &["e", "key"])
+                        }
+                        mpi::Ciphertext::Unknown { mpis, rest } => {
+                            let mut values = self.json_unknown_mpis(mpis);
+                            values["rest"] = hex::encode(rest).into();
+                            values
+                        }
+
+                        // crypto::mpi::Ciphertext is non-exhaustive.
+                        u => format!("{:?}", u).into(),
+                    };
+                }
+                fields
+            }
+
+            SKESK(ref skesk) => match skesk {
+                self::openpgp::packet::SKESK::V4(ref s) => json!({
+                    "version": skesk.version(),
+                    "sym_algo": s.symmetric_algo().to_string(),
+                    "s2k": format!("{:?}", s.s2k()),
+                    "esk": s.esk().ok().flatten().map(hex::encode),
+                }),
+
+                self::openpgp::packet::SKESK::V5(ref s) => json!({
+                    "version": skesk.version(),
+                    "sym_algo": s.symmetric_algo().to_string(),
+                    "aead_algo": s.aead_algo().to_string(),
+                    "s2k": format!("{:?}", s.s2k()),
+                    "iv": s.aead_iv().ok().map(hex::encode),
+                    "esk": s.esk().ok().flatten().map(hex::encode),
+                    "digest": hex::encode(s.aead_digest()),
+                }),
+
+                // SKESK is non-exhaustive.
+                u => json!({ "unknown_variant": format!("{:?}", u) }),
+            },
+
+            SEIP(ref s) => json!({ "version": s.version() }),
+
+            MDC(ref m) => json!({
+                "digest": hex::encode(m.digest()),
+                "computed_digest": hex::encode(m.computed_digest()),
+            }),
+
+            AED(ref a) => json!({
+                "version": a.version(),
+                "sym_algo": a.symmetric_algo().to_string(),
+                "aead": a.aead().to_string(),
+                "chunk_size": a.chunk_size(),
+                "iv": hex::encode(a.iv()),
+            }),
+
+            // openpgp::Packet is non-exhaustive.
+            u => json!({ "unknown_variant": format!("{:?}", u) }),
+        };
+
+        let fields = match fields {
+            serde_json::Value::Object(fields) => fields,
+            _ => unreachable!("packet fields are always an object"),
+        };
+
+        let map = map.map(|map| {
+            map.iter()
+                .map(|field| JsonMapField {
+                    name: field.name().to_string(),
+                    hex: hex::encode(field.as_bytes()),
+                })
+                .collect()
+        });
+
+        JsonPacket {
+            tag,
+            header,
+            fields,
+            additional_fields,
+            map,
+            children: Vec::new(),
+        }
+    }
+
+    fn json_subpacket(&self, s: &Subpacket, sig: &Signature) -> JsonSubpacket {
+        use self::SubpacketValue::*;
+        use serde_json::json;
+
+        fn algos<T: std::fmt::Debug>(algos: &[T]) -> serde_json::Value {
+            algos.iter().map(|a| format!("{:?}", a)).collect()
+        }
+
+        let value = match s.value() {
+            Unknown { body, .. } => hex::encode(body).into(),
+            SignatureCreationTime(t) => (*t).convert().to_rfc3339().into(),
+            SignatureExpirationTime(t) => json!({
+                "seconds": t.as_secs(),
+                "time": sig
+                    .signature_creation_time()
+                    .map(|creation| (creation + std::time::Duration::from(*t)).convert().to_rfc3339()),
+            }),
+            ExportableCertification(e) => (*e).into(),
+            TrustSignature { level, trust } => json!({ "level": level, "trust": trust }),
+            RegularExpression(ref r) => String::from_utf8_lossy(r).into(),
+            Revocable(r) => (*r).into(),
+            KeyExpirationTime(t) => t.as_secs().into(),
+            PreferredSymmetricAlgorithms(ref c) => algos(c),
+            RevocationKey(rk) => {
+                let (pk_algo, fp) = rk.revoker();
+                json!({
+                    "fingerprint": fp.to_hex(),
+                    "pk_algo": pk_algo.to_string(),
+                    "sensitive": rk.sensitive(),
+                })
+            }
+            Issuer(ref is) => is.to_hex().into(),
+            NotationData(n) => json!({
+                "name": n.name(),
+                "human_readable": n.flags().human_readable(),
+                "value": if n.flags().human_readable() {
+                    String::from_utf8_lossy(n.value()).into_owned()
+                } else {
+                    hex::encode(n.value())
+                },
+            }),
+            PreferredHashAlgorithms(ref h) => algos(h),
+            PreferredCompressionAlgorithms(ref c) => algos(c),
+            KeyServerPreferences(ref p) => format!("{:?}", p).into(),
+            PreferredKeyServer(ref k) => String::from_utf8_lossy(k).into(),
+            PrimaryUserID(p) => (*p).into(),
+            PolicyURI(ref p) => String::from_utf8_lossy(p).into(),
+            KeyFlags(ref k) => format!("{:?}", k).into(),
+            SignersUserID(ref u) => String::from_utf8_lossy(u).into(),
+            ReasonForRevocation { code, ref reason } => json!({
+                "code": code.to_string(),
+                "reason": String::from_utf8_lossy(reason),
+            }),
+            Features(ref f) => format!("{:?}", f).into(),
+            SignatureTarget {
+                pk_algo,
+                hash_algo,
+                ref digest,
+            } => json!({
+                "pk_algo": pk_algo.to_string(),
+                "hash_algo": hash_algo.to_string(),
+                "digest": hex::encode(digest),
+            }),
+            EmbeddedSignature(ref sig) => {
+                serde_json::to_value(self.json_packet(None, &sig.clone().into(), None, Vec::new()))
+                    .expect("packets serialize")
+            }
+            IssuerFingerprint(ref fp) => fp.to_hex().into(),
+            PreferredAEADAlgorithms(ref c) => algos(c),
+            IntendedRecipient(ref fp) => fp.to_hex().into(),
+            AttestedCertifications(digests) => digests.iter().map(hex::encode).collect(),
+
+            // SubpacketValue is non-exhaustive.
+            u => format!("{:?}", u).into(),
+        };
+
+        JsonSubpacket {
+            tag: format!("{:?}", s.tag()),
+            critical: s.critical(),
+            value,
+        }
+    }
+
+    fn json_mpis(&self, chunks: &[&[u8]], keys: &[&str]) -> serde_json::Value {
+        assert_eq!(chunks.len(), keys.len());
+        keys.iter()
+            .zip(chunks.iter())
+            .map(|(key, chunk)| (key.to_string(), hex::encode(chunk).into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    fn json_unknown_mpis(&self, mpis: &[mpi::MPI]) -> serde_json::Value {
+        mpis.iter()
+            .enumerate()
+            .map(|(i, m)| (format!("mpi{}", i), hex::encode(m.value()).into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
     fn dump_s2k(&self, output: &mut dyn io::Write, i: &str, s2k: &S2K) -> Result<()> {
         use self::S2K::*;
         #[allow(deprecated)]
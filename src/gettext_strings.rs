@@ -1,4 +1,4 @@
-use gettext_macros::t;
+use gettext_macros::{i18n, t};
 
 fn _dummy() {
     t!("Error");
@@ -44,6 +44,7 @@ fn _dummy() {
     t!("Verification Pending");
     t!("<strong>Note:</strong> Some providers delay emails for up to 15 minutes to prevent spam. Please be patient.");
     t!("Send Verification Email");
+    t!("Encrypt it to this key");
     t!("This key contains one identity that could not be parsed as an email address.<br /> This identity can't be published on <span class=\"brand\">keys.openpgp.org</span>.  (<a href=\"/about/faq#non-email-uids\" target=\"_blank\">Why?</a>)");
     t!("This key contains {{ count_unparsed }} identities that could not be parsed as an email address.<br /> These identities can't be published on <span class=\"brand\">keys.openpgp.org</span>.  (<a href=\"/about/faq#non-email-uids\" target=\"_blank\">Why?</a>)");
     t!("This key contains one revoked identity, which is not published. (<a href=\"/about/faq#revoked-uids\" target=\"_blank\">Why?</a>)");
@@ -88,4 +89,45 @@ fn _dummy() {
     t!("To let others find this key from your email address \"{{userid}}\",\nplease follow the link below:");
     t!("You can find more info at {{base_uri}}/about");
     t!("distributing OpenPGP keys since 2019");
+
+    t!("Your key upload on {{domain}}");
+
+    t!("Hi,");
+    t!("This is an automated message from <a href=\"{{base_uri}}\" style=\"text-decoration:none; color: #333\">{{domain}}</a>.");
+    t!("If you didn't upload your key there, please ignore this message.");
+    t!("OpenPGP key: <tt>{{primary_fp}}</tt>");
+    t!("This key was just uploaded for the first time, and is now published without identity information. If you want to allow others to find this key by e-mail address, please follow this link:");
+    t!("You can find more info at <a href=\"{{base_uri}}/about\">{{domain}}/about</a>.");
+    t!("distributing OpenPGP keys since 2019");
+
+    t!("Hi,");
+    t!("This is an automated message from {{domain}}.");
+    t!("If you didn't upload your key there, please ignore this message.");
+    t!("OpenPGP key: {{primary_fp}}");
+    t!("This key was just uploaded for the first time, and is now published without\nidentity information. If you want to allow others to find this key by e-mail\naddress, please follow this link:");
+    t!("You can find more info at {{base_uri}}/about");
+    t!("distributing OpenPGP keys since 2019");
+}
+
+/// The subjects of mail, which hagrid-mail looks up at runtime.
+fn _dummy_mail_subjects(catalog: &gettext::Catalog) {
+    i18n!(
+        catalog,
+        context = "Subject for verification email, {0} = userid, {1} = keyserver domain",
+        "Verify {0} for your key on {1}";
+        "",
+        "",
+    );
+    i18n!(
+        catalog,
+        context = "Subject for manage email, {} = keyserver domain",
+        "Manage your key on {}";
+        ""
+    );
+    i18n!(
+        catalog,
+        context = "Subject for welcome email, {} = keyserver domain",
+        "Your key upload on {}";
+        ""
+    );
 }
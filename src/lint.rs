@@ -0,0 +1,225 @@
+use std::time::{Duration, SystemTime};
+
+use sequoia_openpgp::cert::Preferences;
+use sequoia_openpgp::crypto::mpi::PublicKey;
+use sequoia_openpgp::packet::Signature;
+use sequoia_openpgp::policy::{NullPolicy, Policy};
+use sequoia_openpgp::types::{HashAlgorithm, RevocationStatus};
+use sequoia_openpgp::Cert;
+
+/// Primary keys expiring within this period are reported.
+pub const EXPIRES_SOON: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// RSA keys shorter than this are reported.
+pub const MIN_RSA_BITS: usize = 2048;
+
+/// A problem with a cert that is likely to make OpenPGP
+/// implementations reject or misuse it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lint {
+    /// The binding signature of the given component, a key
+    /// fingerprint or a user ID, uses SHA-1.
+    Sha1Binding { component: String },
+    /// The given key is an RSA key shorter than `MIN_RSA_BITS`.
+    WeakRsa { fingerprint: String, bits: usize },
+    /// The primary key has expired.
+    Expired,
+    /// The primary key expires within `EXPIRES_SOON`.
+    ExpiresSoon { expiration_time: SystemTime },
+    /// There is no valid key usable for encryption.
+    NoEncryptionKey,
+    /// The cert does not announce the features it supports.
+    NoFeatures,
+}
+
+impl Lint {
+    /// Returns a stable identifier for the kind of problem.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lint::Sha1Binding { .. } => "sha1-binding",
+            Lint::WeakRsa { .. } => "weak-rsa",
+            Lint::Expired => "expired",
+            Lint::ExpiresSoon { .. } => "expires-soon",
+            Lint::NoEncryptionKey => "no-encryption-key",
+            Lint::NoFeatures => "no-features",
+        }
+    }
+}
+
+/// Checks the cert for common problems at time `now`.
+///
+/// Validity is judged by `policy`.  If the policy rejects the cert
+/// altogether, only the problems found without a policy are reported.
+pub fn lint(tpk: &Cert, policy: &dyn Policy, now: SystemTime) -> Vec<Lint> {
+    let mut lints = Vec::new();
+
+    // Find the binding signatures regardless of policy, so that we
+    // can point out the ones the policy is likely to reject.
+    let null_policy = &NullPolicy::new();
+    if is_sha1_binding(
+        tpk.primary_key()
+            .bundle()
+            .binding_signature(null_policy, now),
+    ) {
+        lints.push(Lint::Sha1Binding {
+            component: tpk.fingerprint().to_hex(),
+        });
+    }
+    for uid in tpk.userids() {
+        if is_revoked(uid.revocation_status(null_policy, now)) {
+            continue;
+        }
+        if is_sha1_binding(uid.binding_signature(null_policy, now)) {
+            lints.push(Lint::Sha1Binding {
+                component: String::from_utf8_lossy(uid.userid().value()).into_owned(),
+            });
+        }
+    }
+    for ka in tpk.keys().subkeys() {
+        if is_revoked(ka.bundle().revocation_status(null_policy, now)) {
+            continue;
+        }
+        if is_sha1_binding(ka.bundle().binding_signature(null_policy, now)) {
+            lints.push(Lint::Sha1Binding {
+                component: ka.key().fingerprint().to_hex(),
+            });
+        }
+    }
+
+    for ka in tpk.keys() {
+        if let PublicKey::RSA { .. } = ka.key().mpis() {
+            let bits = ka.key().mpis().bits().unwrap_or(0);
+            if bits < MIN_RSA_BITS {
+                lints.push(Lint::WeakRsa {
+                    fingerprint: ka.key().fingerprint().to_hex(),
+                    bits,
+                });
+            }
+        }
+    }
+
+    let vc = match tpk.with_policy(policy, now) {
+        Ok(vc) => vc,
+        Err(_) => return lints,
+    };
+
+    if let Some(expiration_time) = vc.primary_key().key_expiration_time() {
+        if expiration_time <= now {
+            lints.push(Lint::Expired);
+        } else if expiration_time <= now + EXPIRES_SOON {
+            lints.push(Lint::ExpiresSoon { expiration_time });
+        }
+    }
+
+    let has_encryption_key = vc
+        .keys()
+        .alive()
+        .revoked(false)
+        .for_transport_encryption()
+        .for_storage_encryption()
+        .next()
+        .is_some();
+    if !has_encryption_key {
+        lints.push(Lint::NoEncryptionKey);
+    }
+
+    if !vc.features().map(|f| f.supports_mdc()).unwrap_or(false) {
+        lints.push(Lint::NoFeatures);
+    }
+
+    lints
+}
+
+fn is_revoked(status: RevocationStatus) -> bool {
+    matches!(status, RevocationStatus::Revoked(_))
+}
+
+fn is_sha1_binding(binding: sequoia_openpgp::Result<&Signature>) -> bool {
+    binding
+        .map(|sig| sig.hash_algo() == HashAlgorithm::SHA1)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::packet::signature::SignatureBuilder;
+    use sequoia_openpgp::packet::UserID;
+    use sequoia_openpgp::policy::StandardPolicy;
+    use sequoia_openpgp::types::SignatureType;
+    use sequoia_openpgp::Packet;
+
+    fn codes(tpk: &Cert) -> Vec<&'static str> {
+        lint(tpk, &StandardPolicy::new(), SystemTime::now())
+            .iter()
+            .map(Lint::code)
+            .collect()
+    }
+
+    #[test]
+    fn good_cert() {
+        let (tpk, _) = CertBuilder::general_purpose(None, Some("foo@invalid.example.com"))
+            .generate()
+            .unwrap();
+        assert!(codes(&tpk).is_empty());
+    }
+
+    #[test]
+    fn no_encryption_key() {
+        let (tpk, _) = CertBuilder::new()
+            .add_userid("foo@invalid.example.com")
+            .add_signing_subkey()
+            .generate()
+            .unwrap();
+        assert_eq!(codes(&tpk), vec!["no-encryption-key"]);
+    }
+
+    #[test]
+    fn expiry() {
+        let (tpk, _) = CertBuilder::general_purpose(None, Some("foo@invalid.example.com"))
+            .set_validity_period(Duration::from_secs(24 * 60 * 60))
+            .generate()
+            .unwrap();
+        assert_eq!(codes(&tpk), vec!["expires-soon"]);
+
+        let later = SystemTime::now() + Duration::from_secs(2 * 24 * 60 * 60);
+        let lints = lint(&tpk, &StandardPolicy::new(), later);
+        assert!(lints.contains(&Lint::Expired));
+    }
+
+    #[test]
+    fn sha1_binding() {
+        let (tpk, _) = CertBuilder::general_purpose(None, Some("foo@invalid.example.com"))
+            .generate()
+            .unwrap();
+
+        let mut signer = tpk
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()
+            .unwrap()
+            .into_keypair()
+            .unwrap();
+        let uid = UserID::from("bar@invalid.example.com");
+        let sig = SignatureBuilder::new(SignatureType::PositiveCertification)
+            .set_hash_algo(HashAlgorithm::SHA1)
+            .set_features(sequoia_openpgp::types::Features::sequoia())
+            .unwrap();
+        let sig = uid.bind(&mut signer, &tpk, sig).unwrap();
+        let tpk = tpk
+            .insert_packets(vec![Packet::from(uid), sig.into()])
+            .unwrap()
+            .strip_secret_key_material();
+
+        let lints = lint(&tpk, &StandardPolicy::new(), SystemTime::now());
+        assert_eq!(
+            lints,
+            vec![Lint::Sha1Binding {
+                component: "bar@invalid.example.com".to_string()
+            }]
+        );
+    }
+}
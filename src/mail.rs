@@ -1,62 +1,78 @@
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crate::counters;
-use handlebars::Handlebars;
-use lettre::builder::{EmailBuilder, Mailbox, MimeMultipartType, PartBuilder};
-use lettre::{file::FileTransport, SendmailTransport, Transport as LettreTransport};
+use hagrid_mail::{context, Templates};
+use lettre::builder::Mailbox;
+use lettre::{file::FileTransport, SendableEmail, SendmailTransport, Transport as LettreTransport};
+use sequoia_openpgp::armor;
+use sequoia_openpgp::cert::amalgamation::key::ValidErasedKeyAmalgamation;
+use sequoia_openpgp::cert::ValidCert;
+use sequoia_openpgp::packet::key::PublicParts;
+use sequoia_openpgp::serialize::stream::{Encryptor, LiteralWriter, Message};
 use serde::Serialize;
-use uuid::Uuid;
 
-use gettext_macros::i18n;
 use rocket_i18n::I18n;
 
-use rfc2047::rfc2047_encode;
-
-use crate::template_helpers;
+use crate::dkim::DkimSigner;
+use crate::mail_queue::{MailQueue, QueuedMail};
+use crate::smtp::SmtpClient;
+use crate::web::get_i18n;
 
 use crate::database::types::Email;
 use crate::Result;
 
-mod context {
-    #[derive(Serialize, Clone)]
-    pub struct Verification {
-        pub lang: String,
-        pub primary_fp: String,
-        pub uri: String,
-        pub userid: String,
-        pub base_uri: String,
-        pub domain: String,
-    }
-
-    #[derive(Serialize, Clone)]
-    pub struct Manage {
-        pub lang: String,
-        pub primary_fp: String,
-        pub uri: String,
-        pub base_uri: String,
-        pub domain: String,
-    }
-
-    #[derive(Serialize, Clone)]
-    pub struct Welcome {
-        pub lang: String,
-        pub primary_fp: String,
-        pub uri: String,
-        pub base_uri: String,
-        pub domain: String,
-    }
-}
-
 pub struct Service {
     from: Mailbox,
     domain: String,
-    templates: Handlebars<'static>,
+    templates: Templates,
     transport: Transport,
+    queue: Option<Arc<MailQueue>>,
+    dkim: Option<DkimSigner>,
+    encrypt_verification: bool,
 }
 
+#[derive(Clone)]
 enum Transport {
     Sendmail,
     Filemail(PathBuf),
+    Smtp(SmtpClient),
+}
+
+impl Transport {
+    fn deliver(&self, mail: &QueuedMail) -> Result<()> {
+        let email = || {
+            SendableEmail::new(
+                mail.envelope.clone(),
+                mail.message_id.clone(),
+                mail.message.clone(),
+            )
+        };
+
+        match self {
+            Transport::Sendmail => {
+                let mut transport = SendmailTransport::new();
+                transport.send(email())?;
+            }
+            Transport::Filemail(ref path) => {
+                let mut transport = FileTransport::new(path);
+                transport.send(email())?;
+            }
+            Transport::Smtp(ref client) => {
+                let from = mail.envelope.from().map(|address| address.to_string());
+                let to: Vec<String> = mail
+                    .envelope
+                    .to()
+                    .iter()
+                    .map(|address| address.to_string())
+                    .collect();
+                client.send(from.as_deref(), &to, &mail.message)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Service {
@@ -75,20 +91,79 @@ impl Service {
         )
     }
 
+    /// Sends mail via the SMTP relay at the given host and port.
+    pub fn smtp(
+        from: &str,
+        base_uri: &str,
+        template_dir: &Path,
+        host: &str,
+        port: u16,
+    ) -> Result<Self> {
+        let helo = hagrid_mail::domain_of(base_uri)?;
+        Self::new(
+            from,
+            base_uri,
+            template_dir,
+            Transport::Smtp(SmtpClient::new(host, port, &helo)),
+        )
+    }
+
     fn new(from: &str, base_uri: &str, template_dir: &Path, transport: Transport) -> Result<Self> {
-        let templates = template_helpers::load_handlebars(template_dir)?;
-        let domain = url::Url::parse(base_uri)?
-            .host_str()
-            .ok_or_else(|| anyhow!("No host in base-URI"))?
-            .to_string();
+        let templates = Templates::load(template_dir, get_i18n())?;
+        let domain = hagrid_mail::domain_of(base_uri)?;
         Ok(Self {
             from: from.into(),
             domain,
             templates,
             transport,
+            queue: None,
+            dkim: None,
+            encrypt_verification: false,
         })
     }
 
+    /// Puts outgoing mail into the given queue, instead of sending it
+    /// right away.
+    ///
+    /// The mail is only delivered once `spawn_queue_worker` is called.
+    pub fn with_queue(mut self, queue: MailQueue) -> Self {
+        self.queue = Some(Arc::new(queue));
+        self
+    }
+
+    /// Signs outgoing mail with the given DKIM key.
+    pub fn with_dkim(mut self, dkim: DkimSigner) -> Self {
+        self.dkim = Some(dkim);
+        self
+    }
+
+    /// Requires verification mails to be encrypted, whether or not
+    /// the user asked for it.
+    pub fn with_encrypted_verification(mut self) -> Self {
+        self.encrypt_verification = true;
+        self
+    }
+
+    /// Returns whether verification mails must be encrypted.
+    pub fn encrypts_verification(&self) -> bool {
+        self.encrypt_verification
+    }
+
+    /// Starts a thread that delivers the mail in the queue.
+    pub fn spawn_queue_worker(&self) {
+        if let Some(ref queue) = self.queue {
+            let transport = self.transport.clone();
+            queue
+                .clone()
+                .spawn_worker(move |mail| transport.deliver(mail));
+        }
+    }
+
+    /// Sends the verification link for `userid`.
+    ///
+    /// If `encrypt_to` is given, the body of the mail is encrypted to
+    /// that cert, so that only the owner of the key can follow the
+    /// link.
     pub fn send_verification(
         &self,
         i18n: &I18n,
@@ -96,31 +171,20 @@ impl Service {
         tpk_name: String,
         userid: &Email,
         token: &str,
+        encrypt_to: Option<&ValidCert>,
     ) -> Result<()> {
-        let ctx = context::Verification {
-            lang: i18n.lang.to_string(),
-            primary_fp: tpk_name,
-            uri: format!("{}/verify/{}", base_uri, token),
-            userid: userid.to_string(),
-            base_uri: base_uri.to_owned(),
-            domain: self.domain.clone(),
-        };
-
         counters::inc_mail_sent("verify", userid);
-
-        self.send(
-            &[userid],
-            &i18n!(
-                i18n.catalog,
-                context = "Subject for verification email, {0} = userid, {1} = keyserver domain",
-                "Verify {0} for your key on {1}";
-                userid,
-                &self.domain,
-            ),
-            "verify",
+        let ctx = context::Verification::new(
             i18n.lang,
-            ctx,
-        )
+            tpk_name,
+            &userid.to_string(),
+            base_uri,
+            token,
+            &self.domain,
+        );
+
+        let mail = self.build(&[userid], "verify", i18n.lang, ctx, encrypt_to)?;
+        self.dispatch(mail)
     }
 
     pub fn send_manage_token(
@@ -131,85 +195,37 @@ impl Service {
         recipient: &Email,
         link_path: &str,
     ) -> Result<()> {
-        let ctx = context::Manage {
-            lang: i18n.lang.to_string(),
-            primary_fp: tpk_name,
-            uri: format!("{}{}", base_uri, link_path),
-            base_uri: base_uri.to_owned(),
-            domain: self.domain.clone(),
-        };
-
         counters::inc_mail_sent("manage", recipient);
+        let ctx = context::Manage::new(i18n.lang, tpk_name, base_uri, link_path, &self.domain);
 
-        self.send(
-            &[recipient],
-            &i18n!(
-                i18n.catalog,
-                context = "Subject for manage email, {} = keyserver domain",
-                "Manage your key on {}";
-                &self.domain
-            ),
-            "manage",
-            i18n.lang,
-            ctx,
-        )
+        let mail = self.build(&[recipient], "manage", i18n.lang, ctx, None)?;
+        self.dispatch(mail)
     }
 
     pub fn send_welcome(
         &self,
+        i18n: &I18n,
         base_uri: &str,
         tpk_name: String,
         userid: &Email,
         token: &str,
     ) -> Result<()> {
-        let ctx = context::Welcome {
-            lang: "en".to_owned(),
-            primary_fp: tpk_name,
-            uri: format!("{}/upload/{}", base_uri, token),
-            base_uri: base_uri.to_owned(),
-            domain: self.domain.clone(),
-        };
-
         counters::inc_mail_sent("welcome", userid);
+        let ctx = context::Welcome::new(i18n.lang, tpk_name, base_uri, token, &self.domain);
 
-        self.send(
-            &[userid],
-            &format!("Your key upload on {domain}", domain = self.domain),
-            "welcome",
-            "en",
-            ctx,
-        )
+        let mail = self.build(&[userid], "welcome", i18n.lang, ctx, None)?;
+        self.dispatch(mail)
     }
 
-    fn render_template(
-        &self,
-        template: &str,
-        locale: &str,
-        ctx: impl Serialize,
-    ) -> Result<(String, String)> {
-        let html = self
-            .templates
-            .render(&format!("{}/{}.htm", locale, template), &ctx)
-            .or_else(|_| self.templates.render(&format!("{}.htm", template), &ctx))
-            .map_err(|_| anyhow!("Email template failed to render"))?;
-        let txt = self
-            .templates
-            .render(&format!("{}/{}.txt", locale, template), &ctx)
-            .or_else(|_| self.templates.render(&format!("{}.txt", template), &ctx))
-            .map_err(|_| anyhow!("Email template failed to render"))?;
-
-        Ok((html, txt))
-    }
-
-    fn send(
+    fn build(
         &self,
         to: &[&Email],
-        subject: &str,
         template: &str,
         locale: &str,
         ctx: impl Serialize,
-    ) -> Result<()> {
-        let (html, txt) = self.render_template(template, locale, ctx)?;
+        encrypt_to: Option<&ValidCert>,
+    ) -> Result<QueuedMail> {
+        let (html, txt) = self.templates.render(template, locale, ctx)?;
 
         if cfg!(debug_assertions) {
             for recipient in to.iter() {
@@ -218,47 +234,69 @@ impl Service {
             println!("{}", &txt);
         }
 
-        // build this ourselves, as a temporary workaround for https://github.com/lettre/lettre/issues/400
-        let text = PartBuilder::new()
-            .body(txt)
-            .header(("Content-Type", "text/plain; charset=utf-8"))
-            .header(("Content-Transfer-Encoding", "8bit"))
-            .build();
-
-        let html = PartBuilder::new()
-            .body(html)
-            .header(("Content-Type", "text/html; charset=utf-8"))
-            .header(("Content-Transfer-Encoding", "8bit"))
-            .build();
-
-        let email = EmailBuilder::new()
-            .from(self.from.clone())
-            .subject(rfc2047_encode(subject))
-            .message_id(format!("<{}@{}>", Uuid::new_v4(), self.domain))
-            .message_type(MimeMultipartType::Alternative)
-            .header(("Content-Transfer-Encoding", "8bit"))
-            .child(text)
-            .child(html);
-
-        let email = to.iter().fold(email, |email, to| email.to(to.to_string()));
-
-        let email = email.build()?;
-
-        match self.transport {
-            Transport::Sendmail => {
-                let mut transport = SendmailTransport::new();
-                transport.send(email)?;
-            }
-            Transport::Filemail(ref path) => {
-                let mut transport = FileTransport::new(path);
-                transport.send(email)?;
-            }
+        // The subject of verification mail names the address it is
+        // sent to.
+        let userid = to.first().map(|to| to.to_string()).unwrap_or_default();
+        let subject = self
+            .templates
+            .subject(template, locale, &userid, &self.domain)?;
+        let to: Vec<String> = to.iter().map(|to| to.to_string()).collect();
+        let to: Vec<&str> = to.iter().map(String::as_str).collect();
+        let email = hagrid_mail::build(
+            self.from.clone(),
+            &to,
+            &subject,
+            &self.domain,
+            html,
+            txt,
+            encrypt_to.map(|vc| move |body: &[u8]| encrypt(vc, body)),
+        )?;
+
+        let envelope = email.envelope().clone();
+        let message_id = email.message_id().to_string();
+        let mut message = email.message_to_string()?.into_bytes();
+        if let Some(ref dkim) = self.dkim {
+            message = dkim.sign(&message)?;
         }
+        Ok(QueuedMail::new(envelope, message_id, message))
+    }
 
-        Ok(())
+    fn dispatch(&self, mail: QueuedMail) -> Result<()> {
+        match self.queue {
+            Some(ref queue) => queue.enqueue(mail),
+            None => self.transport.deliver(&mail),
+        }
     }
 }
 
+/// Returns whether mail can be encrypted to the given cert.
+pub fn can_encrypt_to(vc: &ValidCert) -> bool {
+    encryption_keys(vc).next().is_some()
+}
+
+fn encryption_keys<'a>(
+    vc: &'a ValidCert,
+) -> impl Iterator<Item = ValidErasedKeyAmalgamation<'a, PublicParts>> {
+    vc.keys()
+        .supported()
+        .alive()
+        .revoked(false)
+        .for_transport_encryption()
+        .for_storage_encryption()
+}
+
+/// Encrypts `data` to all valid encryption subkeys of `vc`, and
+/// returns it ASCII-armored.
+fn encrypt(vc: &ValidCert, data: &[u8]) -> Result<String> {
+    let mut sink = armor::Writer::new(Vec::new(), armor::Kind::Message)?;
+    let message = Message::new(&mut sink);
+    let message = Encryptor::for_recipients(message, encryption_keys(vc)).build()?;
+    let mut message = LiteralWriter::new(message).build()?;
+    message.write_all(data)?;
+    message.finalize()?;
+    Ok(String::from_utf8(sink.finalize()?)?)
+}
+
 // for some reason, this is no longer public in lettre itself
 // FIXME replace with builtin struct on lettre update
 // see https://github.com/lettre/lettre/blob/master/lettre/src/file/mod.rs#L41
@@ -292,10 +330,20 @@ pub fn pop_mail(dir: &Path) -> Result<Option<String>> {
 
 #[cfg(test)]
 mod test {
-    use crate::web::get_i18n;
-
     use super::*;
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::crypto::SessionKey;
+    use sequoia_openpgp::packet::{PKESK, SKESK};
+    use sequoia_openpgp::parse::stream::{
+        DecryptionHelper, DecryptorBuilder, MessageStructure, VerificationHelper,
+    };
+    use sequoia_openpgp::parse::Parse;
+    use sequoia_openpgp::policy::StandardPolicy;
+    use sequoia_openpgp::types::SymmetricAlgorithm;
+    use sequoia_openpgp::{Cert, Fingerprint, KeyHandle};
+    use std::io::Read;
     use std::str::FromStr;
+    use std::time::SystemTime;
     use tempfile::{tempdir, TempDir};
 
     const BASEDIR: &str = "http://localhost/";
@@ -376,6 +424,7 @@ mod test {
             "fingerprintoo".to_owned(),
             &recipient,
             "token",
+            None,
         )
         .unwrap();
         let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
@@ -401,6 +450,7 @@ mod test {
             "fingerprintoo".to_owned(),
             &recipient,
             "token",
+            None,
         )
         .unwrap();
         let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
@@ -417,6 +467,101 @@ mod test {
         ));
     }
 
+    /// Decrypts messages using the secret keys of a cert.
+    struct Decryptor<'a>(&'a Cert);
+
+    impl<'a> VerificationHelper for Decryptor<'a> {
+        fn get_certs(&mut self, _: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+            Ok(vec![])
+        }
+
+        fn check(&mut self, _: MessageStructure) -> sequoia_openpgp::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> DecryptionHelper for Decryptor<'a> {
+        fn decrypt<D>(
+            &mut self,
+            pkesks: &[PKESK],
+            _: &[SKESK],
+            sym_algo: Option<SymmetricAlgorithm>,
+            mut decrypt: D,
+        ) -> sequoia_openpgp::Result<Option<Fingerprint>>
+        where
+            D: FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
+        {
+            let policy = StandardPolicy::new();
+            for ka in self.0.keys().with_policy(&policy, None).secret() {
+                let mut keypair = ka.key().clone().into_keypair()?;
+                for pkesk in pkesks {
+                    if let Some((algo, sk)) = pkesk.decrypt(&mut keypair, sym_algo) {
+                        if decrypt(algo, &sk) {
+                            return Ok(Some(ka.key().fingerprint()));
+                        }
+                    }
+                }
+            }
+            Err(anyhow!("No key to decrypt message"))
+        }
+    }
+
+    #[test]
+    fn check_encrypted_verification_mail() {
+        let (mail, tempdir) = configure_mail();
+        let i18n = configure_i18n("en");
+        let recipient = Email::from_str(TO).unwrap();
+        let (tpk, _) = CertBuilder::general_purpose(None, Some(TO))
+            .generate()
+            .unwrap();
+        let policy = StandardPolicy::new();
+        let public = tpk.clone().strip_secret_key_material();
+        let vc = public.with_policy(&policy, None).unwrap();
+        assert!(can_encrypt_to(&vc));
+
+        mail.send_verification(
+            &i18n,
+            "test",
+            "fingerprintoo".to_owned(),
+            &recipient,
+            "token",
+            Some(&vc),
+        )
+        .unwrap();
+        let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
+
+        assert!(mail_content.contains("multipart/encrypted"));
+        assert!(mail_content.contains("Content-Type: application/pgp-encrypted"));
+        assert!(mail_content.contains("Subject: Verify recipient@example.org"));
+        assert!(!mail_content.contains("test/verify/token"));
+
+        let start = mail_content.find("-----BEGIN PGP MESSAGE-----").unwrap();
+        let end = mail_content.find("-----END PGP MESSAGE-----").unwrap();
+        let mut decryptor = DecryptorBuilder::from_bytes(&mail_content[start..end + 25])
+            .unwrap()
+            .with_policy(&policy, None, Decryptor(&tpk))
+            .unwrap();
+        let mut body = String::new();
+        decryptor.read_to_string(&mut body).unwrap();
+
+        assert!(body.contains("Content-Type: multipart/alternative"));
+        assert!(body.contains("Content-Type: text/plain; charset=utf-8"));
+        assert!(body.contains("Content-Type: text/html; charset=utf-8"));
+        assert!(body.contains("fingerprintoo"));
+        assert!(body.contains("test/verify/token"));
+    }
+
+    #[test]
+    fn check_no_encryption_key() {
+        let (tpk, _) = CertBuilder::new()
+            .add_userid(TO)
+            .add_signing_subkey()
+            .generate()
+            .unwrap();
+        let policy = StandardPolicy::new();
+        assert!(!can_encrypt_to(&tpk.with_policy(&policy, None).unwrap()));
+    }
+
     #[test]
     fn check_manage_mail_en() {
         let (mail, tempdir) = configure_mail();
@@ -474,10 +619,17 @@ mod test {
     #[test]
     fn check_welcome_mail() {
         let (mail, tempdir) = configure_mail();
+        let i18n = configure_i18n("en");
         let recipient = Email::from_str(TO).unwrap();
 
-        mail.send_welcome("test", "fingerprintoo".to_owned(), &recipient, "token")
-            .unwrap();
+        mail.send_welcome(
+            &i18n,
+            "test",
+            "fingerprintoo".to_owned(),
+            &recipient,
+            "token",
+        )
+        .unwrap();
         let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
 
         check_headers(&mail_content);
@@ -488,4 +640,82 @@ mod test {
         assert!(mail_content.contains("test/about"));
         assert!(mail_content.contains("first time"));
     }
+
+    #[test]
+    fn check_welcome_mail_ja() {
+        let (mail, tempdir) = configure_mail();
+        let i18n = configure_i18n("ja");
+        let recipient = Email::from_str(TO).unwrap();
+
+        mail.send_welcome(
+            &i18n,
+            "test",
+            "fingerprintoo".to_owned(),
+            &recipient,
+            "token",
+        )
+        .unwrap();
+        let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
+
+        check_headers(&mail_content);
+        assert!(mail_content.contains("lang=\"ja\""));
+        assert!(mail_content.contains("どうも、"));
+        assert!(mail_content.contains("fingerprintoo"));
+        assert!(mail_content.contains("test/upload/token"));
+    }
+
+    #[test]
+    fn check_dkim_signed_mail() {
+        let (mail, tempdir) = configure_mail();
+        let dkim =
+            DkimSigner::from_pem("localhost", "hagrid", crate::dkim::tests::PRIVATE_KEY).unwrap();
+        let mail = mail.with_dkim(dkim);
+        let i18n = configure_i18n("en");
+        let recipient = Email::from_str(TO).unwrap();
+
+        mail.send_welcome(
+            &i18n,
+            "test",
+            "fingerprintoo".to_owned(),
+            &recipient,
+            "token",
+        )
+        .unwrap();
+        let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
+
+        check_headers(&mail_content);
+        assert!(mail_content.starts_with("DKIM-Signature: v=1; a=rsa-sha256;"));
+        assert!(mail_content.contains("d=localhost; s=hagrid;"));
+    }
+
+    #[test]
+    fn check_queued_mail() {
+        let (mail, tempdir) = configure_mail();
+        let queue_dir = TempDir::new().unwrap();
+        let mail = mail.with_queue(MailQueue::new(queue_dir.path()).unwrap());
+        let i18n = configure_i18n("en");
+        let recipient = Email::from_str(TO).unwrap();
+
+        mail.send_welcome(
+            &i18n,
+            "test",
+            "fingerprintoo".to_owned(),
+            &recipient,
+            "token",
+        )
+        .unwrap();
+        assert!(pop_mail(tempdir.path()).unwrap().is_none());
+
+        let delivered = mail
+            .queue
+            .as_ref()
+            .unwrap()
+            .process_due(SystemTime::now(), |queued| mail.transport.deliver(queued))
+            .unwrap();
+        assert_eq!(delivered, 1);
+
+        let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
+        check_headers(&mail_content);
+        assert!(mail_content.contains("test/upload/token"));
+    }
 }
@@ -0,0 +1,349 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lettre::Envelope;
+use uuid::Uuid;
+
+use crate::counters;
+use crate::Result;
+
+/// Mail is given up on after this many failed delivery attempts.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+/// The delay before the first retry, which doubles with each attempt.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Retries are never delayed longer than this.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often the worker looks for mail that is due.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An error that retrying will not fix.
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PermanentError {}
+
+/// A mail waiting to be delivered.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueuedMail {
+    pub envelope: Envelope,
+    pub message_id: String,
+    pub message: Vec<u8>,
+    /// The number of failed delivery attempts.
+    attempts: u32,
+    /// When delivery is next attempted, in seconds since the epoch.
+    next_attempt: u64,
+    /// Why the last attempt failed.
+    last_error: Option<String>,
+}
+
+impl QueuedMail {
+    pub fn new(envelope: Envelope, message_id: String, message: Vec<u8>) -> Self {
+        QueuedMail {
+            envelope,
+            message_id,
+            message,
+            attempts: 0,
+            next_attempt: 0,
+            last_error: None,
+        }
+    }
+}
+
+/// Outgoing mail, kept on disk until it is delivered.
+///
+/// Each mail is a JSON file in the queue directory.  Mail that cannot
+/// be delivered is retried with exponential backoff, and eventually
+/// moved to the `dead` subdirectory for an operator to look at.  Only
+/// one worker may process a queue directory at a time.
+pub struct MailQueue {
+    queue_dir: PathBuf,
+    dead_dir: PathBuf,
+    tmp_dir: PathBuf,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+fn secs_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl MailQueue {
+    pub fn new(queue_dir: impl Into<PathBuf>) -> Result<Self> {
+        let queue_dir = queue_dir.into();
+        let dead_dir = queue_dir.join("dead");
+        let tmp_dir = queue_dir.join("tmp");
+        fs::create_dir_all(&dead_dir)?;
+        fs::create_dir_all(&tmp_dir)?;
+
+        Ok(MailQueue {
+            queue_dir,
+            dead_dir,
+            tmp_dir,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        })
+    }
+
+    /// Sets how often delivery is attempted, and the delay before the
+    /// first retry.
+    pub fn with_retries(mut self, max_attempts: u32, retry_delay: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Adds a mail to the queue, to be delivered as soon as possible.
+    pub fn enqueue(&self, mail: QueuedMail) -> Result<()> {
+        let path = self.queue_dir.join(format!("{}.json", Uuid::new_v4()));
+        self.write(&path, &mail)
+    }
+
+    /// Tries to deliver all mail that is due at `now`.
+    ///
+    /// Queue files that cannot be processed are moved to the `dead`
+    /// subdirectory, so that they do not hold up the rest of the
+    /// queue.  Returns the number of mails delivered.
+    pub fn process_due(
+        &self,
+        now: SystemTime,
+        deliver: impl Fn(&QueuedMail) -> Result<()>,
+    ) -> Result<usize> {
+        let now = secs_since_epoch(now);
+        let mut delivered = 0;
+
+        for entry in fs::read_dir(&self.queue_dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext != "json").unwrap_or(true) {
+                continue;
+            }
+
+            match self.process_file(&path, now, &deliver) {
+                Ok(true) => delivered += 1,
+                Ok(false) => (),
+                Err(e) => {
+                    eprintln!("Error processing queued mail {}: {:?}", path.display(), e);
+                    let dead = self.dead_dir.join(path.file_name().unwrap());
+                    if let Err(e) = fs::rename(&path, &dead) {
+                        eprintln!("Error moving {} aside: {:?}", path.display(), e);
+                    }
+                    counters::inc_mail_queue("dead");
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    /// Tries to deliver the mail stored at `path`, if it is due.
+    ///
+    /// Returns whether the mail was delivered.
+    fn process_file(
+        &self,
+        path: &Path,
+        now: u64,
+        deliver: impl Fn(&QueuedMail) -> Result<()>,
+    ) -> Result<bool> {
+        let mut mail: QueuedMail = serde_json::from_slice(&fs::read(path)?)?;
+        if mail.next_attempt > now {
+            return Ok(false);
+        }
+
+        let err = match deliver(&mail) {
+            Ok(()) => {
+                fs::remove_file(path)?;
+                counters::inc_mail_queue("sent");
+                return Ok(true);
+            }
+            Err(err) => err,
+        };
+
+        mail.attempts += 1;
+        mail.last_error = Some(err.to_string());
+        let permanent = err.downcast_ref::<PermanentError>().is_some();
+        if permanent || mail.attempts >= self.max_attempts {
+            eprintln!(
+                "Giving up on mail {} after {} attempts: {}",
+                mail.message_id, mail.attempts, err
+            );
+            self.write(&self.dead_dir.join(path.file_name().unwrap()), &mail)?;
+            fs::remove_file(path)?;
+            counters::inc_mail_queue("dead");
+        } else {
+            mail.next_attempt = now + self.backoff(mail.attempts).as_secs();
+            self.write(path, &mail)?;
+            counters::inc_mail_queue("retry");
+        }
+
+        Ok(false)
+    }
+
+    /// Starts a thread that delivers mail as it becomes due.
+    pub fn spawn_worker(
+        self: Arc<Self>,
+        deliver: impl Fn(&QueuedMail) -> Result<()> + Send + 'static,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            if let Err(e) = self.process_due(SystemTime::now(), &deliver) {
+                eprintln!("Error processing mail queue: {:?}", e);
+            }
+            thread::sleep(POLL_INTERVAL);
+        })
+    }
+
+    /// Returns the delay after the given number of failed attempts.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.retry_delay
+            .checked_mul(factor)
+            .unwrap_or(MAX_RETRY_DELAY)
+            .min(MAX_RETRY_DELAY)
+    }
+
+    /// Writes the mail to `path`, replacing it atomically.
+    fn write(&self, path: &Path, mail: &QueuedMail) -> Result<()> {
+        let tmp = tempfile::NamedTempFile::new_in(&self.tmp_dir)?;
+        serde_json::to_writer(&tmp, mail)?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::EmailAddress;
+    use std::cell::Cell;
+    use tempfile::TempDir;
+
+    fn mail() -> QueuedMail {
+        let envelope = Envelope::new(
+            Some(EmailAddress::new("noreply@localhost".to_owned()).unwrap()),
+            vec![EmailAddress::new("recipient@example.org".to_owned()).unwrap()],
+        )
+        .unwrap();
+        QueuedMail::new(envelope, "id@localhost".to_owned(), b"Hi,\r\n".to_vec())
+    }
+
+    fn count_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_file())
+            .count()
+    }
+
+    #[test]
+    fn deliver() {
+        let tmpdir = TempDir::new().unwrap();
+        let queue = MailQueue::new(tmpdir.path()).unwrap();
+        queue.enqueue(mail()).unwrap();
+        assert_eq!(count_files(tmpdir.path()), 1);
+
+        let delivered = queue
+            .process_due(SystemTime::now(), |mail| {
+                assert_eq!(mail.message, b"Hi,\r\n");
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(delivered, 1);
+        assert_eq!(count_files(tmpdir.path()), 0);
+    }
+
+    #[test]
+    fn retry_with_backoff() {
+        let tmpdir = TempDir::new().unwrap();
+        let queue = MailQueue::new(tmpdir.path())
+            .unwrap()
+            .with_retries(3, Duration::from_secs(60));
+        queue.enqueue(mail()).unwrap();
+
+        let calls = Cell::new(0);
+        let fail = |_: &QueuedMail| {
+            calls.set(calls.get() + 1);
+            Err(anyhow!("relay unavailable"))
+        };
+
+        let now = SystemTime::now();
+        assert_eq!(queue.process_due(now, fail).unwrap(), 0);
+        assert_eq!(calls.get(), 1);
+
+        // Not due again before the delay has passed.
+        queue
+            .process_due(now + Duration::from_secs(59), fail)
+            .unwrap();
+        assert_eq!(calls.get(), 1);
+
+        // The second retry waits twice as long.
+        let now = now + Duration::from_secs(60);
+        queue.process_due(now, fail).unwrap();
+        assert_eq!(calls.get(), 2);
+        queue
+            .process_due(now + Duration::from_secs(119), fail)
+            .unwrap();
+        assert_eq!(calls.get(), 2);
+        assert_eq!(count_files(&tmpdir.path().join("dead")), 0);
+
+        // The third failure is the last.
+        queue
+            .process_due(now + Duration::from_secs(120), fail)
+            .unwrap();
+        assert_eq!(calls.get(), 3);
+        assert_eq!(count_files(tmpdir.path()), 0);
+        assert_eq!(count_files(&tmpdir.path().join("dead")), 1);
+    }
+
+    #[test]
+    fn permanent_error() {
+        let tmpdir = TempDir::new().unwrap();
+        let queue = MailQueue::new(tmpdir.path()).unwrap();
+        queue.enqueue(mail()).unwrap();
+
+        queue
+            .process_due(SystemTime::now(), |_| {
+                Err(PermanentError("no such user".to_owned()).into())
+            })
+            .unwrap();
+        assert_eq!(count_files(tmpdir.path()), 0);
+
+        let dead = fs::read_dir(tmpdir.path().join("dead"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let mail: QueuedMail = serde_json::from_slice(&fs::read(dead.path()).unwrap()).unwrap();
+        assert_eq!(mail.attempts, 1);
+        assert_eq!(mail.last_error.as_deref(), Some("no such user"));
+    }
+
+    #[test]
+    fn corrupt_file() {
+        let tmpdir = TempDir::new().unwrap();
+        let queue = MailQueue::new(tmpdir.path()).unwrap();
+        fs::write(tmpdir.path().join("corrupt.json"), b"{").unwrap();
+        queue.enqueue(mail()).unwrap();
+
+        let delivered = queue.process_due(SystemTime::now(), |_| Ok(())).unwrap();
+        assert_eq!(delivered, 1);
+        assert_eq!(count_files(tmpdir.path()), 0);
+        assert!(tmpdir.path().join("dead").join("corrupt.json").exists());
+
+        // The queue keeps working.
+        queue.enqueue(mail()).unwrap();
+        let delivered = queue.process_due(SystemTime::now(), |_| Ok(())).unwrap();
+        assert_eq!(delivered, 1);
+    }
+}
@@ -25,13 +25,16 @@ init_i18n!("hagrid", en, de, fr, it, ja, nb, pl, tr, zh_Hans, ko, nl, ru, ar, sv
 
 mod anonymize_utils;
 mod counters;
+mod dkim;
 mod dump;
 mod gettext_strings;
-mod i18n;
 mod i18n_helpers;
+mod lint;
 mod mail;
+mod mail_queue;
 mod rate_limiter;
 mod sealed_state;
+mod smtp;
 mod template_helpers;
 mod tokens;
 mod web;
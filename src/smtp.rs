@@ -0,0 +1,262 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::mail_queue::PermanentError;
+use crate::Result;
+
+/// Connections and replies time out after this long.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Submits mail to an SMTP relay.
+///
+/// This is a minimal client meant for a relay on the same host or
+/// network, such as the local MTA.  It speaks plain SMTP, without TLS
+/// or authentication.
+#[derive(Clone, Debug)]
+pub struct SmtpClient {
+    host: String,
+    port: u16,
+    helo: String,
+}
+
+impl SmtpClient {
+    /// Creates a client for the relay at `host` and `port`, which
+    /// greets the relay as `helo`.
+    pub fn new(host: &str, port: u16, helo: &str) -> Self {
+        SmtpClient {
+            host: host.to_owned(),
+            port,
+            helo: helo.to_owned(),
+        }
+    }
+
+    /// Sends `message` from `from` to each of `to`.
+    ///
+    /// If the relay rejects the mail for good, the error is a
+    /// `PermanentError`.
+    pub fn send(&self, from: Option<&str>, to: &[String], message: &[u8]) -> Result<()> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Cannot resolve SMTP relay {}", self.host))?;
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        conn.expect_reply("greeting", &[220])?;
+        let extensions = match conn.command(&format!("EHLO {}", self.helo), &[250]) {
+            Ok(extensions) => extensions,
+            Err(_) => conn.command(&format!("HELO {}", self.helo), &[250])?,
+        };
+        let eight_bit = extensions
+            .iter()
+            .any(|line| line.eq_ignore_ascii_case("8BITMIME"));
+
+        conn.command(
+            &format!(
+                "MAIL FROM:<{}>{}",
+                from.unwrap_or(""),
+                if eight_bit { " BODY=8BITMIME" } else { "" }
+            ),
+            &[250],
+        )?;
+        for recipient in to {
+            conn.command(&format!("RCPT TO:<{}>", recipient), &[250, 251])?;
+        }
+        conn.command("DATA", &[354])?;
+        conn.writer.write_all(&encode_data(message))?;
+        conn.expect_reply("end of data", &[250])?;
+
+        // The mail is accepted, so a failure to say goodbye does not
+        // matter.
+        let _ = conn.command("QUIT", &[221]);
+
+        Ok(())
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    /// Sends a command, and returns the lines of the reply.
+    fn command(&mut self, command: &str, expected: &[u16]) -> Result<Vec<String>> {
+        self.writer.write_all(command.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        let verb = command.split(':').next().unwrap_or(command);
+        self.expect_reply(verb, expected)
+    }
+
+    /// Reads a reply, failing unless its code is one of `expected`.
+    fn expect_reply(&mut self, context: &str, expected: &[u16]) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(anyhow!("SMTP relay closed the connection"));
+            }
+            let line = line.trim_end();
+            if line.len() < 3 || !line.is_char_boundary(3) {
+                return Err(anyhow!("Malformed SMTP reply: {}", line));
+            }
+
+            let code: u16 = line[..3]
+                .parse()
+                .map_err(|_| anyhow!("Malformed SMTP reply: {}", line))?;
+            let last = !line[3..].starts_with('-');
+            lines.push(line.get(4..).unwrap_or("").to_owned());
+
+            if last {
+                if expected.contains(&code) {
+                    return Ok(lines);
+                }
+                let error = format!("SMTP relay rejected {}: {}", context, line);
+                return Err(if code >= 500 {
+                    PermanentError(error).into()
+                } else {
+                    anyhow!(error)
+                });
+            }
+        }
+    }
+}
+
+/// Prepares a message for the DATA command: lines end in CRLF, lines
+/// starting with a dot get another one, and a lone dot ends it.
+fn encode_data(message: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(message.len() + 5);
+    for line in message.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b".") {
+            data.push(b'.');
+        }
+        data.extend_from_slice(line);
+        data.extend_from_slice(b"\r\n");
+    }
+    if message.ends_with(b"\n") {
+        // The split yields an empty line after the final newline.
+        data.truncate(data.len() - 2);
+    }
+    data.extend_from_slice(b".\r\n");
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Accepts a single connection, and records what the client
+    /// sends.  Recipients containing "reject" are refused.
+    fn smtp_sink() -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut transcript = Vec::new();
+            let mut in_data = false;
+
+            writer.write_all(b"220 sink ready\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end_matches("\r\n").to_owned();
+                transcript.push(line.clone());
+
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-sink\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("RCPT") && line.contains("reject") {
+                    b"550 no such user\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            transcript
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn send() {
+        let (port, sink) = smtp_sink();
+        let client = SmtpClient::new("127.0.0.1", port, "localhost");
+        client
+            .send(
+                Some("noreply@localhost"),
+                &["recipient@example.org".to_owned()],
+                b"Subject: test\n\nHi,\n.hidden\n",
+            )
+            .unwrap();
+
+        assert_eq!(
+            sink.join().unwrap(),
+            vec![
+                "EHLO localhost",
+                "MAIL FROM:<noreply@localhost> BODY=8BITMIME",
+                "RCPT TO:<recipient@example.org>",
+                "DATA",
+                "Subject: test",
+                "",
+                "Hi,",
+                "..hidden",
+                ".",
+                "QUIT",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejected_recipient() {
+        let (port, sink) = smtp_sink();
+        let client = SmtpClient::new("127.0.0.1", port, "localhost");
+        let err = client
+            .send(
+                Some("noreply@localhost"),
+                &["reject@example.org".to_owned()],
+                b"Subject: test\r\n\r\nHi,\r\n",
+            )
+            .unwrap_err();
+        assert!(err.downcast_ref::<PermanentError>().is_some());
+        sink.join().unwrap();
+    }
+
+    #[test]
+    fn unreachable() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = SmtpClient::new("127.0.0.1", port, "localhost");
+        let err = client
+            .send(None, &["recipient@example.org".to_owned()], b"Hi,\r\n")
+            .unwrap_err();
+        assert!(err.downcast_ref::<PermanentError>().is_none());
+    }
+}
@@ -1,10 +1,6 @@
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 
-use handlebars::Handlebars;
-
-use crate::i18n::I18NHelper;
-use crate::web::get_i18n;
 use crate::Result;
 
 #[derive(Debug)]
@@ -51,25 +47,6 @@ fn load_localized_template_names(
         .collect()
 }
 
-pub fn load_handlebars(template_dir: &Path) -> Result<Handlebars<'static>> {
-    let mut handlebars = Handlebars::new();
-
-    let i18ns = get_i18n();
-    let i18n_helper = I18NHelper::new(i18ns);
-    handlebars.register_helper("text", Box::new(i18n_helper));
-
-    let mut glob_path = template_dir.join("**").join("*");
-    glob_path.set_extension("hbs");
-    let glob_path = glob_path.to_str().expect("valid glob path string");
-
-    for path in glob::glob(glob_path).unwrap().flatten() {
-        let template_name = remove_extension(path.strip_prefix(template_dir)?);
-        handlebars.register_template_file(&template_name.to_string_lossy(), &path)?;
-    }
-
-    Ok(handlebars)
-}
-
 fn remove_extension<P: AsRef<Path>>(path: P) -> PathBuf {
     let path = path.as_ref();
     let stem = match path.file_stem() {
@@ -1,29 +1,74 @@
-use std::io;
+use std::io::{self, Cursor, Read};
 
+use multipart::server::save::Entries;
+use multipart::server::save::SaveResult::*;
+use multipart::server::Multipart;
+use rocket::http::ContentType;
+use rocket::Data;
 use rocket_i18n::I18n;
+use serde_json::json;
 
 use crate::dump::{self, Kind};
 use crate::i18n_helpers::describe_query_error;
-use crate::web::MyResponse;
+use crate::web::vks;
+use crate::web::vks::response::CheckResponse;
+use crate::web::vks_web::UPLOAD_LIMIT;
+use crate::web::{MyResponse, RequestOrigin};
+use crate::Result;
 
 use crate::database::{Database, KeyDatabase, Query};
 
-#[get("/debug?<q>")]
-pub fn debug_info(db: &rocket::State<KeyDatabase>, i18n: I18n, q: String) -> MyResponse {
+/// Looks up the published cert for the search term `q`.
+fn lookup_armored(
+    db: &rocket::State<KeyDatabase>,
+    i18n: &I18n,
+    q: &str,
+) -> Result<String, MyResponse> {
     let query = match q.parse::<Query>() {
         Ok(query) => query,
-        Err(_) => return MyResponse::bad_request_plain("bad request"),
+        Err(_) => return Err(MyResponse::bad_request_plain("bad request")),
     };
     let fp = match db.lookup_primary_fingerprint(&query) {
         Some(fp) => fp,
-        None => return MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+        None => {
+            return Err(MyResponse::not_found_plain(describe_query_error(
+                i18n, &query,
+            )))
+        }
     };
 
-    let armored_key = match db.by_fpr(&fp) {
-        Some(armored_key) => armored_key,
-        None => return MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+    match db.by_fpr(&fp) {
+        Some(armored_key) => Ok(armored_key),
+        None => Err(MyResponse::not_found_plain(describe_query_error(
+            i18n, &query,
+        ))),
+    }
+}
+
+fn debug_json(armored_key: &str) -> MyResponse {
+    match dump::dump_json(&mut io::Cursor::new(armored_key.as_bytes()), false, false) {
+        Ok((Kind::Cert, packets)) => MyResponse::json(json!({ "packets": packets })),
+        Ok(_) => MyResponse::ise(anyhow!("Internal parsing error!")),
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+#[get("/debug?<q>&<format>")]
+pub fn debug_info(
+    db: &rocket::State<KeyDatabase>,
+    i18n: I18n,
+    q: String,
+    format: Option<String>,
+) -> MyResponse {
+    let armored_key = match lookup_armored(db, &i18n, &q) {
+        Ok(armored_key) => armored_key,
+        Err(response) => return response,
     };
 
+    if format.as_deref() == Some("json") {
+        return debug_json(&armored_key);
+    }
+
     let mut result = Vec::new();
     let dump_result = dump::dump(
         &mut io::Cursor::new(armored_key.as_bytes()),
@@ -42,3 +87,134 @@ pub fn debug_info(db: &rocket::State<KeyDatabase>, i18n: I18n, q: String) -> MyR
         Err(e) => MyResponse::ise(e),
     }
 }
+
+#[get("/vks/v1/debug?<q>")]
+pub fn vks_v1_debug(db: &rocket::State<KeyDatabase>, i18n: I18n, q: String) -> MyResponse {
+    match lookup_armored(db, &i18n, &q) {
+        Ok(armored_key) => debug_json(&armored_key),
+        Err(response) => response,
+    }
+}
+
+mod template {
+    use crate::web::vks::response::LintReport;
+
+    #[derive(Serialize)]
+    pub struct Inspect {
+        pub error: Option<String>,
+        pub key_fprs: Vec<String>,
+        pub is_revoked: bool,
+        pub addresses: Vec<String>,
+        pub count_unparsed: usize,
+        pub lints: Vec<LintReport>,
+        pub dump: String,
+        pub hex: bool,
+        pub mpis: bool,
+    }
+}
+
+/// The fields of the inspection form.
+struct InspectRequest {
+    keytext: Vec<u8>,
+    hex: bool,
+    mpis: bool,
+}
+
+#[get("/debug/inspect")]
+pub fn inspect(origin: RequestOrigin, i18n: I18n) -> MyResponse {
+    MyResponse::ok_bare("debug/inspect", i18n, origin)
+}
+
+/// Shows what we make of arbitrary OpenPGP data, without storing it.
+#[post("/debug/inspect", format = "multipart/form-data", data = "<data>")]
+pub async fn inspect_post(
+    db: &rocket::State<KeyDatabase>,
+    origin: RequestOrigin,
+    i18n: I18n,
+    cont_type: &ContentType,
+    data: Data<'_>,
+) -> MyResponse {
+    let request = match read_inspect_request(data, cont_type).await {
+        Ok(request) => request,
+        Err(err) => return MyResponse::bad_request("debug/inspect", err, i18n, origin),
+    };
+
+    let mut dump = Vec::new();
+    if let Err(e) = dump::dump(
+        &mut Cursor::new(&request.keytext),
+        &mut dump,
+        request.mpis,
+        request.hex,
+        None,
+        32 * 4 + 80,
+    ) {
+        dump.extend_from_slice(format!("\nError: {}\n", e).as_bytes());
+    }
+
+    let mut context = template::Inspect {
+        error: None,
+        key_fprs: vec![],
+        is_revoked: false,
+        addresses: vec![],
+        count_unparsed: 0,
+        lints: vec![],
+        dump: String::from_utf8_lossy(&dump).into_owned(),
+        hex: request.hex,
+        mpis: request.mpis,
+    };
+    match vks::check_key(db, &i18n, Cursor::new(&request.keytext)) {
+        CheckResponse::Ok {
+            key_fpr,
+            is_revoked,
+            addresses,
+            count_unparsed,
+            lints,
+        } => {
+            context.key_fprs = vec![key_fpr];
+            context.is_revoked = is_revoked;
+            context.addresses = addresses;
+            context.count_unparsed = count_unparsed;
+            context.lints = lints;
+        }
+        CheckResponse::OkMulti { key_fprs } => context.key_fprs = key_fprs,
+        CheckResponse::Error(error) => context.error = Some(error),
+    }
+
+    MyResponse::ok("debug/inspect", context, i18n, origin)
+}
+
+async fn read_inspect_request(data: Data<'_>, cont_type: &ContentType) -> Result<InspectRequest> {
+    let (_, boundary) = cont_type
+        .params()
+        .find(|&(k, _)| k == "boundary")
+        .ok_or_else(|| {
+            anyhow!(
+                "`Content-Type: multipart/form-data` \
+                                      boundary param not provided"
+            )
+        })?;
+
+    let data = Cursor::new(data.open(UPLOAD_LIMIT).into_bytes().await?.value);
+    match Multipart::with_body(data, boundary).save().temp() {
+        Full(entries) => inspect_request_from_entries(entries),
+        Partial(partial, _) => inspect_request_from_entries(partial.entries),
+        Error(err) => Err(err.into()),
+    }
+}
+
+fn inspect_request_from_entries(entries: Entries) -> Result<InspectRequest> {
+    let mut keytext = Vec::new();
+    match entries.fields.get("keytext") {
+        Some(ent) if ent.len() == 1 => {
+            ent[0].data.readable()?.read_to_end(&mut keytext)?;
+        }
+        Some(_) => return Err(anyhow!("Multiple keytexts found")),
+        None => return Err(anyhow!("No keytext found")),
+    }
+
+    Ok(InspectRequest {
+        keytext,
+        hex: entries.fields.contains_key("hex"),
+        mpis: entries.fields.contains_key("mpis"),
+    })
+}
@@ -4,9 +4,16 @@ use std::collections::HashMap;
 use std::str::FromStr;
 use std::time::SystemTime;
 
+use gettext_macros::i18n;
 use rocket::http::ContentType;
 use rocket::Data;
 use rocket_i18n::I18n;
+use sequoia_openpgp::cert::amalgamation::key::ErasedKeyAmalgamation;
+use sequoia_openpgp::cert::amalgamation::{ValidAmalgamation, ValidateAmalgamation};
+use sequoia_openpgp::packet::key::PublicParts;
+use sequoia_openpgp::policy::Policy;
+use sequoia_openpgp::types::{PublicKeyAlgorithm, RevocationStatus};
+use sequoia_openpgp::Cert;
 use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
 
 use crate::database::types::{Email, Fingerprint, KeyID};
@@ -112,6 +119,7 @@ pub async fn pks_add_form(
                 &origin,
                 mail_service,
                 rate_limiter,
+                &i18n,
                 token,
                 status,
                 is_new_key,
@@ -120,10 +128,7 @@ pub async fn pks_add_form(
             );
             MyResponse::plain(msg)
         }
-        Ok(_) => {
-            let msg = format!("Upload successful. Please note that identity information will only be published after verification. See {baseuri}/about/usage#gnupg-upload", baseuri = origin.get_base_uri());
-            MyResponse::plain(msg)
-        }
+        Ok(_) => MyResponse::plain(upload_pending_msg(&origin, &i18n)),
         Err(err) => MyResponse::ise(err),
     }
 }
@@ -132,6 +137,7 @@ fn pks_add_ok(
     origin: &RequestOrigin,
     mail_service: &mail::Service,
     rate_limiter: &RateLimiter,
+    i18n: &I18n,
     token: String,
     status: HashMap<String, EmailStatus>,
     is_new_key: bool,
@@ -139,45 +145,59 @@ fn pks_add_ok(
     primary_uid: Option<Email>,
 ) -> String {
     if primary_uid.is_none() {
-        return format!("Upload successful. Please note that identity information will only be published after verification. See {baseuri}/about/usage#gnupg-upload", baseuri = origin.get_base_uri());
+        return upload_pending_msg(origin, i18n);
     }
     let primary_uid = primary_uid.unwrap();
 
     if is_new_key {
-        if send_welcome_mail(origin, mail_service, key_fpr, &primary_uid, token) {
+        if send_welcome_mail(origin, mail_service, i18n, key_fpr, &primary_uid, token) {
             rate_limiter.action_perform(format!("hkp-sent-{}", &primary_uid));
-            return "Upload successful. This is a new key, a welcome email has been sent."
-                .to_string();
+            return i18n!(
+                i18n.catalog,
+                "Upload successful. This is a new key, a welcome email has been sent."
+            );
         }
-        return format!("Upload successful. Please note that identity information will only be published after verification. See {baseuri}/about/usage#gnupg-upload", baseuri = origin.get_base_uri());
+        return upload_pending_msg(origin, i18n);
     }
 
     let has_unverified = status.iter().any(|(_, v)| *v == EmailStatus::Unpublished);
     if !has_unverified {
-        return "Upload successful.".to_string();
+        return i18n!(i18n.catalog, "Upload successful.");
     }
 
-    return format!("Upload successful. Please note that identity information will only be published after verification. See {baseuri}/about/usage#gnupg-upload", baseuri = origin.get_base_uri());
+    upload_pending_msg(origin, i18n)
+}
+
+fn upload_pending_msg(origin: &RequestOrigin, i18n: &I18n) -> String {
+    i18n!(
+        i18n.catalog,
+        "Upload successful. Please note that identity information will only be published after verification. See {}/about/usage#gnupg-upload";
+        origin.get_base_uri()
+    )
 }
 
 fn send_welcome_mail(
     origin: &RequestOrigin,
     mail_service: &mail::Service,
+    i18n: &I18n,
     fpr: String,
     primary_uid: &Email,
     token: String,
 ) -> bool {
     mail_service
-        .send_welcome(origin.get_base_uri(), fpr, primary_uid, &token)
+        .send_welcome(i18n, origin.get_base_uri(), fpr, primary_uid, &token)
         .is_ok()
 }
 
-#[get("/pks/lookup?<op>&<search>")]
+#[get("/pks/lookup?<op>&<search>&<options>&<fingerprint>&<exact>")]
 pub fn pks_lookup(
     db: &rocket::State<KeyDatabase>,
     i18n: I18n,
     op: Option<String>,
     search: Option<String>,
+    options: Option<String>,
+    fingerprint: Option<String>,
+    exact: Option<String>,
 ) -> MyResponse {
     let search = search.unwrap_or_default();
     let key = match Hkp::from_str(&search) {
@@ -196,10 +216,18 @@ pub fn pks_lookup(
     };
 
     if let Some(op) = op {
+        let index_options = |verbose| {
+            IndexOptions::new(
+                verbose,
+                options.as_deref(),
+                fingerprint.as_deref(),
+                exact.as_deref(),
+            )
+        };
         match op.as_str() {
-            "index" => key_to_hkp_index(db, i18n, query),
+            "index" => key_to_hkp_index(db, i18n, query, &search, index_options(false)),
             "get" => web::key_to_response_plain(db, i18n, query),
-            "vindex" => MyResponse::not_implemented_plain("vindex not implemented"),
+            "vindex" => key_to_hkp_index(db, i18n, query, &search, index_options(true)),
             s if s.starts_with("x-") => {
                 MyResponse::not_implemented_plain("x-* operations not implemented")
             }
@@ -210,22 +238,199 @@ pub fn pks_lookup(
     }
 }
 
-#[get("/pks/internal/index/<query_string>")]
+#[get("/pks/internal/index/<query_string>?<options>&<fingerprint>&<exact>")]
 pub fn pks_internal_index(
     db: &rocket::State<KeyDatabase>,
     i18n: I18n,
     query_string: String,
+    options: Option<String>,
+    fingerprint: Option<String>,
+    exact: Option<String>,
 ) -> MyResponse {
+    let options = IndexOptions::new(
+        false,
+        options.as_deref(),
+        fingerprint.as_deref(),
+        exact.as_deref(),
+    );
     match query_string.parse() {
-        Ok(query) => key_to_hkp_index(db, i18n, query),
+        Ok(query) => key_to_hkp_index(db, i18n, query, &query_string, options),
         Err(_) => MyResponse::bad_request_plain("Invalid search query!"),
     }
 }
 
-fn key_to_hkp_index(db: &rocket::State<KeyDatabase>, i18n: I18n, query: Query) -> MyResponse {
-    use sequoia_openpgp::policy::StandardPolicy;
-    use sequoia_openpgp::types::RevocationStatus;
+#[get("/pks/internal/vindex/<query_string>?<options>&<fingerprint>&<exact>")]
+pub fn pks_internal_vindex(
+    db: &rocket::State<KeyDatabase>,
+    i18n: I18n,
+    query_string: String,
+    options: Option<String>,
+    fingerprint: Option<String>,
+    exact: Option<String>,
+) -> MyResponse {
+    let options = IndexOptions::new(
+        true,
+        options.as_deref(),
+        fingerprint.as_deref(),
+        exact.as_deref(),
+    );
+    match query_string.parse() {
+        Ok(query) => key_to_hkp_index(db, i18n, query, &query_string, options),
+        Err(_) => MyResponse::bad_request_plain("Invalid search query!"),
+    }
+}
+
+/// Options of an HKP index request.
+///
+/// See draft-shaw-openpgp-hkp-00, section 3.2.
+#[derive(Debug, Clone, Copy)]
+struct IndexOptions {
+    /// Machine readable output.  This is the default, as many clients
+    /// do not send `options=mr`; only a request with `options` that do
+    /// not include `mr` gets the human readable listing.
+    machine_readable: bool,
+    /// `op=vindex`: also list the self-signatures of user IDs.
+    verbose: bool,
+    /// `fingerprint=on`: show fingerprints in human readable output.
+    fingerprint: bool,
+    /// `exact=on`: the search term must match a user ID exactly.
+    exact: bool,
+}
+
+impl IndexOptions {
+    fn new(
+        verbose: bool,
+        options: Option<&str>,
+        fingerprint: Option<&str>,
+        exact: Option<&str>,
+    ) -> Self {
+        IndexOptions {
+            machine_readable: options
+                .map(|options| options.split(',').any(|option| option == "mr"))
+                .unwrap_or(true),
+            verbose,
+            fingerprint: fingerprint == Some("on"),
+            exact: exact == Some("on"),
+        }
+    }
+}
+
+/// A primary key or subkey, as listed in an HKP index.
+struct IndexKey {
+    fingerprint: sequoia_openpgp::Fingerprint,
+    algo: PublicKeyAlgorithm,
+    bits: usize,
+    creation_time: SystemTime,
+    expiration_time: Option<SystemTime>,
+    revoked: bool,
+}
+
+impl IndexKey {
+    fn new(ka: ErasedKeyAmalgamation<PublicParts>, policy: &dyn Policy) -> Self {
+        let key = ka.key();
+        let (expiration_time, revoked) = match ka.clone().with_policy(policy, None) {
+            Ok(vka) => (
+                vka.key_expiration_time(),
+                vka.revocation_status() != RevocationStatus::NotAsFarAsWeKnow,
+            ),
+            Err(_) => (None, false),
+        };
+
+        IndexKey {
+            fingerprint: key.fingerprint(),
+            algo: key.pk_algo(),
+            bits: key.mpis().bits().unwrap_or(0),
+            creation_time: key.creation_time(),
+            expiration_time,
+            revoked,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expiration_time
+            .map(|time| time <= SystemTime::now())
+            .unwrap_or(false)
+    }
+
+    /// Returns the `pub` or `sub` line of a machine readable index.
+    fn to_mr_line(&self, record: &str) -> String {
+        let algo: u8 = self.algo.into();
+        format!(
+            "{}:{}:{}:{}:{}:{}:{}{}\r\n",
+            record,
+            self.fingerprint.to_hex(),
+            algo,
+            self.bits,
+            unix_time(self.creation_time),
+            self.expiration_time.map(unix_time).unwrap_or_default(),
+            if self.revoked { "r" } else { "" },
+            if self.is_expired() { "e" } else { "" },
+        )
+    }
+
+    /// Returns the `pub` or `sub` lines of a human readable index.
+    fn to_hr_lines(&self, record: &str, options: IndexOptions) -> String {
+        let mut out = format!(
+            "{:<5}{} {} ({} bits), created {}",
+            record,
+            sequoia_openpgp::KeyID::from(&self.fingerprint).to_hex(),
+            self.algo,
+            self.bits,
+            date(self.creation_time)
+        );
+        if let Some(expiration_time) = self.expiration_time {
+            let verb = if self.is_expired() {
+                "expired"
+            } else {
+                "expires"
+            };
+            out.push_str(&format!(", {} {}", verb, date(expiration_time)));
+        }
+        if self.revoked {
+            out.push_str(", revoked");
+        }
+        out.push_str("\r\n");
+        if options.fingerprint {
+            out.push_str(&format!("     Key fingerprint = {}\r\n", self.fingerprint));
+        }
+        out
+    }
+}
+
+fn unix_time(time: SystemTime) -> String {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs().to_string())
+        .unwrap_or_default()
+}
+
+fn date(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Returns whether `search` is one of the user IDs of `tpk`, or the
+/// address of one of them.
+fn matches_exactly(tpk: &Cert, search: &str) -> bool {
+    tpk.userids().any(|uid| {
+        let uid = uid.userid();
+        uid.value() == search.as_bytes()
+            || uid
+                .email()
+                .ok()
+                .flatten()
+                .map(|address| address.eq_ignore_ascii_case(search))
+                .unwrap_or(false)
+    })
+}
 
+fn key_to_hkp_index(
+    db: &rocket::State<KeyDatabase>,
+    i18n: I18n,
+    query: Query,
+    search: &str,
+    options: IndexOptions,
+) -> MyResponse {
     let tpk = match db.lookup(&query) {
         Ok(Some(tpk)) => tpk,
         Ok(None) => return MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
@@ -233,62 +438,85 @@ fn key_to_hkp_index(db: &rocket::State<KeyDatabase>, i18n: I18n, query: Query) -
             return MyResponse::ise(err);
         }
     };
-    let mut out = String::default();
-    let p = tpk.primary_key();
 
-    let policy = &StandardPolicy::new();
+    // Lookups by fingerprint and key ID are always exact.
+    if options.exact && matches!(query, Query::ByEmail(_)) && !matches_exactly(&tpk, search) {
+        return MyResponse::not_found_plain(describe_query_error(&i18n, &query));
+    }
 
-    let ctime = format!(
-        "{}",
-        p.creation_time()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    );
-    let is_rev = if tpk.revocation_status(policy, None) != RevocationStatus::NotAsFarAsWeKnow {
-        "r"
+    let policy = db.policy();
+    let primary = IndexKey::new(tpk.primary_key().into(), policy);
+    let subkeys: Vec<IndexKey> = tpk
+        .keys()
+        .subkeys()
+        .map(|ka| IndexKey::new(ka.into(), policy))
+        .collect();
+
+    let mut out = String::default();
+    if options.machine_readable {
+        out.push_str("info:1:1\r\n");
+        out.push_str(&primary.to_mr_line("pub"));
     } else {
-        ""
-    };
-    let algo: u8 = p.pk_algo().into();
-
-    out.push_str("info:1:1\r\n");
-    out.push_str(&format!(
-        "pub:{}:{}:{}:{}:{}:{}{}\r\n",
-        p.fingerprint().to_string().replace(" ", ""),
-        algo,
-        p.mpis().bits().unwrap_or(0),
-        ctime,
-        "",
-        "",
-        is_rev
-    ));
+        out.push_str(&primary.to_hr_lines("pub", options));
+    }
 
     for uid in tpk.userids() {
         let uidstr = uid.userid().to_string();
-        let u = utf8_percent_encode(&uidstr, DEFAULT_ENCODE_SET).to_string();
-        let ctime = uid
-            .binding_signature(policy, None)
-            .ok()
-            .and_then(|x| x.signature_creation_time())
-            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
-            .map(|x| format!("{}", x.as_secs()))
-            .unwrap_or_default();
-        let is_rev = if uid.revocation_status(policy, None) != RevocationStatus::NotAsFarAsWeKnow {
-            "r"
+        let is_rev = uid.revocation_status(policy, None) != RevocationStatus::NotAsFarAsWeKnow;
+
+        if options.machine_readable {
+            let u = utf8_percent_encode(&uidstr, DEFAULT_ENCODE_SET).to_string();
+            let ctime = uid
+                .binding_signature(policy, None)
+                .ok()
+                .and_then(|x| x.signature_creation_time())
+                .map(unix_time)
+                .unwrap_or_default();
+            let is_rev = if is_rev { "r" } else { "" };
+
+            out.push_str(&format!("uid:{}:{}:{}:{}{}\r\n", u, ctime, "", "", is_rev));
         } else {
-            ""
-        };
+            out.push_str(&format!(
+                "uid  {}{}\r\n",
+                uidstr,
+                if is_rev { " (revoked)" } else { "" }
+            ));
+            if options.verbose {
+                for sig in uid.self_signatures() {
+                    out.push_str(&format!(
+                        "sig  {} {} {}\r\n",
+                        sequoia_openpgp::KeyID::from(&primary.fingerprint).to_hex(),
+                        sig.signature_creation_time().map(date).unwrap_or_default(),
+                        sig.typ()
+                    ));
+                }
+            }
+        }
+    }
 
-        out.push_str(&format!("uid:{}:{}:{}:{}{}\r\n", u, ctime, "", "", is_rev));
+    for subkey in subkeys {
+        if options.machine_readable {
+            out.push_str(&subkey.to_mr_line("sub"));
+        } else {
+            out.push_str(&subkey.to_hr_lines("sub", options));
+        }
     }
 
-    MyResponse::plain(out)
+    if options.machine_readable {
+        MyResponse::plain(out)
+    } else {
+        MyResponse::html(format!(
+            "<!DOCTYPE html>\n<html><head><title>Search results</title></head>\n\
+             <body><pre>\n{}</pre></body></html>\n",
+            handlebars::html_escape(&out)
+        ))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use rocket::http::ContentType;
+    use rocket::http::Header;
     use rocket::http::Status;
 
     use sequoia_openpgp::serialize::Serialize;
@@ -372,6 +600,38 @@ mod tests {
         assert_consistency(client.rocket());
     }
 
+    #[test]
+    fn hkp_localized() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut armored = Vec::new();
+        {
+            use sequoia_openpgp::armor::{Kind, Writer};
+            let mut w = Writer::new(&mut armored, Kind::PublicKey).unwrap();
+            tpk.serialize(&mut w).unwrap();
+            w.finalize().unwrap();
+        }
+        let mut post_data = String::from("keytext=");
+        for enc in url::form_urlencoded::byte_serialize(&armored) {
+            post_data.push_str(enc);
+        }
+
+        let response = client
+            .post("/pks/add")
+            .body(post_data.as_bytes())
+            .header(ContentType::Form)
+            .header(Header::new("Accept-Language", "ja"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // The welcome mail is in the requested language.
+        let welcome_mail = pop_mail(filemail_into.as_path()).unwrap().unwrap();
+        assert!(welcome_mail.contains("lang=\"ja\""));
+        assert!(welcome_mail.contains("どうも、"));
+    }
+
     #[test]
     fn hkp_add_two() {
         let (tmpdir, client) = client().unwrap();
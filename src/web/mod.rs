@@ -1,3 +1,4 @@
+use hagrid_mail::I18NHelper;
 use hyperx::header::{Charset, ContentDisposition, DispositionParam, DispositionType};
 use rocket::figment::Figment;
 use rocket::fs::NamedFile;
@@ -14,18 +15,24 @@ use gettext_macros::{compile_i18n, include_i18n};
 
 use serde::Serialize;
 
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::counters;
-use crate::i18n::I18NHelper;
+use crate::dkim::DkimSigner;
 use crate::i18n_helpers::describe_query_error;
 use crate::mail;
+use crate::mail_queue::{self, MailQueue};
 use crate::rate_limiter::RateLimiter;
 use crate::template_helpers::TemplateOverrides;
 use crate::tokens;
 
 use crate::database::types::Fingerprint;
-use crate::database::{Database, KeyDatabase, Query};
+use crate::database::{
+    CertPolicy, Database, DatabaseConfig, FileTokenStore, KeyDatabase, PolicyConfig, Query,
+    SqliteTokenStore, TokenStore, DEFAULT_HISTORY_RETENTION,
+};
 use crate::Result;
 
 use std::convert::TryInto;
@@ -72,6 +79,10 @@ pub enum MyResponse {
     Success(HagridTemplate),
     #[response(status = 200, content_type = "plain")]
     Plain(String),
+    #[response(status = 200, content_type = "html")]
+    Html(String),
+    #[response(status = 200, content_type = "json")]
+    Json(serde_json::Value),
     #[response(status = 200, content_type = "xml")]
     Xml(HagridTemplate),
     #[response(status = 200, content_type = "application/pgp-keys")]
@@ -118,6 +129,14 @@ impl MyResponse {
         MyResponse::Plain(s)
     }
 
+    pub fn html(s: String) -> Self {
+        MyResponse::Html(s)
+    }
+
+    pub fn json(value: serde_json::Value) -> Self {
+        MyResponse::Json(value)
+    }
+
     pub fn key(armored_key: String, fp: &Fingerprint) -> Self {
         let content_disposition = Header::new(
             rocket::http::hyper::header::CONTENT_DISPOSITION.as_str(),
@@ -428,6 +447,7 @@ fn rocket_factory(
         vks_api::vks_v1_by_email,
         vks_api::vks_v1_by_fingerprint,
         vks_api::vks_v1_by_keyid,
+        vks_api::vks_v1_changes,
         vks_api::upload_json,
         vks_api::upload_fallback,
         vks_api::request_verify_json,
@@ -445,11 +465,15 @@ fn rocket_factory(
         vks_web::quick_upload_proceed,
         // Debug
         debug_web::debug_info,
+        debug_web::vks_v1_debug,
+        debug_web::inspect,
+        debug_web::inspect_post,
         // HKP
         hkp::pks_lookup,
         hkp::pks_add_form,
         hkp::pks_add_form_data,
         hkp::pks_internal_index,
+        hkp::pks_internal_vindex,
         // WKD
         wkd::wkd_policy,
         wkd::wkd_query,
@@ -515,12 +539,31 @@ fn configure_prometheus(config: &Figment) -> Option<PrometheusMetrics> {
 }
 
 fn configure_db_service(config: &Figment) -> Result<KeyDatabase> {
-    let keys_internal_dir: PathBuf = config.extract_inner("keys_internal_dir")?;
-    let keys_external_dir: PathBuf = config.extract_inner("keys_external_dir")?;
-    let tmp_dir: PathBuf = config.extract_inner("tmp_dir")?;
+    let backend: String = config
+        .extract_inner("database")
+        .unwrap_or_else(|_| "filesystem".to_owned());
+
+    let policy = if config.contains("policy") {
+        CertPolicy::from_config(&config.extract_inner::<PolicyConfig>("policy")?)?
+    } else {
+        CertPolicy::default()
+    };
 
-    let fs_db = KeyDatabase::new(keys_internal_dir, keys_external_dir, tmp_dir)?;
-    Ok(fs_db)
+    let history_retention = config
+        .extract_inner::<u64>("history_retention_days")
+        .ok()
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+        .unwrap_or(DEFAULT_HISTORY_RETENTION);
+
+    KeyDatabase::open(DatabaseConfig {
+        backend,
+        keys_internal_dir: config.extract_inner("keys_internal_dir")?,
+        keys_external_dir: config.extract_inner("keys_external_dir")?,
+        tmp_dir: config.extract_inner("tmp_dir")?,
+        history_retention,
+        policy,
+        dry_run: false,
+    })
 }
 
 fn configure_hagrid_state(config: &Figment) -> Result<HagridState> {
@@ -540,7 +583,23 @@ fn configure_hagrid_state(config: &Figment) -> Result<HagridState> {
 
 fn configure_stateful_token_service(config: &Figment) -> Result<database::StatefulTokens> {
     let token_dir: PathBuf = config.extract_inner("token_dir")?;
-    database::StatefulTokens::new(token_dir)
+    let ttls: HashMap<String, u64> = config
+        .extract_inner("stateful_token_ttl")
+        .unwrap_or_default();
+
+    let token_store: String = config
+        .extract_inner("token_store")
+        .unwrap_or_else(|_| "file".to_owned());
+
+    let mut token_service: database::StatefulTokens = match token_store.as_str() {
+        "file" => FileTokenStore::new(token_dir)?.into(),
+        "sqlite" => SqliteTokenStore::new(token_dir)?.into(),
+        _ => return Err(anyhow!("Unknown token store: {}", token_store)),
+    };
+    for (token_type, ttl) in ttls {
+        token_service.set_ttl(&token_type, Duration::from_secs(ttl));
+    }
+    Ok(token_service)
 }
 
 fn configure_stateless_token_service(config: &Figment) -> Result<tokens::Service> {
@@ -557,11 +616,58 @@ fn configure_mail_service(config: &Figment) -> Result<mail::Service> {
     let from: String = config.extract_inner("from")?;
 
     let filemail_into: Option<PathBuf> = config.extract_inner::<PathBuf>("filemail_into").ok();
+    let smtp_host: Option<String> = config.extract_inner::<String>("smtp_host").ok();
+
+    let service = if let Some(path) = filemail_into {
+        mail::Service::filemail(&from, &base_uri, &email_template_dir, &path)?
+    } else if let Some(host) = smtp_host {
+        let port: u16 = config.extract_inner("smtp_port").unwrap_or(25);
+        mail::Service::smtp(&from, &base_uri, &email_template_dir, &host, port)?
+    } else {
+        mail::Service::sendmail(&from, &base_uri, &email_template_dir)?
+    };
+
+    let dkim_selector: Option<String> = config.extract_inner::<String>("dkim_selector").ok();
+    let service = if let Some(selector) = dkim_selector {
+        let private_key: PathBuf = config.extract_inner("dkim_private_key")?;
+        // Sign for the domain of the sender address, which is what
+        // DMARC checks the signature against.
+        let domain = from
+            .rsplit('@')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('>');
+        let dkim = DkimSigner::from_file(domain, &selector, &private_key)?;
+        service.with_dkim(dkim)
+    } else {
+        service
+    };
+
+    let encrypt_verification: bool = config
+        .extract_inner("encrypt_verification_mails")
+        .unwrap_or(false);
+    let service = if encrypt_verification {
+        service.with_encrypted_verification()
+    } else {
+        service
+    };
 
-    if let Some(path) = filemail_into {
-        mail::Service::filemail(&from, &base_uri, &email_template_dir, &path)
+    let mail_queue_dir: Option<PathBuf> = config.extract_inner::<PathBuf>("mail_queue_dir").ok();
+    if let Some(queue_dir) = mail_queue_dir {
+        let max_attempts: u32 = config
+            .extract_inner("mail_queue_max_attempts")
+            .unwrap_or(mail_queue::DEFAULT_MAX_ATTEMPTS);
+        let retry_delay = config
+            .extract_inner::<u64>("mail_queue_retry_secs")
+            .map(Duration::from_secs)
+            .unwrap_or(mail_queue::DEFAULT_RETRY_DELAY);
+        let queue = MailQueue::new(queue_dir)?.with_retries(max_attempts, retry_delay);
+
+        let service = service.with_queue(queue);
+        service.spawn_queue_worker();
+        Ok(service)
     } else {
-        mail::Service::sendmail(&from, &base_uri, &email_template_dir)
+        Ok(service)
     }
 }
 
@@ -627,6 +733,13 @@ pub mod tests {
     /// duration of your test.  To debug the test, mem::forget it to
     /// prevent cleanup.
     pub fn configuration() -> Result<(TempDir, rocket::figment::Figment)> {
+        configuration_with_backend("filesystem")
+    }
+
+    /// Returns a configuration using the given database backend.
+    pub fn configuration_with_backend(
+        backend: &str,
+    ) -> Result<(TempDir, rocket::figment::Figment)> {
         let root = tempdir()?;
         let filemail = root.path().join("filemail");
         ::std::fs::create_dir_all(&filemail)?;
@@ -670,6 +783,7 @@ pub mod tests {
             ))
             .merge(("tmp_dir", base_dir.join("tmp").to_str().unwrap()))
             .merge(("token_dir", base_dir.join("tokens").to_str().unwrap()))
+            .merge(("database", backend))
             .merge((
                 "maintenance_file",
                 base_dir.join("maintenance").to_str().unwrap(),
@@ -690,7 +804,11 @@ pub mod tests {
     }
 
     pub fn client() -> Result<(TempDir, Client)> {
-        let (tmpdir, config) = configuration()?;
+        client_with_backend("filesystem")
+    }
+
+    pub fn client_with_backend(backend: &str) -> Result<(TempDir, Client)> {
+        let (tmpdir, config) = configuration_with_backend(backend)?;
         let rocket = rocket_factory(rocket::custom(config))?;
         Ok((tmpdir, Client::untracked(rocket)?))
     }
@@ -814,9 +932,18 @@ pub mod tests {
             .contains("maintenance-message"));
     }
 
+    /// The database backends the upload flows are tested with.
+    const BACKENDS: &[&str] = &["filesystem", "memory", "sqlite"];
+
     #[test]
     fn upload_verify_single() {
-        let (tmpdir, client) = client().unwrap();
+        for backend in BACKENDS {
+            check_upload_verify_single(backend);
+        }
+    }
+
+    fn check_upload_verify_single(backend: &str) {
+        let (tmpdir, client) = client_with_backend(backend).unwrap();
         let filemail_into = tmpdir.path().join("filemail");
 
         // Generate a key and upload it.
@@ -872,6 +999,46 @@ pub mod tests {
         assert_consistency(client.rocket());
     }
 
+    #[test]
+    fn changes_feed() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_json_get_token(&client, &tpk_serialized);
+
+        let response = client.get("/vks/v1/changes?since=0").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let changes: vks_api::json::Changes =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(changes.changes.len(), 1);
+        assert_eq!(changes.changes[0].fingerprint, tpk.fingerprint().to_hex());
+        assert!(changes.changes[0].key.is_none());
+
+        // Keys are only included on request.
+        let response = client.get("/vks/v1/changes?since=0&keys=true").dispatch();
+        let with_keys: vks_api::json::Changes =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert!(with_keys.changes[0].key.is_some());
+
+        // Nothing new since the last cursor.
+        let response = client
+            .get(format!("/vks/v1/changes?cursor={}", changes.cursor))
+            .dispatch();
+        let changes: vks_api::json::Changes =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert!(changes.changes.is_empty());
+
+        let response = client.get("/vks/v1/changes?cursor=..%2Fkeys:0").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = client
+            .get("/vks/v1/changes?since=99999999999999")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
     #[test]
     fn upload_verify_lang() {
         let (tmpdir, client) = client().unwrap();
@@ -923,7 +1090,13 @@ pub mod tests {
 
     #[test]
     fn upload_verify_two() {
-        let (tmpdir, config) = configuration().unwrap();
+        for backend in BACKENDS {
+            check_upload_verify_two(backend);
+        }
+    }
+
+    fn check_upload_verify_two(backend: &str) {
+        let (tmpdir, config) = configuration_with_backend(backend).unwrap();
         let filemail_into = tmpdir.path().join("filemail");
 
         let rocket = rocket_factory(rocket::custom(config)).unwrap();
@@ -1032,6 +1205,115 @@ pub mod tests {
         assert_consistency(client.rocket());
     }
 
+    #[test]
+    fn upload_verify_encrypted() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", &token)
+            .append_pair("address", "foo@invalid.example.com")
+            .append_pair("encrypt", "on")
+            .finish();
+        let response = client
+            .post("/upload/request-verify")
+            .header(ContentType::Form)
+            .body(encoded.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // The verification link is only readable by the key holder.
+        let mail_content = pop_mail(&filemail_into).unwrap().unwrap();
+        assert!(mail_content.contains("multipart/encrypted"));
+        assert!(mail_content.contains("-----BEGIN PGP MESSAGE-----"));
+        assert!(!mail_content.contains("/verify/"));
+
+        // Keys that cannot receive encrypted mail are refused.
+        let (tpk, _) = CertBuilder::new()
+            .add_signing_subkey()
+            .add_userid("bar@invalid.example.com")
+            .generate()
+            .unwrap();
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_json_get_token(&client, &tpk_serialized);
+
+        let json = format!(
+            r#"{{"token":"{}","addresses":["bar@invalid.example.com"],"encrypt":true}}"#,
+            token
+        );
+        let response = client
+            .post("/vks/v1/request-verify")
+            .header(ContentType::JSON)
+            .body(json.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("no valid encryption subkey"));
+        assert!(pop_mail(&filemail_into).unwrap().is_none());
+    }
+
+    #[test]
+    fn upload_verify_encryption_required() {
+        let (tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("encrypt_verification_mails", true));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let filemail_into = tmpdir.path().join("filemail");
+
+        // Asking for a cleartext mail still gets an encrypted one.
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_json_get_token(&client, &tpk_serialized);
+
+        let json = format!(
+            r#"{{"token":"{}","addresses":["foo@invalid.example.com"],"encrypt":false}}"#,
+            token
+        );
+        let response = client
+            .post("/vks/v1/request-verify")
+            .header(ContentType::JSON)
+            .body(json.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let mail_content = pop_mail(&filemail_into).unwrap().unwrap();
+        assert!(mail_content.contains("-----BEGIN PGP MESSAGE-----"));
+        assert!(!mail_content.contains("/verify/"));
+
+        // Keys that cannot receive encrypted mail get no link at all.
+        let (tpk, _) = CertBuilder::new()
+            .add_signing_subkey()
+            .add_userid("bar@invalid.example.com")
+            .generate()
+            .unwrap();
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", &token)
+            .append_pair("address", "bar@invalid.example.com")
+            .finish();
+        let response = client
+            .post("/upload/request-verify")
+            .header(ContentType::Form)
+            .body(encoded.as_bytes())
+            .dispatch();
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("no valid encryption subkey"));
+        assert!(pop_mail(&filemail_into).unwrap().is_none());
+    }
+
     #[test]
     fn upload_curl_shortcut() {
         let (_tmpdir, client) = client().unwrap();
@@ -1192,6 +1474,30 @@ pub mod tests {
             .unwrap()
             .as_secs();
         assert!(body.contains(&format!(":{}:", creation_time)));
+
+        for subkey in tpk.keys().subkeys() {
+            assert!(body.contains(&format!("\r\nsub:{}:", subkey.fingerprint().to_hex())));
+        }
+    }
+
+    /// Asserts that the given URI returns a human readable hkp
+    /// "index" response for the given Cert.
+    pub fn check_hr_index_response(client: &Client, uri: &str, tpk: &Cert) {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let body = response.into_string().unwrap();
+
+        assert!(!body.contains("info:1:1"));
+        let keyid = sequoia_openpgp::KeyID::from(tpk.fingerprint()).to_hex();
+        assert!(body.contains(&format!("<pre>\npub  {} ", keyid)));
+        assert_eq!(
+            body.contains(&tpk.fingerprint().to_hex()),
+            uri.contains("fingerprint=on")
+        );
+        if body.contains("\r\nuid  ") {
+            assert_eq!(body.contains("\r\nsig  "), uri.contains("op=vindex"));
+        }
     }
 
     /// Asserts that we can get the given Cert back using the various
@@ -1239,6 +1545,45 @@ pub mod tests {
         );
 
         check_index_response(client, &format!("/pks/lookup?op=index&search={}", fp), tpk);
+        check_index_response(
+            client,
+            &format!("/pks/lookup?op=index&options=mr&search={}", fp),
+            tpk,
+        );
+        check_index_response(client, &format!("/pks/lookup?op=vindex&search={}", fp), tpk);
+        check_index_response(client, &format!("/pks/internal/index/{}", fp), tpk);
+        check_hr_index_response(
+            client,
+            &format!("/pks/lookup?op=index&options=nm&search={}", fp),
+            tpk,
+        );
+        check_hr_index_response(
+            client,
+            &format!(
+                "/pks/lookup?op=vindex&options=nm&fingerprint=on&search={}",
+                fp
+            ),
+            tpk,
+        );
+        check_debug_json_response(client, &format!("/vks/v1/debug?q={}", fp), tpk);
+        check_debug_json_response(client, &format!("/debug?q={}&format=json", keyid), tpk);
+    }
+
+    /// Asserts that the given URI returns the packets of the given
+    /// Cert as JSON.
+    pub fn check_debug_json_response(client: &Client, uri: &str, tpk: &Cert) {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let body: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+
+        let packets = body["packets"].as_array().unwrap();
+        assert_eq!(packets[0]["tag"], "Public-Key Packet");
+        assert_eq!(
+            packets[0]["fields"]["fingerprint"],
+            tpk.fingerprint().to_hex()
+        );
     }
 
     /// Asserts that the given URI contains the search string.
@@ -1260,6 +1605,11 @@ pub mod tests {
         assert!(body.contains("found"));
         assert!(body.contains(&tpk.fingerprint().to_hex()));
 
+        // The details list the subkeys.
+        for subkey in tpk.keys().subkeys() {
+            assert!(body.contains(&subkey.fingerprint().to_hex()));
+        }
+
         // Extract the links.
         let link_re = regex::Regex::new(&format!("{}(/vks/[^ \t\n\"<]*)", BASE_URI)).unwrap();
         let mut n = 0;
@@ -1383,6 +1733,167 @@ pub mod tests {
         String::from_utf8_lossy(capture_content).to_string()
     }
 
+    #[test]
+    fn debug_inspect() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+
+        let ct = ContentType::with_params("multipart", "form-data", ("boundary", "xyz"));
+        let mut body = Vec::new();
+        body.extend_from_slice(
+            b"--xyz\r\n\
+              Content-Disposition: form-data; name=\"mpis\"\r\n\
+              \r\n\
+              on\r\n\
+              --xyz\r\n\
+              Content-Disposition: form-data; name=\"keytext\"; filename=\".k\"\r\n\
+              Content-Type: application/octet-stream\r\n\
+              \r\n",
+        );
+        body.extend_from_slice(&tpk_serialized);
+        body.extend_from_slice(b"\r\n--xyz--");
+
+        let response = client
+            .post("/debug/inspect")
+            .header(ct)
+            .body(&body[..])
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains(&tpk.fingerprint().to_hex()));
+        assert!(body.contains("foo@invalid.example.com"));
+        assert!(body.contains("Public-Key Packet"));
+        assert!(body.contains("Public Key:"));
+
+        // Nothing was stored.
+        check_null_response(
+            &client,
+            &format!("/vks/v1/by-fingerprint/{}", tpk.fingerprint().to_hex()),
+        );
+        assert_consistency(client.rocket());
+    }
+
+    #[test]
+    fn upload_lints() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let (tpk, _) = CertBuilder::new()
+            .add_signing_subkey()
+            .add_userid("foo@invalid.example.com")
+            .generate()
+            .unwrap();
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+
+        let response = client
+            .post("/vks/v1/upload")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "keytext": "{}" }}"#,
+                base64::encode(&tpk_serialized)
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result: vks_api::json::UploadResult =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let codes: Vec<_> = result.lints.iter().map(|l| l.code.as_str()).collect();
+        assert_eq!(codes, vec!["no-encryption-key"]);
+
+        let response = vks_publish_submit_response(&client, &tpk_serialized);
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("This key has no valid subkey for encryption."));
+    }
+
+    #[test]
+    fn upload_lints_merged_cert() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        // Upload only the primary key and user ID, the subkeys are
+        // already stored.
+        let fragment = tpk.retain_subkeys(|_| false);
+        let mut fragment_serialized = Vec::new();
+        fragment.serialize(&mut fragment_serialized).unwrap();
+
+        let response = client
+            .post("/vks/v1/upload")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "keytext": "{}" }}"#,
+                base64::encode(&fragment_serialized)
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result: vks_api::json::UploadResult =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert!(result.lints.is_empty());
+    }
+
+    #[test]
+    fn upload_lints_unpublished_uid() {
+        use sequoia_openpgp::packet::signature::SignatureBuilder;
+        use sequoia_openpgp::packet::UserID;
+        use sequoia_openpgp::types::{HashAlgorithm, SignatureType};
+        use sequoia_openpgp::Packet;
+
+        let (_tmpdir, client) = client().unwrap();
+
+        // Bind a second user ID using SHA-1.
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut signer = tpk
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()
+            .unwrap()
+            .into_keypair()
+            .unwrap();
+        let uid = UserID::from("Unpublished <bar@invalid.example.com>");
+        let sig = SignatureBuilder::new(SignatureType::PositiveCertification)
+            .set_hash_algo(HashAlgorithm::SHA1)
+            .set_features(sequoia_openpgp::types::Features::sequoia())
+            .unwrap();
+        let sig = uid.bind(&mut signer, &tpk, sig).unwrap();
+        let tpk = tpk
+            .insert_packets(vec![Packet::from(uid), sig.into()])
+            .unwrap();
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        // Upload only the primary key.  The stored Cert has the
+        // unpublished user ID, but the response must not name it.
+        let bare = tpk.retain_userids(|_| false).retain_subkeys(|_| false);
+        let mut bare_serialized = Vec::new();
+        bare.serialize(&mut bare_serialized).unwrap();
+
+        let response = client
+            .post("/vks/v1/upload")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{ "keytext": "{}" }}"#,
+                base64::encode(&bare_serialized)
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(!body.contains("Unpublished"));
+        let result: vks_api::json::UploadResult = serde_json::from_str(&body).unwrap();
+        assert!(result.lints.is_empty());
+
+        let response = vks_publish_submit_response(&client, &bare_serialized);
+        assert_eq!(response.status(), Status::Ok);
+        assert!(!response.into_string().unwrap().contains("Unpublished"));
+    }
+
     fn vks_publish_submit_multiple(client: &Client, data: &[u8]) {
         let response = vks_publish_submit_response(client, data);
         let status = response.status();
@@ -3,8 +3,9 @@ use crate::Result;
 use crate::counters;
 use crate::database::types::{Email, Fingerprint};
 use crate::database::{
-    Database, EmailAddressStatus, ImportResult, KeyDatabase, StatefulTokens, TpkStatus,
+    Database, EmailAddressStatus, ImportResult, KeyDatabase, StatefulTokens, TokenStore, TpkStatus,
 };
+use crate::lint::{self, Lint};
 use crate::mail;
 use crate::rate_limiter::RateLimiter;
 use crate::tokens::{self, StatelessSerializable};
@@ -14,13 +15,15 @@ use gettext_macros::i18n;
 use rocket_i18n::I18n;
 
 use sequoia_openpgp::armor::ReaderMode;
-use sequoia_openpgp::cert::CertParser;
+use sequoia_openpgp::cert::{CertParser, ValidCert};
 use sequoia_openpgp::parse::{Dearmor, PacketParserBuilder, Parse};
+use sequoia_openpgp::types::RevocationStatus;
 use sequoia_openpgp::Cert;
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io::Read;
+use std::time::SystemTime;
 
 use self::response::*;
 
@@ -52,6 +55,13 @@ pub mod response {
         Revoked,
     }
 
+    /// A problem found by the upload linter.
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct LintReport {
+        pub code: String,
+        pub message: String,
+    }
+
     use std::collections::HashMap;
 
     pub enum UploadResponse {
@@ -63,6 +73,7 @@ pub mod response {
             count_unparsed: usize,
             is_new_key: bool,
             primary_uid: Option<Email>,
+            lints: Vec<LintReport>,
         },
         OkMulti {
             key_fprs: Vec<String>,
@@ -76,6 +87,26 @@ pub mod response {
         }
     }
 
+    pub enum CheckResponse {
+        Ok {
+            key_fpr: String,
+            is_revoked: bool,
+            addresses: Vec<String>,
+            count_unparsed: usize,
+            lints: Vec<LintReport>,
+        },
+        OkMulti {
+            key_fprs: Vec<String>,
+        },
+        Error(String),
+    }
+
+    impl CheckResponse {
+        pub fn err(err: impl Into<String>) -> Self {
+            CheckResponse::Error(err.into())
+        }
+    }
+
     pub enum PublishResponse {
         Ok { fingerprint: String, email: String },
         Error(String),
@@ -97,6 +128,24 @@ struct VerifyTpkState {
 
 impl StatelessSerializable for VerifyTpkState {}
 
+/// Parses all Certs in the uploaded key data.
+///
+/// Fails with a user-facing message if any of them does not parse.
+fn parse_keys(
+    i18n: &I18n,
+    reader: impl Read + Send + Sync,
+) -> std::result::Result<Vec<Cert>, String> {
+    let parser = match PacketParserBuilder::from_reader(reader)
+        .and_then(|ppb| ppb.dearmor(Dearmor::Auto(ReaderMode::VeryTolerant)).build())
+    {
+        Ok(ppr) => CertParser::from(ppr),
+        Err(_) => return Err(i18n!(i18n.catalog, "Parsing of key data failed.")),
+    };
+    parser
+        .collect::<Result<Vec<_>>>()
+        .map_err(|_| i18n!(i18n.catalog, "Parsing of key data failed."))
+}
+
 pub fn process_key(
     db: &KeyDatabase,
     i18n: &I18n,
@@ -105,29 +154,16 @@ pub fn process_key(
     reader: impl Read + Send + Sync,
 ) -> response::UploadResponse {
     // First, parse all Certs and error out if one fails.
-    let parser = match PacketParserBuilder::from_reader(reader)
-        .and_then(|ppb| ppb.dearmor(Dearmor::Auto(ReaderMode::VeryTolerant)).build())
-    {
-        Ok(ppr) => CertParser::from(ppr),
-        Err(_) => return UploadResponse::err(i18n!(i18n.catalog, "Parsing of key data failed.")),
+    let tpks = match parse_keys(i18n, reader) {
+        Ok(tpks) => tpks,
+        Err(e) => return UploadResponse::err(e),
     };
-    let mut tpks = Vec::new();
-    for tpk in parser {
-        tpks.push(match tpk {
-            Ok(t) => {
-                if t.is_tsk() {
-                    counters::inc_key_upload("secret");
-                    return UploadResponse::err(i18n!(
-                        i18n.catalog,
-                        "Whoops, please don't upload secret keys!"
-                    ));
-                }
-                t
-            }
-            Err(_) => {
-                return UploadResponse::err(i18n!(i18n.catalog, "Parsing of key data failed."));
-            }
-        });
+    if tpks.iter().any(Cert::is_tsk) {
+        counters::inc_key_upload("secret");
+        return UploadResponse::err(i18n!(
+            i18n.catalog,
+            "Whoops, please don't upload secret keys!"
+        ));
     }
 
     match tpks.len() {
@@ -143,6 +179,100 @@ pub fn process_key(
     }
 }
 
+/// Checks whether `process_key` would accept the uploaded key data,
+/// without touching the database.
+pub fn check_key(
+    db: &KeyDatabase,
+    i18n: &I18n,
+    reader: impl Read + Send + Sync,
+) -> response::CheckResponse {
+    let tpks = match parse_keys(i18n, reader) {
+        Ok(tpks) => tpks,
+        Err(e) => return CheckResponse::err(e),
+    };
+    if tpks.iter().any(Cert::is_tsk) {
+        return CheckResponse::err(i18n!(
+            i18n.catalog,
+            "Whoops, please don't upload secret keys!"
+        ));
+    }
+
+    match tpks.len() {
+        0 => CheckResponse::err(i18n!(i18n.catalog, "No key uploaded.")),
+        1 => check_key_single(db, i18n, tpks.into_iter().next().unwrap()),
+        _ => CheckResponse::OkMulti {
+            key_fprs: tpks
+                .iter()
+                .flat_map(|tpk| Fingerprint::try_from(tpk.fingerprint()))
+                .map(|fpr| fpr.to_string())
+                .collect(),
+        },
+    }
+}
+
+fn check_key_single(db: &KeyDatabase, i18n: &I18n, tpk: Cert) -> response::CheckResponse {
+    let key_fpr = match Fingerprint::try_from(tpk.fingerprint()) {
+        Ok(fpr) => fpr.to_string(),
+        Err(_) => return CheckResponse::err(i18n!(i18n.catalog, "Error processing uploaded key.")),
+    };
+
+    // Mirrors the checks in Database::merge.
+    let policy = db.policy();
+    let is_revoked = matches!(
+        tpk.revocation_status(policy, None),
+        RevocationStatus::Revoked(_)
+    );
+    if !is_revoked && tpk.keys().subkeys().next().is_none() && tpk.userids().next().is_none() {
+        return CheckResponse::err(i18n!(
+            i18n.catalog,
+            "This key has neither subkeys nor identities."
+        ));
+    }
+    if let Err(e) = policy.check_cert(&tpk, is_revoked) {
+        return CheckResponse::err(i18n!(
+            i18n.catalog,
+            "This key is not accepted by this server: {}";
+            e
+        ));
+    }
+
+    let count_unparsed = tpk
+        .userids()
+        .filter(|binding| Email::try_from(binding.userid()).is_err())
+        .count();
+
+    let mut addresses: Vec<String> = if is_revoked {
+        vec![]
+    } else {
+        tpk.userids()
+            .filter(|binding| {
+                !matches!(
+                    binding.revocation_status(policy, None),
+                    RevocationStatus::Revoked(_)
+                )
+            })
+            .flat_map(|binding| Email::try_from(binding.userid()))
+            .map(|email| email.to_string())
+            .collect()
+    };
+    addresses.sort();
+    addresses.dedup();
+
+    let lints = if is_revoked {
+        vec![]
+    } else {
+        lint_key(db, i18n, &tpk)
+    };
+
+    CheckResponse::Ok {
+        key_fpr,
+        is_revoked,
+        addresses,
+        count_unparsed,
+        lints,
+    }
+}
+
 fn log_db_merge(import_result: Result<ImportResult>) -> Result<ImportResult> {
     match import_result {
         Ok(ImportResult::New(_)) => counters::inc_key_upload("new"),
@@ -173,7 +303,7 @@ fn process_key_single(
 ) -> response::UploadResponse {
     let fp = Fingerprint::try_from(tpk.fingerprint()).unwrap();
 
-    let (tpk_status, is_new_key) = match log_db_merge(db.merge(tpk)) {
+    let (tpk_status, is_new_key) = match log_db_merge(db.merge(tpk.clone())) {
         Ok(ImportResult::New(tpk_status)) => (tpk_status, true),
         Ok(ImportResult::Updated(tpk_status)) => (tpk_status, false),
         Ok(ImportResult::Unchanged(tpk_status)) => (tpk_status, false),
@@ -182,6 +312,11 @@ fn process_key_single(
         }
     };
 
+    // The upload may be only a part of the stored Cert.
+    let lints = cert_to_lint(db, &fp, Some(tpk))
+        .map(|tpk| lint_key(db, i18n, &tpk))
+        .unwrap_or_default();
+
     let verify_state = {
         let emails = tpk_status
             .email_status
@@ -197,7 +332,14 @@ fn process_key_single(
 
     let token = tokens_stateless.create(&verify_state);
 
-    show_upload_verify(rate_limiter, token, tpk_status, verify_state, is_new_key)
+    show_upload_verify(
+        rate_limiter,
+        token,
+        tpk_status,
+        verify_state,
+        is_new_key,
+        lints,
+    )
 }
 
 pub fn request_verify(
@@ -210,6 +352,7 @@ pub fn request_verify(
     i18n: &I18n,
     token: String,
     addresses: Vec<String>,
+    encrypt: bool,
 ) -> response::UploadResponse {
     let (verify_state, tpk_status) = match check_tpk_state(db, token_stateless, i18n, &token) {
         Ok(ok) => ok,
@@ -217,7 +360,7 @@ pub fn request_verify(
     };
 
     if tpk_status.is_revoked {
-        return show_upload_verify(rate_limiter, token, tpk_status, verify_state, false);
+        return show_upload_verify(rate_limiter, token, tpk_status, verify_state, false, vec![]);
     }
 
     let emails_requested: Vec<_> = addresses
@@ -232,6 +375,28 @@ pub fn request_verify(
         })
         .collect();
 
+    let tpk = db
+        .by_fpr_full(&verify_state.fpr)
+        .and_then(|armored| Cert::from_bytes(armored.as_bytes()).ok());
+
+    // The operator may require encryption, so that nobody but the key
+    // holder can get a verification link.
+    let encrypt_to = if encrypt || mail_service.encrypts_verification() {
+        let vc = tpk
+            .as_ref()
+            .and_then(|tpk| tpk.with_policy(db.policy(), None).ok())
+            .filter(|vc| mail::can_encrypt_to(vc));
+        if vc.is_none() {
+            return UploadResponse::err(i18n!(
+                i18n.catalog,
+                "This key has no valid encryption subkey, so the verification email can't be encrypted."
+            ));
+        }
+        vc
+    } else {
+        None
+    };
+
     for email in emails_requested {
         let rate_limit_ok = rate_limiter.action_perform(format!("verify-{}", &email));
         if rate_limit_ok
@@ -242,6 +407,7 @@ pub fn request_verify(
                 i18n,
                 &verify_state.fpr,
                 &email,
+                encrypt_to.as_ref(),
             )
             .is_err()
         {
@@ -249,7 +415,11 @@ pub fn request_verify(
         }
     }
 
-    show_upload_verify(rate_limiter, token, tpk_status, verify_state, false)
+    let lints = cert_to_lint(db, &verify_state.fpr, None)
+        .map(|tpk| lint_key(db, i18n, &tpk))
+        .unwrap_or_default();
+
+    show_upload_verify(rate_limiter, token, tpk_status, verify_state, false, lints)
 }
 
 fn check_tpk_state(
@@ -277,6 +447,7 @@ fn send_verify_email(
     i18n: &I18n,
     fpr: &Fingerprint,
     email: &Email,
+    encrypt_to: Option<&ValidCert>,
 ) -> Result<()> {
     let token_content = (fpr.clone(), email.clone());
     let token_str = serde_json::to_string(&token_content)?;
@@ -288,6 +459,7 @@ fn send_verify_email(
         fpr.to_string(),
         email,
         &token_verify,
+        encrypt_to,
     )
 }
 
@@ -328,6 +500,7 @@ fn show_upload_verify(
     tpk_status: TpkStatus,
     verify_state: VerifyTpkState,
     is_new_key: bool,
+    lints: Vec<LintReport>,
 ) -> response::UploadResponse {
     let key_fpr = verify_state.fpr.to_string();
     if tpk_status.is_revoked {
@@ -339,6 +512,7 @@ fn show_upload_verify(
             status: HashMap::new(),
             is_new_key: false,
             primary_uid: None,
+            lints: vec![],
         };
     }
 
@@ -378,5 +552,68 @@ fn show_upload_verify(
         status,
         is_new_key,
         primary_uid,
+        lints,
     }
 }
+
+/// Returns the published Cert, merged with the `uploaded` one.
+///
+/// The lints name user IDs, so we must not lint the stored Cert: it
+/// contains user IDs that are not published, and the uploader may not
+/// know them.
+fn cert_to_lint(db: &KeyDatabase, fpr: &Fingerprint, uploaded: Option<Cert>) -> Option<Cert> {
+    let published = db
+        .by_fpr(fpr)
+        .and_then(|armored| Cert::from_bytes(armored.as_bytes()).ok());
+    match (uploaded, published) {
+        (Some(uploaded), Some(published)) => uploaded.merge_public(published).ok(),
+        (uploaded, published) => uploaded.or(published),
+    }
+}
+
+/// Runs the upload linter on the cert, and describes the problems it
+/// finds.
+fn lint_key(db: &KeyDatabase, i18n: &I18n, tpk: &Cert) -> Vec<LintReport> {
+    lint::lint(tpk, db.policy(), SystemTime::now())
+        .into_iter()
+        .map(|lint| {
+            let message = match &lint {
+                Lint::Sha1Binding { component } => i18n!(
+                    i18n.catalog,
+                    "The self-signature on {} uses SHA-1. Many OpenPGP implementations reject it.";
+                    component
+                ),
+                Lint::WeakRsa { fingerprint, bits } => i18n!(
+                    i18n.catalog,
+                    "The key {} is an RSA key of only {} bits. Use at least {} bits.";
+                    fingerprint,
+                    bits,
+                    lint::MIN_RSA_BITS
+                ),
+                Lint::Expired => i18n!(i18n.catalog, "This key has expired."),
+                Lint::ExpiresSoon { expiration_time } => {
+                    let date = chrono::DateTime::<chrono::Utc>::from(*expiration_time)
+                        .format("%Y-%m-%d")
+                        .to_string();
+                    i18n!(
+                        i18n.catalog,
+                        "This key expires on {}. Remember to extend its expiration date and upload it again.";
+                        date
+                    )
+                }
+                Lint::NoEncryptionKey => i18n!(
+                    i18n.catalog,
+                    "This key has no valid subkey for encryption. Nobody can send you encrypted messages."
+                ),
+                Lint::NoFeatures => i18n!(
+                    i18n.catalog,
+                    "This key does not announce which features it supports. Some implementations will fall back to insecure encryption."
+                ),
+            };
+            LintReport {
+                code: lint.code().to_string(),
+                message,
+            }
+        })
+        .collect()
+}
@@ -7,7 +7,7 @@ use serde_json::json;
 use std::io::Cursor;
 
 use crate::database::types::{Email, Fingerprint, KeyID};
-use crate::database::{KeyDatabase, Query, StatefulTokens};
+use crate::database::{Database, KeyDatabase, Query, StatefulTokens, WriteLogCursor};
 use crate::mail;
 use crate::rate_limiter::RateLimiter;
 use crate::tokens;
@@ -20,7 +20,7 @@ use crate::web::{MyResponse, RequestOrigin};
 use rocket::serde::json::Error as JsonError;
 
 pub mod json {
-    use crate::web::vks::response::EmailStatus;
+    use crate::web::vks::response::{EmailStatus, LintReport};
     use std::collections::HashMap;
 
     #[derive(Deserialize)]
@@ -28,6 +28,8 @@ pub mod json {
         pub token: String,
         pub addresses: Vec<String>,
         pub locale: Option<Vec<String>>,
+        #[serde(default)]
+        pub encrypt: bool,
     }
 
     #[derive(Deserialize)]
@@ -40,11 +42,33 @@ pub mod json {
         pub token: String,
         pub key_fpr: String,
         pub status: HashMap<String, EmailStatus>,
+        #[serde(default)]
+        pub lints: Vec<LintReport>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Change {
+        pub fingerprint: String,
+        pub timestamp: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub key: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Changes {
+        pub changes: Vec<Change>,
+        pub cursor: String,
     }
 }
 
 type JsonResult = Result<serde_json::Value, JsonErrorResponse>;
 
+/// Maximum number of entries returned by a single changes request.
+const CHANGES_PAGE_SIZE: usize = 1000;
+/// Maximum number of entries returned by a changes request that
+/// includes keys.
+const CHANGES_PAGE_SIZE_WITH_KEYS: usize = 100;
+
 #[derive(Debug)]
 pub struct JsonErrorResponse(Status, String);
 
@@ -76,11 +100,13 @@ fn upload_ok_json(response: UploadResponse) -> Result<serde_json::Value, JsonErr
             token,
             key_fpr,
             status,
+            lints,
             ..
         } => Ok(json!(json::UploadResult {
             token,
             key_fpr,
-            status
+            status,
+            lints,
         })),
         UploadResponse::OkMulti { key_fprs } => Ok(json!(key_fprs)),
         UploadResponse::Error(error) => Err(JsonErrorResponse(Status::BadRequest, error)),
@@ -141,6 +167,7 @@ pub fn request_verify_json(
         token,
         addresses,
         locale,
+        encrypt,
     } = data.into_inner();
     let i18n = get_locale(langs, locale.unwrap_or_default());
     let result = vks::request_verify(
@@ -153,6 +180,7 @@ pub fn request_verify_json(
         &i18n,
         token,
         addresses,
+        encrypt,
     );
     upload_ok_json(result)
 }
@@ -200,3 +228,49 @@ pub fn vks_v1_by_keyid(db: &rocket::State<KeyDatabase>, i18n: I18n, kid: String)
 
     web::key_to_response_plain(db, i18n, query)
 }
+
+#[get("/vks/v1/changes?<since>&<cursor>&<keys>")]
+pub fn vks_v1_changes(
+    db: &rocket::State<KeyDatabase>,
+    since: Option<u64>,
+    cursor: Option<String>,
+    keys: Option<bool>,
+) -> JsonResult {
+    let with_keys = keys.unwrap_or(false);
+    let since = since.unwrap_or(0);
+    let cursor = match cursor {
+        Some(cursor) => cursor
+            .parse::<WriteLogCursor>()
+            .map_err(|_| JsonErrorResponse(Status::BadRequest, "malformed cursor".to_owned()))?,
+        None => WriteLogCursor::at_timestamp(since).ok_or_else(|| {
+            JsonErrorResponse(Status::BadRequest, "since is out of range".to_owned())
+        })?,
+    };
+    let limit = if with_keys {
+        CHANGES_PAGE_SIZE_WITH_KEYS
+    } else {
+        CHANGES_PAGE_SIZE
+    };
+
+    let (entries, cursor) = db
+        .read_write_log(since, &cursor, limit)
+        .map_err(|e| JsonErrorResponse(Status::InternalServerError, e.to_string()))?;
+
+    let changes = entries
+        .into_iter()
+        .map(|entry| json::Change {
+            key: if with_keys {
+                db.by_primary_fpr(&entry.fingerprint)
+            } else {
+                None
+            },
+            fingerprint: entry.fingerprint.to_string(),
+            timestamp: entry.timestamp,
+        })
+        .collect();
+
+    Ok(json!(json::Changes {
+        changes,
+        cursor: cursor.to_string(),
+    }))
+}
@@ -11,6 +11,13 @@ use rocket::form::ValueField;
 use rocket::http::ContentType;
 use rocket::Data;
 use rocket_i18n::I18n;
+use sequoia_openpgp::cert::amalgamation::key::ErasedKeyAmalgamation;
+use sequoia_openpgp::cert::amalgamation::{ValidAmalgamation, ValidateAmalgamation};
+use sequoia_openpgp::crypto::mpi::PublicKey;
+use sequoia_openpgp::packet::key::PublicParts;
+use sequoia_openpgp::policy::Policy;
+use sequoia_openpgp::types::{Curve, RevocationStatus};
+use sequoia_openpgp::Cert;
 use url::percent_encoding::percent_decode;
 
 use crate::database::{Database, KeyDatabase, Query, StatefulTokens};
@@ -22,17 +29,20 @@ use crate::web::{MyResponse, RequestOrigin};
 
 use std::collections::HashMap;
 use std::io::Cursor;
+use std::time::SystemTime;
 
 use crate::web::vks;
 use crate::web::vks::response::*;
 
-const UPLOAD_LIMIT: ByteUnit = ByteUnit::Mebibyte(1);
+pub const UPLOAD_LIMIT: ByteUnit = ByteUnit::Mebibyte(1);
 
 mod forms {
     #[derive(FromForm, Deserialize)]
     pub struct VerifyRequest {
         pub token: String,
         pub address: String,
+        #[serde(default)]
+        pub encrypt: bool,
     }
 
     #[derive(Deserialize)]
@@ -42,6 +52,8 @@ mod forms {
 }
 
 mod template {
+    use crate::web::vks::response::LintReport;
+
     #[derive(Serialize)]
     pub struct VerifyForm {
         pub token: String,
@@ -58,6 +70,30 @@ mod template {
     pub struct Search {
         pub query: String,
         pub fpr: String,
+        pub key: Option<KeyDetails>,
+    }
+
+    #[derive(Serialize)]
+    pub struct KeyDetails {
+        pub primary: KeyInfo,
+        pub subkeys: Vec<KeyInfo>,
+        pub userids: Vec<String>,
+        pub is_revoked: bool,
+        pub revocation_reason: Option<String>,
+        pub warnings: Vec<String>,
+    }
+
+    #[derive(Serialize)]
+    pub struct KeyInfo {
+        pub fpr: String,
+        pub algo: String,
+        /// Key flags in GnuPG notation, e.g. "SC".
+        pub capabilities: String,
+        pub created: String,
+        pub expires: Option<String>,
+        pub is_expired: bool,
+        pub is_revoked: bool,
+        pub is_weak: bool,
     }
 
     #[derive(Serialize)]
@@ -72,6 +108,7 @@ mod template {
         pub count_revoked: usize,
         pub count_unparsed_one: bool,
         pub count_unparsed: usize,
+        pub lints: Vec<LintReport>,
     }
 
     #[derive(Serialize)]
@@ -95,13 +132,16 @@ mod template {
 impl MyResponse {
     fn upload_response_quick(response: UploadResponse, i18n: I18n, origin: RequestOrigin) -> Self {
         match response {
-            UploadResponse::Ok { token, .. } => {
+            UploadResponse::Ok { token, lints, .. } => {
                 let uri = uri!(quick_upload_proceed(token));
-                let text = format!(
+                let mut text = format!(
                     "Key successfully uploaded. Proceed with verification here:\n{}{}\n",
                     origin.get_base_uri(),
                     uri
                 );
+                for lint in lints {
+                    text.push_str(&format!("Warning: {}\n", lint.message));
+                }
                 MyResponse::plain(text)
             }
             UploadResponse::OkMulti { key_fprs } => MyResponse::plain(format!(
@@ -122,6 +162,7 @@ impl MyResponse {
                 is_revoked,
                 count_unparsed,
                 status,
+                lints,
                 ..
             } => Self::upload_ok(
                 token,
@@ -129,6 +170,7 @@ impl MyResponse {
                 is_revoked,
                 count_unparsed,
                 status,
+                lints,
                 i18n,
                 origin,
             ),
@@ -145,6 +187,7 @@ impl MyResponse {
         is_revoked: bool,
         count_unparsed: usize,
         uid_status: HashMap<String, EmailStatus>,
+        lints: Vec<LintReport>,
         i18n: I18n,
         origin: RequestOrigin,
     ) -> Self {
@@ -185,6 +228,7 @@ impl MyResponse {
             count_revoked,
             count_unparsed_one: count_unparsed == 1,
             count_unparsed,
+            lints,
         };
         MyResponse::ok("upload/upload-ok", context, i18n, origin)
     }
@@ -272,14 +316,157 @@ fn key_to_response(
         return MyResponse::not_found(None, describe_query_error(&i18n, &query), i18n, origin);
     };
 
+    let key = match db.lookup(&query) {
+        Ok(tpk) => tpk.map(|tpk| key_details(&i18n, db.policy(), &tpk)),
+        Err(e) => return MyResponse::ise(e),
+    };
+
     let context = template::Search {
         query: query_string,
         fpr: fp.to_string(),
+        key,
     };
 
     MyResponse::ok("found", context, i18n, origin)
 }
 
+/// Describes the published parts of a Cert for the search result
+/// page.
+fn key_details(i18n: &I18n, policy: &dyn Policy, tpk: &Cert) -> template::KeyDetails {
+    let primary = key_info(tpk.primary_key().into(), policy);
+    let subkeys: Vec<template::KeyInfo> = tpk
+        .keys()
+        .subkeys()
+        .map(|ka| key_info(ka.into(), policy))
+        .collect();
+    let userids = tpk
+        .userids()
+        .map(|uid| String::from_utf8_lossy(uid.userid().value()).into_owned())
+        .collect();
+
+    let (is_revoked, revocation_reason) = match tpk.revocation_status(policy, None) {
+        RevocationStatus::Revoked(sigs) => {
+            let reason =
+                sigs.iter()
+                    .find_map(|sig| sig.reason_for_revocation())
+                    .map(|(reason, message)| {
+                        let message = String::from_utf8_lossy(message);
+                        if message.is_empty() {
+                            reason.to_string()
+                        } else {
+                            format!("{}: {}", reason, message)
+                        }
+                    });
+            (true, reason)
+        }
+        _ => (false, None),
+    };
+
+    let mut warnings = Vec::new();
+    if primary.is_expired {
+        warnings.push(i18n!(i18n.catalog, "This key has expired."));
+    }
+    for key in std::iter::once(&primary).chain(subkeys.iter()) {
+        if key.is_weak {
+            warnings.push(i18n!(
+                i18n.catalog,
+                "The key {} uses {}, which is considered weak.";
+                key.fpr,
+                key.algo
+            ));
+        }
+    }
+
+    template::KeyDetails {
+        primary,
+        subkeys,
+        userids,
+        is_revoked,
+        revocation_reason,
+        warnings,
+    }
+}
+
+fn key_info(ka: ErasedKeyAmalgamation<PublicParts>, policy: &dyn Policy) -> template::KeyInfo {
+    let key = ka.key();
+    let bits = key.mpis().bits().unwrap_or(0);
+
+    let (key_flags, expiration_time, is_revoked) = match ka.clone().with_policy(policy, None) {
+        Ok(vka) => (
+            vka.key_flags(),
+            vka.key_expiration_time(),
+            vka.revocation_status() != RevocationStatus::NotAsFarAsWeKnow,
+        ),
+        Err(_) => (None, None, false),
+    };
+
+    let mut capabilities = String::new();
+    if let Some(flags) = key_flags {
+        if flags.for_certification() {
+            capabilities.push('C');
+        }
+        if flags.for_signing() {
+            capabilities.push('S');
+        }
+        if flags.for_transport_encryption() || flags.for_storage_encryption() {
+            capabilities.push('E');
+        }
+        if flags.for_authentication() {
+            capabilities.push('A');
+        }
+    }
+
+    let is_weak = match key.mpis() {
+        PublicKey::RSA { .. } | PublicKey::DSA { .. } | PublicKey::ElGamal { .. } => bits < 2048,
+        _ => false,
+    };
+
+    template::KeyInfo {
+        fpr: key.fingerprint().to_hex(),
+        algo: algorithm_name(key.mpis(), bits),
+        capabilities,
+        created: date(key.creation_time()),
+        expires: expiration_time.map(date),
+        is_expired: expiration_time
+            .map(|time| time <= SystemTime::now())
+            .unwrap_or(false),
+        is_revoked,
+        is_weak,
+    }
+}
+
+/// Returns the name of the key's algorithm in GnuPG notation, e.g.
+/// "rsa3072" or "ed25519".
+fn algorithm_name(mpis: &PublicKey, bits: usize) -> String {
+    match mpis {
+        PublicKey::RSA { .. } => format!("rsa{}", bits),
+        PublicKey::DSA { .. } => format!("dsa{}", bits),
+        PublicKey::ElGamal { .. } => format!("elg{}", bits),
+        PublicKey::EdDSA { curve, .. }
+        | PublicKey::ECDSA { curve, .. }
+        | PublicKey::ECDH { curve, .. } => match curve {
+            Curve::Ed25519 => "ed25519".to_string(),
+            Curve::Cv25519 => "cv25519".to_string(),
+            Curve::NistP256 => "nistp256".to_string(),
+            Curve::NistP384 => "nistp384".to_string(),
+            Curve::NistP521 => "nistp521".to_string(),
+            Curve::BrainpoolP256 => "brainpoolP256r1".to_string(),
+            Curve::BrainpoolP512 => "brainpoolP512r1".to_string(),
+            curve => curve.to_string(),
+        },
+        mpis => mpis
+            .algo()
+            .map(|algo| algo.to_string())
+            .unwrap_or_else(|| "unknown".to_string()),
+    }
+}
+
+fn date(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
 #[put("/", data = "<data>")]
 pub async fn quick_upload(
     db: &rocket::State<KeyDatabase>,
@@ -322,6 +509,7 @@ pub fn quick_upload_proceed(
         &i18n,
         token,
         vec![],
+        false,
     );
     MyResponse::upload_response(result, i18n, origin)
 }
@@ -444,7 +632,11 @@ pub fn request_verify_form(
     i18n: I18n,
     request: Form<forms::VerifyRequest>,
 ) -> MyResponse {
-    let forms::VerifyRequest { token, address } = request.into_inner();
+    let forms::VerifyRequest {
+        token,
+        address,
+        encrypt,
+    } = request.into_inner();
     let result = vks::request_verify(
         db,
         &origin,
@@ -455,6 +647,7 @@ pub fn request_verify_form(
         &i18n,
         token,
         vec![address],
+        encrypt,
     );
     MyResponse::upload_response(result, i18n, origin)
 }
@@ -474,7 +667,11 @@ pub fn request_verify_form_data(
     i18n: I18n,
     request: Form<forms::VerifyRequest>,
 ) -> MyResponse {
-    let forms::VerifyRequest { token, address } = request.into_inner();
+    let forms::VerifyRequest {
+        token,
+        address,
+        encrypt,
+    } = request.into_inner();
     let result = vks::request_verify(
         db,
         &origin,
@@ -485,6 +682,7 @@ pub fn request_verify_form_data(
         &i18n,
         token,
         vec![address],
+        encrypt,
     );
     MyResponse::upload_response(result, i18n, origin)
 }